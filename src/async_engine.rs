@@ -0,0 +1,109 @@
+use std::{panic, path::PathBuf};
+
+use tokio::task;
+
+use crate::{batch::WriteBatch, engine::DBEngine, entry::Entry, error::Result};
+
+/// `DBEngine` for async code. Every call runs on tokio's blocking pool, so
+/// waiting for the lock or for the disk never stalls the executor.
+#[derive(Clone)]
+pub struct AsyncDBEngine {
+    engine: DBEngine,
+}
+
+impl AsyncDBEngine {
+    pub async fn new(dir: PathBuf) -> Result<Self> {
+        let engine = blocking(move || DBEngine::new(dir)).await?;
+        Ok(AsyncDBEngine::from(engine))
+    }
+
+    pub async fn set(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut engine = self.engine.clone();
+        let (key, value) = (key.to_owned(), value.to_owned());
+        blocking(move || engine.set(&key, &value)).await
+    }
+
+    pub async fn get(&self, key: &[u8]) -> Result<Option<Entry>> {
+        let engine = self.engine.clone();
+        let key = key.to_owned();
+        blocking(move || engine.get(&key)).await
+    }
+
+    pub async fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        let engine = self.engine.clone();
+        let key = key.to_owned();
+        blocking(move || engine.contains_key(&key)).await
+    }
+
+    pub async fn delete(&self, key: &[u8]) -> Result<()> {
+        let mut engine = self.engine.clone();
+        let key = key.to_owned();
+        blocking(move || engine.delete(&key)).await
+    }
+
+    pub async fn write(&self, batch: WriteBatch) -> Result<()> {
+        let mut engine = self.engine.clone();
+        blocking(move || engine.write(batch)).await
+    }
+
+    /// The wrapped engine, for calls that are cheap enough to make directly.
+    pub fn engine(&self) -> &DBEngine {
+        &self.engine
+    }
+}
+
+impl From<DBEngine> for AsyncDBEngine {
+    fn from(engine: DBEngine) -> Self {
+        AsyncDBEngine { engine }
+    }
+}
+
+/// Runs `f` on the blocking pool; a panic in `f` is resumed in the caller.
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    match task::spawn_blocking(f).await {
+        Ok(value) => value,
+        Err(e) => panic::resume_unwind(e.into_panic()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use rand::Rng;
+
+    use crate::utils::remove_dir;
+
+    use super::AsyncDBEngine;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_tasks() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let engine = AsyncDBEngine::new(path.clone()).await.unwrap();
+
+        let tasks: Vec<_> = (0..8u32)
+            .map(|task| {
+                let engine = engine.clone();
+                tokio::spawn(async move {
+                    for idx in 0..25u32 {
+                        let key = format!("key{}-{}", task, idx);
+                        engine.set(key.as_bytes(), b"value").await.unwrap();
+                        assert!(engine.get(key.as_bytes()).await.unwrap().is_some());
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(200, engine.engine().len().unwrap());
+        engine.delete(b"key0-0").await.unwrap();
+        assert!(!engine.contains_key(b"key0-0").await.unwrap());
+        assert_eq!(None, engine.get(b"key0-0").await.unwrap());
+
+        // clean up
+        remove_dir(&path).unwrap();
+    }
+}
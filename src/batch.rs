@@ -0,0 +1,80 @@
+/// A group of sets and deletes that `Db::write` applies with a single commit.
+/// Operations take effect in the order they were added, so a later operation
+/// on a key overrides an earlier one.
+#[derive(Debug, Default, Clone)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum BatchOp {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.ops.push(BatchOp::Set {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        });
+        self
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.ops.push(BatchOp::Delete { key: key.to_owned() });
+        self
+    }
+
+    /// Number of operations added so far.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+
+    pub(crate) fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keeps_operation_order() {
+        let mut batch = WriteBatch::new();
+        assert!(batch.is_empty());
+
+        batch.set(b"a", b"1").delete(b"a").set(b"b", b"2");
+        assert_eq!(3, batch.len());
+        assert_eq!(
+            &[
+                BatchOp::Set {
+                    key: b"a".to_vec(),
+                    value: b"1".to_vec()
+                },
+                BatchOp::Delete { key: b"a".to_vec() },
+                BatchOp::Set {
+                    key: b"b".to_vec(),
+                    value: b"2".to_vec()
+                },
+            ],
+            batch.ops()
+        );
+
+        batch.clear();
+        assert!(batch.is_empty());
+    }
+}
@@ -0,0 +1,73 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+/// False positive rate the filters are sized for.
+const FALSE_POSITIVE_RATE: f64 = 0.01;
+
+/// A Bloom filter over the keys of a segment: `may_contain` never answers
+/// `false` for an inserted key, and answers `true` for about 1% of the others.
+#[derive(Debug, Clone)]
+pub(crate) struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// An empty filter sized for `expected` keys.
+    pub fn new(expected: usize) -> BloomFilter {
+        let ln2 = std::f64::consts::LN_2;
+        let expected = expected.max(1) as f64;
+        let num_bits = (-expected * FALSE_POSITIVE_RATE.ln() / (ln2 * ln2)).ceil().max(64.0) as u64;
+        let hashes = ((num_bits as f64 / expected) * ln2).round().max(1.0) as u32;
+
+        BloomFilter {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            hashes,
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        for bit in bit_indexes(key, self.hashes, self.num_bits) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        bit_indexes(key, self.hashes, self.num_bits)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+}
+
+/// The `hashes` bits for `key`, derived from one 64-bit hash by double hashing.
+fn bit_indexes(key: &[u8], hashes: u32, num_bits: u64) -> impl Iterator<Item = u64> {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    let hash = hasher.finish();
+    let (h1, h2) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+
+    (0..hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+}
+
+#[cfg(test)]
+mod test {
+    use super::BloomFilter;
+
+    #[test]
+    fn no_false_negatives() {
+        let mut filter = BloomFilter::new(1000);
+        for idx in 0..1000u32 {
+            filter.insert(&idx.to_le_bytes());
+        }
+
+        assert!((0..1000u32).all(|idx| filter.may_contain(&idx.to_le_bytes())));
+
+        let false_positives = (1000..11000u32)
+            .filter(|idx| filter.may_contain(&idx.to_le_bytes()))
+            .count();
+        assert!(false_positives < 200, "{} false positives", false_positives);
+    }
+}
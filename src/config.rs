@@ -0,0 +1,124 @@
+use std::{path::PathBuf, sync::Arc};
+
+use crate::{
+    db::{EncryptionKey, OpenMode},
+    segment_store::{LocalFs, SegmentNaming, SegmentStore},
+};
+
+/// Everything `Db::open` needs to know, gathered in one place. Options not
+/// set keep the same defaults as `Db::init_from_existing`.
+#[derive(Debug, Clone)]
+pub struct DbConfig {
+    pub(crate) dir: PathBuf,
+    pub(crate) mode: OpenMode,
+    pub(crate) encryption_key: Option<EncryptionKey>,
+    pub(crate) sync: bool,
+    pub(crate) max_memtable_bytes: Option<usize>,
+    pub(crate) max_total_bytes: Option<usize>,
+    pub(crate) compression_threshold: Option<usize>,
+    pub(crate) value_cache_capacity: Option<usize>,
+    pub(crate) parallel_segment_reads: bool,
+    pub(crate) absent_cache_capacity: Option<usize>,
+    pub(crate) naming: SegmentNaming,
+    pub(crate) max_merged_on_open: Option<usize>,
+    pub(crate) store: Arc<dyn SegmentStore>,
+}
+
+impl DbConfig {
+    /// Defaults for a database in `dir`, which is created if it is missing.
+    pub fn new(dir: PathBuf) -> DbConfig {
+        DbConfig {
+            dir,
+            mode: OpenMode::default(),
+            encryption_key: None,
+            sync: false,
+            max_memtable_bytes: None,
+            max_total_bytes: None,
+            compression_threshold: None,
+            value_cache_capacity: None,
+            parallel_segment_reads: false,
+            absent_cache_capacity: None,
+            naming: SegmentNaming::default(),
+            max_merged_on_open: None,
+            store: Arc::new(LocalFs),
+        }
+    }
+
+    /// See `OpenMode`.
+    pub fn with_open_mode(mut self, mode: OpenMode) -> DbConfig {
+        self.mode = mode;
+        self
+    }
+
+    /// See `Db::init_from_existing_with_key`.
+    pub fn with_encryption_key(mut self, key: Option<EncryptionKey>) -> DbConfig {
+        self.encryption_key = key;
+        self
+    }
+
+    /// See `Db::set_sync`.
+    pub fn with_sync(mut self, sync: bool) -> DbConfig {
+        self.sync = sync;
+        self
+    }
+
+    /// See `Db::set_max_memtable_bytes`.
+    pub fn with_max_memtable_bytes(mut self, max: usize) -> DbConfig {
+        self.max_memtable_bytes = Some(max);
+        self
+    }
+
+    /// See `Db::set_max_total_bytes`.
+    pub fn with_max_total_bytes(mut self, quota: usize) -> DbConfig {
+        self.max_total_bytes = Some(quota);
+        self
+    }
+
+    /// See `Db::set_compression_threshold`.
+    pub fn with_compression_threshold(mut self, threshold: usize) -> DbConfig {
+        self.compression_threshold = Some(threshold);
+        self
+    }
+
+    /// See `Db::set_value_cache_capacity`.
+    pub fn with_value_cache_capacity(mut self, entries: usize) -> DbConfig {
+        self.value_cache_capacity = Some(entries);
+        self
+    }
+
+    /// See `Db::set_parallel_segment_reads`.
+    pub fn with_parallel_segment_reads(mut self, parallel: bool) -> DbConfig {
+        self.parallel_segment_reads = parallel;
+        self
+    }
+
+    /// See `Db::set_absent_cache_capacity`.
+    pub fn with_absent_cache_capacity(mut self, entries: usize) -> DbConfig {
+        self.absent_cache_capacity = Some(entries);
+        self
+    }
+
+    /// See `SegmentNaming`. Segments already in the directory keep their
+    /// names, whichever scheme they follow.
+    pub fn with_segment_naming(mut self, naming: SegmentNaming) -> DbConfig {
+        self.naming = naming;
+        self
+    }
+
+    /// Bounds the work of opening with `OpenMode::Rewrite` or
+    /// `OpenMode::RewriteKeepingOld`: at most the `max` oldest segments are
+    /// merged into one, and should that leave others, the database is opened
+    /// as with `OpenMode::Reopen`. Later opens, or `compact`, merge the rest.
+    /// Every segment is still replayed.
+    pub fn with_max_merged_segments_on_open(mut self, max: usize) -> DbConfig {
+        self.max_merged_on_open = Some(max);
+        self
+    }
+
+    /// Keeps the segments in `store` instead of the local file system; `dir`
+    /// then names a directory of the store.
+    pub fn with_segment_store(mut self, store: Arc<dyn SegmentStore>) -> DbConfig {
+        self.store = store;
+        self
+    }
+}
@@ -0,0 +1,71 @@
+use std::{fmt, io};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Nonce,
+};
+
+pub(crate) const NONCE_SIZE: usize = 12;
+pub(crate) const TAG_SIZE: usize = 16;
+
+/// AES-256-GCM over record bodies. Every sealed body gets a fresh random
+/// nonce, and the caller's associated data is authenticated along with it.
+#[derive(Clone)]
+pub(crate) struct RecordCipher {
+    cipher: Aes256Gcm,
+}
+
+impl fmt::Debug for RecordCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RecordCipher { .. }")
+    }
+}
+
+impl RecordCipher {
+    pub fn new(key: &[u8; 32]) -> RecordCipher {
+        RecordCipher {
+            cipher: Aes256Gcm::new(key.into()),
+        }
+    }
+
+    /// Encrypts `plaintext`, returning the nonce and the ciphertext with its
+    /// tag appended.
+    pub fn seal(&self, plaintext: &[u8], aad: &[u8]) -> io::Result<([u8; NONCE_SIZE], Vec<u8>)> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let sealed = self
+            .cipher
+            .encrypt(&nonce, Payload { msg: plaintext, aad })
+            .map_err(|_| io::Error::other("could not encrypt record"))?;
+        Ok((nonce.into(), sealed))
+    }
+
+    /// Decrypts what `seal` produced, or `None` if the key is wrong or the
+    /// ciphertext, tag or associated data were altered.
+    pub fn open(&self, nonce: &[u8], sealed: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: sealed, aad })
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let cipher = RecordCipher::new(&[7; 32]);
+
+        let (nonce, sealed) = cipher.seal(b"HelloWorld!", b"header").unwrap();
+        assert_eq!(11 + TAG_SIZE, sealed.len());
+        assert_eq!(Some(b"HelloWorld!".to_vec()), cipher.open(&nonce, &sealed, b"header"));
+
+        // the associated data and the key are both checked
+        assert_eq!(None, cipher.open(&nonce, &sealed, b"other"));
+        assert_eq!(None, RecordCipher::new(&[8; 32]).open(&nonce, &sealed, b"header"));
+
+        // nonces are not reused
+        let (other_nonce, _) = cipher.seal(b"HelloWorld!", b"header").unwrap();
+        assert_ne!(nonce, other_nonce);
+    }
+}
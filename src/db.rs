@@ -1,441 +1,4826 @@
 use std::{
-    io,
-    path::PathBuf,
-    time::{SystemTime, UNIX_EPOCH},
+    borrow::Cow,
+    collections::{BTreeMap, HashSet},
+    fmt, fs,
+    io::{self, Seek, SeekFrom},
+    ops::Bound,
+    path::{Path, PathBuf},
+    panic,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
+    batch::{BatchOp, WriteBatch},
+    bloom::BloomFilter,
+    config::DbConfig,
     entry::Entry,
+    error::{DbError, Result},
+    lru::LruCache,
     memtable::MemTable,
-    storage::Storage,
+    segment_store::{LocalFs, SegmentNaming, SegmentStore},
+    stats::{DbStats, LatencyStats},
+    storage::{encode_record, framed_len, record_len, Storage, EXPIRY_SIZE, FLAG_DELETED, TEMP_EXTENSION},
     storage_iterator::StorageIterator,
-    utils::{remove_file, scan_dir, create_dir},
+    utils::{scan_dir, scan_dir_in},
 };
 
-#[derive(Debug)]
+/// Decides whether a key may be written; returning `false` rejects the write.
+pub type KeyValidator = Arc<dyn Fn(&[u8]) -> bool + Send + Sync>;
+
+/// Checks a value before it is written; the error message is returned to the caller.
+pub type ValueValidator = Arc<dyn Fn(&[u8]) -> Result<(), String> + Send + Sync>;
+
+/// A 256-bit AES key for databases encrypted at rest.
+pub type EncryptionKey = [u8; 32];
+
+/// Called after every successful commit with the highest timestamp committed so far.
+pub type CommitHook = Arc<dyn Fn(u128) + Send + Sync>;
+
+/// What opening an existing database does with the segments it replayed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OpenMode {
+    /// Writes the replayed state into a fresh segment and removes the old
+    /// ones, so the directory holds a single compact segment afterwards.
+    /// The new segment is written under a temporary name and only renamed
+    /// into place once it is synced, so a crash midway leaves the old
+    /// segments to replay.
+    #[default]
+    Rewrite,
+    /// Like `Rewrite`, but the old segments are kept for debugging, renamed
+    /// with an `.old` extension so they are no longer replayed.
+    RewriteKeepingOld,
+    /// Keeps every segment and appends to the newest one, leaving the
+    /// space taken by overwritten values for `compact` to reclaim. Opening
+    /// large databases is cheaper this way.
+    Reopen,
+}
+
+/// How far `Db::compact_incremental` got.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionProgress {
+    /// Segments merged into others by this call.
+    pub merged: usize,
+    /// Segments still to merge; the compaction is complete once this is 0.
+    pub remaining: usize,
+}
+
+/// Progress reported while `Db::open_with_recovery_callback` replays segments.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecoveryEvent {
+    /// The file does not start with a storage header and was left untouched.
+    ForeignFileSkipped { path: PathBuf },
+    SegmentOpened { path: PathBuf },
+    SegmentReplayed { path: PathBuf, records: u64 },
+    /// The segment ends in a partial record; everything from `offset` on was ignored.
+    TruncationDetected { path: PathBuf, offset: u64, file_len: u64 },
+    /// The record at `offset` failed its checksum; it and everything after it
+    /// were ignored.
+    CorruptionDetected { path: PathBuf, offset: u64 },
+    /// `next` holds a record older than the newest record of `previous`, even
+    /// though it is replayed later. Usually a sign of clock skew or a file
+    /// name collision. Replay still keeps the newest timestamp per key.
+    TimestampOverlap {
+        previous: PathBuf,
+        previous_max: u128,
+        next: PathBuf,
+        next_min: u128,
+    },
+}
+
 pub struct Db {
     pub dir: PathBuf,
+    store: Arc<dyn SegmentStore>,
     mem_table: MemTable,
-    storage: Storage,
+    storage: Option<Storage>,
+    key_validator: Option<KeyValidator>,
+    value_validator: Option<ValueValidator>,
+    commit_hook: Option<CommitHook>,
+    committed_timestamp: u128,
+    tombstones_visible: bool,
+    latencies: Option<LatencyStats>,
+    max_total_bytes: Option<usize>,
+    /// Bytes of live data held only by sealed segments, as the MemTable
+    /// accounted them before the flush that cleared it.
+    flushed_bytes: usize,
+    generation: u64,
+    compressed_keys: HashSet<Vec<u8>>,
+    max_memtable_bytes: Option<usize>,
+    sealed: Vec<SealedSegment>,
+    sync_on_commit: bool,
+    compression_threshold: Option<usize>,
+    encryption_key: Option<EncryptionKey>,
+    segment_naming: SegmentNaming,
+    families: BTreeMap<String, Db>,
+    /// The one file an `open_segment` handle covers, in place of every
+    /// segment in `dir`.
+    only_segment: Option<PathBuf>,
+    parallel_segment_reads: bool,
+    value_cache_capacity: Option<usize>,
+    /// Entries lookups found in sealed segments, see `set_value_cache_capacity`.
+    value_cache: Mutex<LruCache<Entry>>,
+    absent_cache_capacity: Option<usize>,
+    /// Keys lookups found in no sealed segment, see `set_absent_cache_capacity`.
+    absent_cache: Mutex<LruCache<()>>,
+    /// Sealed segments opened by lookups, see `disk_reads`.
+    disk_reads: AtomicU64,
 }
 
-impl Db {
-    pub fn new(dir: PathBuf) -> Db {
-        let storage = match Storage::new(&dir) {
-            Ok(s) => s,
-            Err(e) => {
-                panic!("Error in creating the file {}", e);
-            }
-        };
+/// A segment sealed by a MemTable flush, with a Bloom filter over its keys.
+#[derive(Debug)]
+struct SealedSegment {
+    path: PathBuf,
+    filter: BloomFilter,
+}
 
-        let mem_table = MemTable::new();
+impl fmt::Debug for Db {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Db")
+            .field("dir", &self.dir)
+            .field("mem_table", &self.mem_table)
+            .field("storage", &self.storage)
+            .finish_non_exhaustive()
+    }
+}
 
+impl Db {
+    fn with_parts(dir: PathBuf, storage: Option<Storage>, mem_table: MemTable) -> Db {
         Db {
             dir,
+            store: Arc::new(LocalFs),
             storage,
             mem_table,
+            key_validator: None,
+            value_validator: None,
+            commit_hook: None,
+            committed_timestamp: 0,
+            tombstones_visible: false,
+            latencies: None,
+            max_total_bytes: None,
+            flushed_bytes: 0,
+            generation: 0,
+            compressed_keys: HashSet::new(),
+            max_memtable_bytes: None,
+            sealed: Vec::new(),
+            sync_on_commit: false,
+            compression_threshold: None,
+            segment_naming: SegmentNaming::default(),
+            encryption_key: None,
+            families: BTreeMap::new(),
+            only_segment: None,
+            parallel_segment_reads: false,
+            value_cache_capacity: None,
+            value_cache: Mutex::new(LruCache::new(0)),
+            absent_cache_capacity: None,
+            absent_cache: Mutex::new(LruCache::new(0)),
+            disk_reads: AtomicU64::new(0),
         }
     }
 
-    pub fn init_from_existing(dir: PathBuf) -> io::Result<Db> {
-        let mut mem_table = MemTable::new();
+    /// Creates an empty database with a fresh segment in `dir`, which must
+    /// already exist. Fails instead of panicking if the segment cannot be
+    /// created, e.g. when the directory is missing or read-only.
+    pub fn new(dir: PathBuf) -> Result<Db> {
+        Db::new_with_key(dir, None)
+    }
 
-        let files = scan_dir(&dir).or_else(|e| {if let io::ErrorKind::NotFound = e.kind(){ create_dir(&dir)?; Ok(Vec::new())} else {Err(e)}})?;
-        for file in &files {
-            let data: Vec<Entry> = StorageIterator::new(file)?.collect();
-            for entry in data {
-                if !entry.deleted {
-                    mem_table.set_or_insert(&entry.key, &entry.value.unwrap(), entry.timestamp);
-                } else {
-                    mem_table.delete(&entry.key, entry.timestamp);
+    /// Like `new`, but with `Some(key)` every record is encrypted with
+    /// AES-256-GCM; lengths stay readable, keys and values do not.
+    pub fn new_with_key(dir: PathBuf, key: Option<EncryptionKey>) -> Result<Db> {
+        let mut storage = Storage::new(&dir)?;
+        storage.set_encryption_key(key.as_ref());
+
+        let mem_table = MemTable::new();
+
+        let mut db = Db::with_parts(dir, Some(storage), mem_table);
+        db.encryption_key = key;
+        Ok(db)
+    }
+
+    /// Opens the database in `dir`, creating the directory if it is missing,
+    /// and replays its segments into the MemTable. A segment ending in a
+    /// partial record, as a crash midway through a write leaves it, is not an
+    /// error: the complete records before it are kept and the partial one is
+    /// dropped, see `RecoveryEvent::TruncationDetected`. Only snapshots, which
+    /// are always written whole, report truncation as `DbError::Corrupt`.
+    pub fn init_from_existing(dir: PathBuf) -> Result<Db> {
+        Db::open_dir(dir, None, OpenMode::Rewrite, |_| {})
+    }
+
+    /// Like `init_from_existing`, with `mode` deciding whether the replayed
+    /// segments are rewritten or appended to.
+    pub fn init_from_existing_with_mode(dir: PathBuf, mode: OpenMode) -> Result<Db> {
+        Db::open_dir(dir, None, mode, |_| {})
+    }
+
+    /// Like `init_from_existing` for a database encrypted with `key`. Fails
+    /// with `DbError::Encryption`, before anything is rewritten, if a record
+    /// cannot be decrypted with the key given. Plaintext records left from
+    /// before encryption was turned on are read as they are and encrypted
+    /// when the segments are rewritten.
+    pub fn init_from_existing_with_key(dir: PathBuf, key: Option<EncryptionKey>) -> Result<Db> {
+        Db::open_dir(dir, key, OpenMode::Rewrite, |_| {})
+    }
+
+    /// Same as `init_from_existing`, but reports each step of the replay to
+    /// `on_event` so operators can see what recovery found.
+    pub fn open_with_recovery_callback(
+        dir: PathBuf,
+        on_event: impl FnMut(RecoveryEvent),
+    ) -> Result<Db> {
+        Db::open_dir(dir, None, OpenMode::Rewrite, on_event)
+    }
+
+    /// Opens the database described by `config`, replaying what is already
+    /// in its directory like `init_from_existing` does.
+    pub fn open(config: DbConfig) -> Result<Db> {
+        let mut db = Db::open_dir_in(
+            config.store,
+            config.dir,
+            config.encryption_key,
+            config.mode,
+            config.naming,
+            config.max_merged_on_open,
+            |_| {},
+        )?;
+        db.set_sync(config.sync);
+        db.set_max_memtable_bytes(config.max_memtable_bytes);
+        db.set_max_total_bytes(config.max_total_bytes);
+        db.set_compression_threshold(config.compression_threshold);
+        db.set_value_cache_capacity(config.value_cache_capacity);
+        db.set_parallel_segment_reads(config.parallel_segment_reads);
+        db.set_absent_cache_capacity(config.absent_cache_capacity);
+        Ok(db)
+    }
+
+    fn open_dir(
+        dir: PathBuf,
+        key: Option<EncryptionKey>,
+        mode: OpenMode,
+        on_event: impl FnMut(RecoveryEvent),
+    ) -> Result<Db> {
+        Db::open_dir_in(Arc::new(LocalFs), dir, key, mode, SegmentNaming::default(), None, on_event)
+    }
+
+    fn open_dir_in(
+        store: Arc<dyn SegmentStore>,
+        dir: PathBuf,
+        key: Option<EncryptionKey>,
+        mode: OpenMode,
+        naming: SegmentNaming,
+        max_merged: Option<usize>,
+        mut on_event: impl FnMut(RecoveryEvent),
+    ) -> Result<Db> {
+        let files = scan_dir_in(&*store, &dir).or_else(|e| {if let io::ErrorKind::NotFound = e.kind(){ store.create_dir(&dir)?; Ok(Vec::new())} else {Err(e)}})?;
+        remove_temp_files(&*store, &dir)?;
+        let Replayed {
+            mem_table,
+            mut segments,
+            mut filters,
+            compressed_keys,
+        } = replay(&*store, &files, key.as_ref(), &mut on_event)?;
+
+        let merge_all = max_merged.is_none_or(|max| segments.len() <= max);
+        let mut sealed = Vec::new();
+        let storage = match mode {
+            OpenMode::Rewrite | OpenMode::RewriteKeepingOld if merge_all => {
+                let mut storage = Storage::new_temp_in(store.clone(), &dir, naming)?;
+                storage.set_encryption_key(key.as_ref());
+                write_mem_table(&mut storage, &mem_table, &compressed_keys)?;
+                storage.publish()?;
+
+                // now it is safe to remove old DB files
+                for file in &segments {
+                    if mode == OpenMode::RewriteKeepingOld {
+                        store.rename(file, &file.with_extension("old"))?;
+                    } else {
+                        store.remove(file)?;
+                    }
+                }
+                storage
+            }
+            _ => {
+                if let (OpenMode::Rewrite | OpenMode::RewriteKeepingOld, Some(max @ 1..)) = (mode, max_merged) {
+                    let filter = merge_oldest(&store, &segments[..max], key.as_ref(), mode)?;
+                    segments.drain(..max - 1);
+                    filters.drain(..max);
+                    filters.insert(0, filter);
+                }
+
+                let mut storage = match segments.last() {
+                    Some(last) => match Storage::from_path_in(store.clone(), last) {
+                        Ok(storage) => storage,
+                        // segments of older format versions are never appended to
+                        Err(e) if e.kind() == io::ErrorKind::InvalidData => Storage::new_in(store.clone(), &dir, naming)?,
+                        Err(e) => return Err(e.into()),
+                    },
+                    None => Storage::new_in(store.clone(), &dir, naming)?,
+                };
+                storage.set_encryption_key(key.as_ref());
+
+                // once a flush clears the MemTable, the keys of the older
+                // segments are only found in them
+                for (path, filter) in segments.into_iter().zip(filters) {
+                    if path != storage.path() {
+                        sealed.push(SealedSegment { path, filter });
+                    }
                 }
+                storage
             }
+        };
+
+        let families = open_families(&*store, &dir, |dir| {
+            let mut config = DbConfig::new(dir)
+                .with_encryption_key(key)
+                .with_open_mode(mode)
+                .with_segment_naming(naming)
+                .with_segment_store(store.clone());
+            config.max_merged_on_open = max_merged;
+            Db::open(config)
+        })?;
+        let mut db = Db::with_parts(dir, Some(storage), mem_table);
+        db.store = store;
+        db.compressed_keys = compressed_keys;
+        db.encryption_key = key;
+        db.segment_naming = naming;
+        db.families = families;
+        db.sealed = sealed;
+        Ok(db)
+    }
+
+    /// Opens an existing store for reads only. Segments are replayed into the
+    /// MemTable, but no file is created, rewritten or removed, so this works on
+    /// read-only media. Writes fail with `DbError::ReadOnly`.
+    pub fn open_read_only(dir: PathBuf) -> Result<Db> {
+        Db::open_read_only_in(Arc::new(LocalFs), dir)
+    }
+
+    /// Like `open_read_only`, for a database kept in `store`.
+    pub fn open_read_only_in(store: Arc<dyn SegmentStore>, dir: PathBuf) -> Result<Db> {
+        let files = scan_dir_in(&*store, &dir)?;
+        let mem_table = replay(&*store, &files, None, &mut |_| {})?.mem_table;
+        let families = open_families(&*store, &dir, |dir| Db::open_read_only_in(store.clone(), dir))?;
+        let mut db = Db::with_parts(dir, None, mem_table);
+        db.store = store;
+        db.families = families;
+        Ok(db)
+    }
+
+    /// Opens a single segment file read-only, e.g. to inspect one file without
+    /// replaying the rest of its directory. Only that segment's records are
+    /// visible, `total_records` and `stats` count that file alone and, as
+    /// with `open_read_only`, writes are rejected.
+    pub fn open_segment(path: PathBuf) -> Result<Db> {
+        Db::open_segment_in(Arc::new(LocalFs), path)
+    }
+
+    /// Like `open_segment`, for a segment kept in `store`.
+    pub fn open_segment_in(store: Arc<dyn SegmentStore>, path: PathBuf) -> Result<Db> {
+        let replayed = replay(&*store, std::slice::from_ref(&path), None, &mut |_| {})?;
+        if replayed.segments.is_empty() {
+            return Err(DbError::InvalidFormat(format!("{} is not a storage file", path.display())));
         }
+        let mem_table = replayed.mem_table;
+        let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        let mut db = Db::with_parts(dir, None, mem_table);
+        db.store = store;
+        db.only_segment = Some(path);
+        Ok(db)
+    }
 
-        // create the new storage
-        // suggestion: can continue from the last available file
-        let mut storage = Storage::new(&dir)?;
+    /// The segment files this handle covers, oldest first.
+    fn segment_files(&self) -> io::Result<Vec<PathBuf>> {
+        match &self.only_segment {
+            Some(path) => Ok(vec![path.clone()]),
+            None => scan_dir_in(&*self.store, &self.dir),
+        }
+    }
 
-        for entry in mem_table.get_all() {
-            if !entry.deleted {
-                storage.set(
-                    &entry.key,
-                    &entry.value.as_ref().unwrap(),
-                    false,
-                    entry.timestamp,
-                )?;
-            } else {
-                storage.delete(&entry.key, entry.timestamp)?;
+    fn storage(&mut self) -> Result<&mut Storage> {
+        self.storage.as_mut().ok_or(DbError::ReadOnly)
+    }
+
+    /// Like `init_from_existing`, but also reports whether the store is new:
+    /// the flag is `true` when the directory was missing or held no segments.
+    pub fn open_or_create(dir: PathBuf) -> Result<(Db, bool)> {
+        let created = match scan_dir(&dir) {
+            Ok(files) => files.is_empty(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => true,
+            Err(e) => return Err(e.into()),
+        };
+        Ok((Db::init_from_existing(dir)?, created))
+    }
+
+    /// Installs (or clears) a validator that every written key must satisfy.
+    /// Keys failing validation are rejected with `DbError::InvalidKey` before
+    /// anything reaches storage.
+    pub fn set_key_validator(&mut self, validator: Option<KeyValidator>) {
+        self.key_validator = validator;
+    }
+
+    /// Installs (or clears) a validator that every written value must pass.
+    /// On failure the write is rejected with `DbError::InvalidValue` carrying
+    /// the validator's message.
+    pub fn set_value_validator(&mut self, validator: Option<ValueValidator>) {
+        self.value_validator = validator;
+    }
+
+    fn validate_value(&self, value: &[u8]) -> Result<()> {
+        match &self.value_validator {
+            Some(validator) => validator(value).map_err(|reason| DbError::InvalidValue { reason }),
+            None => Ok(()),
+        }
+    }
+
+    fn validate_key(&self, key: &[u8]) -> Result<()> {
+        match &self.key_validator {
+            Some(validator) if !validator(key) => Err(DbError::InvalidKey),
+            _ => Ok(()),
+        }
+    }
+
+    /// Caps the live data size: every key with its newest value or tombstone,
+    /// as accounted by the MemTable, including keys flushed out of it. Writes
+    /// that would grow the data past the quota fail with
+    /// `DbError::QuotaExceeded`; deletes and shrinking overwrites are always
+    /// allowed.
+    pub fn set_max_total_bytes(&mut self, quota: Option<usize>) {
+        self.max_total_bytes = quota;
+        for family in self.families.values_mut() {
+            family.set_max_total_bytes(quota);
+        }
+    }
+
+    /// The live data size `set_max_total_bytes` caps.
+    fn live_bytes(&self) -> usize {
+        self.mem_table.size() + self.flushed_bytes
+    }
+
+    /// The bytes the newest version of `key` adds to `live_bytes`, 0 if it was
+    /// never written, and whether that version is only in sealed segments.
+    fn stored_size(&self, key: &[u8]) -> Result<(usize, bool)> {
+        let size = |entry: &Entry| key.len() + entry.value.as_ref().map_or(0, |v| v.len()) + 16 + 1;
+        if let Some(entry) = self.mem_table.get(key) {
+            return Ok((size(entry), false));
+        }
+        Ok(self.find(key)?.map_or((0, false), |entry| (size(&entry), true)))
+    }
+
+    /// Checks that `writes` keep the live data within the quota; `None` values
+    /// are deletes, which never grow it. Returns the bytes the written keys
+    /// hold in sealed segments only, which move out of `flushed_bytes` once
+    /// the writes reach the MemTable.
+    fn check_quota<'a>(&self, writes: impl IntoIterator<Item = (&'a [u8], Option<&'a [u8]>)>) -> Result<usize> {
+        let mut growth = 0;
+        let mut superseded = 0;
+        let mut flushed = HashSet::new();
+        for (key, value) in writes {
+            let (stored, only_flushed) = self.stored_size(key)?;
+            if only_flushed && flushed.insert(key) {
+                superseded += stored;
+            }
+            if let Some(value) = value {
+                growth += (key.len() + value.len() + 16 + 1).saturating_sub(stored);
             }
         }
-        storage.commit()?;
 
-        // now it is safe to remove old DB files
-        // delete the files
-        // suggestion: this can be an option from config
-        for file in &files {
-            remove_file(file)?;
+        match self.max_total_bytes {
+            Some(quota) if growth > 0 && self.live_bytes() + growth > quota => Err(DbError::QuotaExceeded {
+                quota,
+                requested: self.live_bytes() + growth,
+            }),
+            _ => Ok(superseded),
         }
+    }
 
-        Ok(Db {
-            dir,
-            storage,
-            mem_table,
-        })
+    /// Flushes the MemTable once it holds more than `max` bytes: the active
+    /// segment is sealed, writes continue in a fresh one and the MemTable is
+    /// cleared. `get` falls back to the sealed segments, newest first, and
+    /// skips those whose Bloom filter rules the key out. Scans, ranges and
+    /// snapshots read the sealed segments back and merge them under the
+    /// MemTable, so they cost a pass over every sealed segment until the next
+    /// open or `compact` merges everything back. `None` (the default) never
+    /// flushes.
+    pub fn set_max_memtable_bytes(&mut self, max: Option<usize>) {
+        self.max_memtable_bytes = max;
+        for family in self.families.values_mut() {
+            family.set_max_memtable_bytes(max);
+        }
     }
 
-    pub fn set(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
-            .as_micros();
+    /// Keeps up to `entries` entries that lookups read from sealed segments in
+    /// memory, evicting the least recently used, so hot keys flushed out of
+    /// the MemTable are read from disk once. Writes need no invalidation while
+    /// the MemTable holds the key, since it is checked first; its entry is
+    /// dropped when the MemTable is flushed. `None` (the default) caches
+    /// nothing. Setting the capacity empties the cache.
+    pub fn set_value_cache_capacity(&mut self, entries: Option<usize>) {
+        self.value_cache_capacity = entries;
+        *self.value_cache.get_mut().unwrap_or_else(PoisonError::into_inner) = LruCache::new(entries.unwrap_or(0));
+        for family in self.families.values_mut() {
+            family.set_value_cache_capacity(entries);
+        }
+    }
+
+    /// Makes lookups that fall through to the sealed segments read every
+    /// segment whose Bloom filter admits the key at once, one thread each,
+    /// and keep the newest entry found, instead of reading them newest first
+    /// until one holds the key. That costs more reads in total but less
+    /// waiting when segments sit on slow storage. Off by default.
+    pub fn set_parallel_segment_reads(&mut self, parallel: bool) {
+        self.parallel_segment_reads = parallel;
+        for family in self.families.values_mut() {
+            family.set_parallel_segment_reads(parallel);
+        }
+    }
 
-        self.storage.set(key, value, false, timestamp)?;
-        self.storage.commit()?;
+    /// Sealed segments read by lookups since the database was opened; lookups
+    /// served by the MemTable or the value cache read none.
+    pub fn disk_reads(&self) -> u64 {
+        self.disk_reads.load(Ordering::Relaxed)
+    }
 
-        self.mem_table.set_or_insert(key, value, timestamp);
+    /// Remembers up to `entries` keys that lookups read sealed segments for
+    /// without finding them, evicting the least recently used, so repeated
+    /// misses that get past the Bloom filters read no segment again. Like the
+    /// value cache, a key is forgotten when the MemTable holding a write to
+    /// it is flushed. `None` (the default) remembers nothing. Setting the
+    /// capacity empties the cache.
+    pub fn set_absent_cache_capacity(&mut self, entries: Option<usize>) {
+        self.absent_cache_capacity = entries;
+        *self.absent_cache.get_mut().unwrap_or_else(PoisonError::into_inner) = LruCache::new(entries.unwrap_or(0));
+        for family in self.families.values_mut() {
+            family.set_absent_cache_capacity(entries);
+        }
+    }
+
+    fn value_cache(&self) -> MutexGuard<'_, LruCache<Entry>> {
+        self.value_cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn absent_cache(&self) -> MutexGuard<'_, LruCache<()>> {
+        self.absent_cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Empties the lookup caches once the sealed segments are replaced.
+    fn clear_caches(&mut self) {
+        self.value_cache.get_mut().unwrap_or_else(PoisonError::into_inner).clear();
+        self.absent_cache.get_mut().unwrap_or_else(PoisonError::into_inner).clear();
+    }
+
+    /// Makes every commit fsync the active segment before returning, trading
+    /// write throughput for durability across power loss. Off by default.
+    pub fn set_sync(&mut self, sync: bool) {
+        self.sync_on_commit = sync;
+        if let Some(storage) = self.storage.as_mut() {
+            storage.set_sync(sync);
+        }
+        for family in self.families.values_mut() {
+            family.set_sync(sync);
+        }
+    }
+
+    /// Stores values of at least `threshold` bytes zstd compressed on disk
+    /// when that makes them smaller; `get` returns them unchanged. `None` (the
+    /// default) leaves values verbatim unless written with `set_compressed`.
+    pub fn set_compression_threshold(&mut self, threshold: Option<usize>) {
+        self.compression_threshold = threshold;
+        if let Some(storage) = self.storage.as_mut() {
+            storage.set_compression_threshold(threshold);
+        }
+        for family in self.families.values_mut() {
+            family.set_compression_threshold(threshold);
+        }
+    }
+
+    /// A new segment in the database directory, in the current sync,
+    /// compression and encryption modes.
+    fn new_storage(&self) -> Result<Storage> {
+        Ok(self.configure(Storage::new_in(self.store.clone(), &self.dir, self.segment_naming)?))
+    }
+
+    /// Like `new_storage`, under a temporary name until it is published.
+    fn new_temp_storage(&self) -> Result<Storage> {
+        Ok(self.configure(Storage::new_temp_in(self.store.clone(), &self.dir, self.segment_naming)?))
+    }
+
+    fn configure(&self, mut storage: Storage) -> Storage {
+        storage.set_sync(self.sync_on_commit);
+        storage.set_compression_threshold(self.compression_threshold);
+        storage.set_encryption_key(self.encryption_key.as_ref());
+        storage
+    }
+
+    fn flush_if_full(&mut self) -> Result<()> {
+        match self.max_memtable_bytes {
+            Some(max) if self.mem_table.size() > max => {}
+            _ => return Ok(()),
+        }
 
+        self.seal_active()?;
+        // lookups of the flushed keys no longer stop at the MemTable
+        let values = self.value_cache.get_mut().unwrap_or_else(PoisonError::into_inner);
+        let absent = self.absent_cache.get_mut().unwrap_or_else(PoisonError::into_inner);
+        for entry in self.mem_table.get_all() {
+            values.remove(&entry.key);
+            absent.remove(&entry.key);
+        }
+        self.flushed_bytes += self.mem_table.size();
+        self.mem_table.purge_mem_table();
+        self.compressed_keys.clear();
         Ok(())
     }
 
-    pub fn instant_set(&mut self, entry: &mut Entry) -> io::Result<()>{
-        self.storage.set(&entry.key, entry.value.as_mut().unwrap(), entry.deleted, entry.timestamp)?;
-        self.storage.commit()?;
+    /// Commits the active segment and continues in a fresh one. The old
+    /// segment is complete and never written again, which makes a clear
+    /// point in time for backups or replication. Unlike the flushes done for
+    /// `set_max_memtable_bytes`, the MemTable is kept as it is.
+    pub fn flush(&mut self) -> Result<()> {
+        self.storage()?.commit()?;
+        self.seal_active()
+    }
 
-        self.mem_table.set_or_insert(&entry.key, entry.value.as_mut().unwrap(), entry.timestamp);
+    /// Swaps the active segment for a fresh one and records it as sealed.
+    fn seal_active(&mut self) -> Result<()> {
+        let fresh = self.new_storage()?;
+        let sealed = std::mem::replace(self.storage()?, fresh);
+        // every key written to the sealed segment is in the MemTable
+        let mut filter = BloomFilter::new(self.mem_table.get_all().len());
+        for entry in self.mem_table.get_all() {
+            filter.insert(&entry.key);
+        }
+        self.sealed.push(SealedSegment {
+            path: sealed.path().to_owned(),
+            filter,
+        });
         Ok(())
     }
 
-    pub fn get(&mut self, key: &[u8]) -> Option<Entry> {
-        if let Some(res) = self.mem_table.get(key) {
-            return Some(Entry {
-                key: res.key.clone(),
-                value: res.value.clone(),
-                timestamp: res.timestamp.clone(),
-                deleted: res.deleted.clone(),
-            });
+    /// Registers a callback invoked after each durable commit, e.g. to let an
+    /// external WAL advance its checkpoint. Replaces any previous callback.
+    pub fn on_commit(&mut self, cb: CommitHook) {
+        self.commit_hook = Some(cb);
+    }
+
+    /// Turns collection of set and flush latencies on or off. Turning it on
+    /// starts from empty statistics.
+    pub fn collect_latencies(&mut self, enabled: bool) {
+        self.latencies = enabled.then(LatencyStats::default);
+    }
+
+    /// Latencies recorded since collection was enabled, or `None` when it is off.
+    pub fn latency_stats(&self) -> Option<LatencyStats> {
+        self.latencies
+    }
+
+    /// Counts of what the database holds in memory and on disk. Many
+    /// tombstones or files compared to live keys suggest a `compact`.
+    pub fn stats(&self) -> Result<DbStats> {
+        let now = now_micros()?;
+        let merged = self.merged()?;
+        let files = self.segment_files()?;
+        let mut disk_bytes = 0;
+        for file in &files {
+            disk_bytes += self.store.open_reader(file)?.seek(SeekFrom::End(0))?;
+        }
+        Ok(DbStats {
+            live_keys: merged.len(now),
+            tombstones: merged.get_all().filter(|e| e.deleted).count(),
+            memtable_bytes: self.mem_table.size(),
+            files: files.len(),
+            disk_bytes,
+            generation: self.generation,
+        })
+    }
+
+    fn commit(&mut self, timestamp: u128) -> Result<()> {
+        let started = Instant::now();
+        self.storage()?.commit()?;
+        self.generation += 1;
+        if let Some(latencies) = self.latencies.as_mut() {
+            latencies.flush.record(started.elapsed());
+        }
+
+        self.committed_timestamp = self.committed_timestamp.max(timestamp);
+        if let Some(hook) = &self.commit_hook {
+            hook(self.committed_timestamp);
+        }
+        Ok(())
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let timestamp = now_micros()?;
+        self.write_value(key, value, timestamp)
+    }
+
+    /// Like `set`, but the value is stored zstd compressed on disk. Worth it
+    /// for large, compressible values; `get` returns the original bytes.
+    pub fn set_compressed(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.validate_key(key)?;
+        self.validate_value(value)?;
+        let superseded = self.check_quota([(key, Some(value))])?;
+
+        let timestamp = now_micros()?;
+        self.storage()?.set_compressed(key, value, timestamp)?;
+        self.commit(timestamp)?;
+
+        self.mem_table.set_or_insert(key, value, timestamp);
+        self.flushed_bytes -= superseded;
+        self.compressed_keys.insert(key.to_owned());
+
+        self.flush_if_full()
+    }
+
+    /// Like `set`, but the key expires `ttl` from now: `get` treats it as
+    /// absent from then on and compaction drops it.
+    pub fn set_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.validate_key(key)?;
+        self.validate_value(value)?;
+        let superseded = self.check_quota([(key, Some(value))])?;
+
+        let timestamp = now_micros()?;
+        let entry = Entry {
+            key: key.to_owned(),
+            value: Some(Arc::new(value.to_owned())),
+            timestamp,
+            deleted: false,
+            expires_at: Some(timestamp + ttl.as_micros()),
+        };
+        self.storage()?.set_entry(&entry, false)?;
+        self.commit(timestamp)?;
+
+        self.mem_table.insert(entry);
+        self.flushed_bytes -= superseded;
+        self.compressed_keys.remove(key);
+
+        self.flush_if_full()
+    }
+
+    /// Gives `key` a fresh expiry `ttl` from now, keeping its value, and
+    /// returns `true`; keys without a TTL get one. Returns `false` without
+    /// writing if the key is absent, deleted or already expired.
+    pub fn extend_ttl(&mut self, key: &[u8], ttl: Duration) -> Result<bool> {
+        match self.live_value(key)? {
+            Some(value) => {
+                self.set_with_ttl(key, &value, ttl)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Sets `key` with a timestamp strictly greater than `barrier`, the timestamp
+    /// of a prior write this one depends on. Returns the assigned timestamp, or
+    /// `ErrorKind::InvalidInput` if no timestamp can be greater than `barrier`.
+    pub fn set_after(&mut self, key: &[u8], value: &[u8], barrier: u128) -> Result<u128> {
+        let after = barrier.checked_add(1).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no timestamp is greater than the barrier")
+        })?;
+        let timestamp = now_micros()?.max(after);
+        self.write_value(key, value, timestamp)?;
+        Ok(timestamp)
+    }
+
+    /// Starts a streaming ingest: pushed writes are buffered and committed in
+    /// groups once the buffer grows past the ingestor's threshold.
+    pub fn ingest(&mut self) -> Ingestor<'_> {
+        Ingestor {
+            db: self,
+            pending: Vec::new(),
+            pending_bytes: 0,
+            threshold: DEFAULT_INGEST_THRESHOLD,
+            commits: 0,
+        }
+    }
+
+    /// Writes all pairs with a single commit, all or nothing: if any record
+    /// fails to reach storage the partial batch is rolled back, the error is
+    /// returned and the MemTable is left untouched.
+    pub fn set_batch_atomic(&mut self, pairs: &[(&[u8], &[u8])]) -> Result<()> {
+        self.write_values(pairs)
+    }
+
+    /// Writes all pairs to the storage buffer and flushes once, which makes
+    /// bulk loads far cheaper than one `set` per pair. Same all-or-nothing
+    /// guarantee as `set_batch_atomic`.
+    pub fn set_batch(&mut self, pairs: &[(&[u8], &[u8])]) -> Result<()> {
+        self.write_values(pairs)
+    }
+
+    /// Deletes all keys with a single commit. If any tombstone fails to reach
+    /// storage the partial batch is rolled back and no key is deleted.
+    pub fn delete_batch(&mut self, keys: &[&[u8]]) -> Result<()> {
+        for key in keys {
+            self.validate_key(key)?;
+        }
+        let superseded = self.check_quota(keys.iter().map(|key| (*key, None)))?;
+
+        let timestamp = now_micros()?;
+        let written = keys
+            .iter()
+            .try_for_each(|key| self.storage()?.delete(key, timestamp).map_err(DbError::from))
+            .and_then(|_| self.commit(timestamp));
+        if let Err(e) = written {
+            self.storage()?.rollback()?;
+            return Err(e);
+        }
+
+        for key in keys {
+            self.mem_table.delete(key, timestamp);
+        }
+        self.flushed_bytes -= superseded;
+
+        self.flush_if_full()
+    }
+
+    fn write_values<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, pairs: &[(K, V)]) -> Result<()> {
+        for (key, value) in pairs {
+            self.validate_key(key.as_ref())?;
+            self.validate_value(value.as_ref())?;
+        }
+        let superseded = self.check_quota(pairs.iter().map(|(key, value)| (key.as_ref(), Some(value.as_ref()))))?;
+
+        let timestamp = now_micros()?;
+        let written = pairs
+            .iter()
+            .try_for_each(|(key, value)| {
+                self.storage()?
+                    .set(key.as_ref(), value.as_ref(), false, timestamp)
+                    .map_err(DbError::from)
+            })
+            .and_then(|_| self.commit(timestamp));
+        if let Err(e) = written {
+            self.storage()?.rollback()?;
+            return Err(e);
+        }
+
+        for (key, value) in pairs {
+            self.mem_table
+                .set_or_insert(key.as_ref(), value.as_ref(), timestamp);
+            self.compressed_keys.remove(key.as_ref());
+        }
+        self.flushed_bytes -= superseded;
+
+        self.flush_if_full()
+    }
+
+    /// Like `set_batch`, with an absolute expiry per entry (`None` never
+    /// expires), e.g. for entries restored by `import_json`.
+    pub(crate) fn set_batch_expiring(&mut self, entries: &[(&[u8], &[u8], Option<u128>)]) -> Result<()> {
+        for (key, value, _) in entries {
+            self.validate_key(key)?;
+            self.validate_value(value)?;
+        }
+        let superseded = self.check_quota(entries.iter().map(|(key, value, _)| (*key, Some(*value))))?;
+
+        let timestamp = now_micros()?;
+        let entries: Vec<Entry> = entries
+            .iter()
+            .map(|(key, value, expires_at)| Entry {
+                key: key.to_vec(),
+                value: Some(Arc::new(value.to_vec())),
+                timestamp,
+                deleted: false,
+                expires_at: *expires_at,
+            })
+            .collect();
+        let written = entries
+            .iter()
+            .try_for_each(|entry| self.storage()?.set_entry(entry, false).map_err(DbError::from))
+            .and_then(|_| self.commit(timestamp));
+        if let Err(e) = written {
+            self.storage()?.rollback()?;
+            return Err(e);
+        }
+
+        for entry in entries {
+            self.compressed_keys.remove(&entry.key);
+            self.mem_table.insert(entry);
+        }
+        self.flushed_bytes -= superseded;
+
+        self.flush_if_full()
+    }
+
+    /// Applies every operation in `batch` with a single commit, all or
+    /// nothing like `set_batch`. Operations share one timestamp and are
+    /// applied in order, so the last operation on a key decides its state,
+    /// also after reopening.
+    pub fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        for op in batch.ops() {
+            match op {
+                BatchOp::Set { key, value } => {
+                    self.validate_key(key)?;
+                    self.validate_value(value)?;
+                }
+                BatchOp::Delete { key } => self.validate_key(key)?,
+            }
+        }
+        let superseded = self.check_quota(batch.ops().iter().map(|op| match op {
+            BatchOp::Set { key, value } => (&key[..], Some(&value[..])),
+            BatchOp::Delete { key } => (&key[..], None),
+        }))?;
+
+        let timestamp = now_micros()?;
+        let written = batch
+            .ops()
+            .iter()
+            .try_for_each(|op| {
+                let storage = self.storage()?;
+                match op {
+                    BatchOp::Set { key, value } => storage.set(key, value, false, timestamp),
+                    BatchOp::Delete { key } => storage.delete(key, timestamp),
+                }
+                .map_err(DbError::from)
+            })
+            .and_then(|_| self.commit(timestamp));
+        if let Err(e) = written {
+            self.storage()?.rollback()?;
+            return Err(e);
+        }
+
+        for op in batch.ops() {
+            match op {
+                BatchOp::Set { key, value } => {
+                    self.mem_table.set_or_insert(key, value, timestamp);
+                    self.compressed_keys.remove(key);
+                }
+                BatchOp::Delete { key } => self.mem_table.delete(key, timestamp),
+            }
+        }
+        self.flushed_bytes -= superseded;
+
+        self.flush_if_full()
+    }
+
+    /// Sets `key` using the caller's timestamp, so a retried request writes the
+    /// same record. Last-writer-wins: when the stored record (live or deleted)
+    /// is as new or newer, nothing is written. Returns whether it applied.
+    pub fn set_with_timestamp(&mut self, key: &[u8], value: &[u8], ts: u128) -> Result<bool> {
+        if self.find(key)?.is_some_and(|e| e.timestamp >= ts) {
+            return Ok(false);
+        }
+        self.write_value(key, value, ts)?;
+        Ok(true)
+    }
+
+    /// Appends `suffix` to the live value of `key` (an absent or deleted key
+    /// counts as empty) and returns the new length. The whole concatenated
+    /// value is written as one record.
+    pub fn append(&mut self, key: &[u8], suffix: &[u8]) -> Result<usize> {
+        let mut value = match self.find(key)? {
+            Some(entry) if !entry.deleted => Arc::unwrap_or_clone(entry.value.unwrap()),
+            _ => Vec::new(),
+        };
+        value.extend_from_slice(suffix);
+
+        self.set(key, &value)?;
+        Ok(value.len())
+    }
+
+    /// Read-modify-write: stores `f` applied to the live value of `key`
+    /// (`None` if it is absent, deleted or expired). Holding `&mut self`
+    /// keeps other writers out between the read and the write.
+    pub fn merge(&mut self, key: &[u8], f: impl FnOnce(Option<&[u8]>) -> Vec<u8>) -> Result<()> {
+        let current = self.live_value(key)?;
+        let value = f(current.as_deref());
+        self.set(key, &value)
+    }
+
+    /// Sets `key` to `new` only if its live value equals `expected`, where
+    /// `None` means the key must be absent, deleted or expired. Returns
+    /// whether the value was swapped.
+    pub fn compare_and_swap(&mut self, key: &[u8], expected: Option<&[u8]>, new: &[u8]) -> Result<bool> {
+        if self.live_value(key)?.as_deref() != expected {
+            return Ok(false);
         }
-        None
+        self.set(key, new)?;
+        Ok(true)
     }
 
-    pub fn get_keys_with_pattern(&mut self, pattern: &[u8]) -> Vec<Entry>{
-        let entries = self.mem_table.get_all();
-        let mut keys: Vec<Entry> = Vec::new();
-        for e in entries {
-            if e.key.starts_with(pattern) {
-                keys.push(e.clone())
-            }
+    /// The live value of `key`: `None` if it is absent, deleted or expired.
+    fn live_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let now = now_micros()?;
+        Ok(self
+            .find(key)?
+            .filter(|entry| entry.is_live(now))
+            .and_then(|entry| entry.value)
+            .map(Arc::unwrap_or_clone))
+    }
+
+    fn write_value(&mut self, key: &[u8], value: &[u8], timestamp: u128) -> Result<()> {
+        self.validate_key(key)?;
+        self.validate_value(value)?;
+        let superseded = self.check_quota([(key, Some(value))])?;
+
+        let started = Instant::now();
+
+        self.storage()?.set(key, value, false, timestamp)?;
+        self.commit(timestamp)?;
+
+        self.mem_table.set_or_insert(key, value, timestamp);
+        self.flushed_bytes -= superseded;
+        self.compressed_keys.remove(key);
+
+        if let Some(latencies) = self.latencies.as_mut() {
+            latencies.set.record(started.elapsed());
+        }
+
+        self.flush_if_full()
+    }
+
+    /// Writes `entry` as given, keeping its timestamp, deleted flag and expiry
+    /// instead of stamping a new write, e.g. when replaying entries from
+    /// another node.
+    pub fn instant_set(&mut self, entry: &mut Entry) -> Result<()> {
+        self.validate_key(&entry.key)?;
+
+        if entry.deleted {
+            let superseded = self.check_quota([(&entry.key[..], None)])?;
+            self.storage()?.delete(&entry.key, entry.timestamp)?;
+            self.commit(entry.timestamp)?;
+
+            self.mem_table.delete(&entry.key, entry.timestamp);
+            self.flushed_bytes -= superseded;
+            return self.flush_if_full();
+        }
+
+        let value = entry.value.as_deref().map(Vec::as_slice).unwrap_or_default();
+        self.validate_value(value)?;
+        let superseded = self.check_quota([(&entry.key[..], Some(value))])?;
+
+        let live = Entry {
+            value: Some(Arc::new(value.to_owned())),
+            ..entry.clone()
+        };
+        self.storage()?.set_entry(&live, false)?;
+        self.commit(entry.timestamp)?;
+
+        self.mem_table.insert(live);
+        self.flushed_bytes -= superseded;
+        self.compressed_keys.remove(&entry.key);
+
+        self.flush_if_full()
+    }
+
+    /// Controls whether `get` returns tombstoned entries (`deleted == true`)
+    /// or treats deleted keys as absent. Tombstones are hidden by default.
+    pub fn set_tombstone_visibility(&mut self, visible: bool) {
+        self.tombstones_visible = visible;
+    }
+
+    /// A counter bumped by every write. External caches can store it next to
+    /// a value and compare it later to cheaply tell whether anything changed.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Like `get`, paired with the generation the read observed.
+    pub fn get_with_generation(&self, key: &[u8]) -> Result<Option<(Entry, u64)>> {
+        Ok(self.lookup(key)?.map(|entry| (entry, self.generation)))
+    }
+
+    /// The entry for `key`, or `None` if it is missing, deleted or expired.
+    /// Fails if a sealed segment that may hold the key cannot be read.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Entry>> {
+        self.lookup(key)
+    }
+
+    /// Resolves several keys at once, in the order given. Deleted and
+    /// missing keys come back as `None`, whatever the tombstone visibility
+    /// setting.
+    pub fn multi_get(&self, keys: &[&[u8]]) -> Result<Vec<Option<Entry>>> {
+        keys.iter()
+            .map(|key| Ok(self.lookup(key)?.filter(|entry| !entry.deleted)))
+            .collect()
+    }
+
+    /// Whether `key` holds a live value, without cloning it. Deleted and
+    /// expired keys count as absent, whatever the tombstone visibility setting.
+    pub fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        let now = now_micros()?;
+        if self.mem_table.contains_key(key, now) {
+            return Ok(true);
+        }
+        // flushed keys are only in the sealed segments
+        if self.mem_table.get(key).is_some() || self.sealed.is_empty() {
+            return Ok(false);
+        }
+        Ok(self.lookup(key)?.is_some_and(|entry| !entry.deleted))
+    }
+
+    /// The timestamp of the newest write to `key`, deletes included, or
+    /// `None` if it was never written. Lets last-writer-wins replication
+    /// decide whether an incoming update is newer without reading the value.
+    pub fn get_timestamp(&self, key: &[u8]) -> Result<Option<u128>> {
+        Ok(self.find(key)?.map(|entry| entry.timestamp))
+    }
+
+    /// Like `get`, but returns tombstones as well, whatever the tombstone
+    /// visibility setting.
+    pub fn get_raw(&self, key: &[u8]) -> Result<Option<Entry>> {
+        self.find(key)
+    }
+
+    fn lookup(&self, key: &[u8]) -> Result<Option<Entry>> {
+        let now = now_micros()?;
+        Ok(self
+            .find(key)?
+            .filter(|res| (!res.deleted || self.tombstones_visible) && !res.is_expired(now)))
+    }
+
+    /// The newest entry for `key`, tombstones included: from the MemTable if
+    /// it is there, otherwise from the lookup caches or the newest sealed
+    /// segment holding the key.
+    fn find(&self, key: &[u8]) -> Result<Option<Entry>> {
+        if let Some(entry) = self.mem_table.get(key) {
+            return Ok(Some(entry.clone()));
+        }
+        if let Some(entry) = self.value_cache().get(key) {
+            return Ok(Some(entry));
+        }
+        if self.absent_cache().get(key).is_some() {
+            return Ok(None);
+        }
+
+        let candidates: Vec<&Path> = self
+            .sealed
+            .iter()
+            .rev()
+            .filter(|s| s.filter.may_contain(key))
+            .map(|s| &*s.path)
+            .collect();
+        let found = if self.parallel_segment_reads && candidates.len() > 1 {
+            self.find_in_segments_parallel(&candidates, key)?
+        } else {
+            self.find_in_segments(&candidates, key)?
+        };
+        match &found {
+            Some(entry) => self.value_cache().insert(key.to_owned(), entry.clone()),
+            // only misses that cost a segment read are worth remembering
+            None if !candidates.is_empty() => self.absent_cache().insert(key.to_owned(), ()),
+            None => {}
+        }
+        Ok(found)
+    }
+
+    /// The entry for `key` in the first of `files` holding it.
+    fn find_in_segments(&self, files: &[&Path], key: &[u8]) -> Result<Option<Entry>> {
+        for file in files {
+            if let Some(entry) = self.find_in_segment(file, key)? {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The newest entry for `key` in any of `files`, read on a thread each.
+    /// On equal timestamps the earlier file wins, as with `find_in_segments`.
+    fn find_in_segments_parallel(&self, files: &[&Path], key: &[u8]) -> Result<Option<Entry>> {
+        let results: Vec<_> = thread::scope(|scope| {
+            let reads: Vec<_> = files
+                .iter()
+                .map(|file| scope.spawn(move || self.find_in_segment(file, key)))
+                .collect();
+            reads
+                .into_iter()
+                .map(|read| read.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+                .collect()
+        });
+
+        let mut newest: Option<Entry> = None;
+        for found in results {
+            if let Some(entry) = found? {
+                if newest.as_ref().is_none_or(|n| entry.timestamp > n.timestamp) {
+                    newest = Some(entry);
+                }
+            }
+        }
+        Ok(newest)
+    }
+
+    /// The newest entry for `key` in the sealed segment `file`.
+    fn find_in_segment(&self, file: &Path, key: &[u8]) -> Result<Option<Entry>> {
+        self.disk_reads.fetch_add(1, Ordering::Relaxed);
+
+        let mut found: Option<Entry> = None;
+        let mut iterator = StorageIterator::open_in(&*self.store, file, self.encryption_key.as_ref())?;
+        for entry in iterator.by_ref().filter(|e| e.key == key) {
+            if found.as_ref().is_none_or(|f| entry.timestamp >= f.timestamp) {
+                found = Some(entry);
+            }
+        }
+        check_decrypted(&iterator, file)?;
+        Ok(found)
+    }
+
+    /// The newest entry of every key, tombstones included: the sealed segments
+    /// replayed oldest first with the MemTable on top. Borrows the MemTable
+    /// when nothing was flushed out of it.
+    fn merged(&self) -> Result<Cow<'_, MemTable>> {
+        if self.sealed.is_empty() {
+            return Ok(Cow::Borrowed(&self.mem_table));
+        }
+
+        let mut merged = MemTable::new();
+        for segment in &self.sealed {
+            let mut iterator = StorageIterator::open_in(&*self.store, &segment.path, self.encryption_key.as_ref())?;
+            for entry in iterator.by_ref() {
+                merged.insert_if_newer(entry);
+            }
+            check_decrypted(&iterator, &segment.path)?;
+        }
+        for entry in self.mem_table.get_all() {
+            merged.insert(entry.clone());
+        }
+        Ok(Cow::Owned(merged))
+    }
+
+    /// Stale-while-revalidate read: returns the current entry right away and,
+    /// when it is older than `max_age`, refreshes it through `refresh`.
+    /// `Some(value)` from `refresh` overwrites the key and `None` deletes it.
+    /// The refresh runs synchronously, but callers still receive the value
+    /// they would have seen before it.
+    pub fn get_swr(
+        &mut self,
+        key: &[u8],
+        max_age: Duration,
+        refresh: impl FnOnce(&[u8]) -> io::Result<Option<Vec<u8>>>,
+    ) -> Result<Option<Entry>> {
+        let current = match self.get(key)? {
+            Some(entry) if !entry.deleted => entry,
+            _ => return Ok(None),
+        };
+
+        let age = now_micros()?.saturating_sub(current.timestamp);
+        if age > max_age.as_micros() {
+            match refresh(key)? {
+                Some(value) => self.set(key, &value)?,
+                None => self.delete(key)?,
+            }
+        }
+
+        Ok(Some(current))
+    }
+
+    /// The live entries whose key starts with `pattern`, in key order. The
+    /// MemTable is sorted, so this seeks to the first candidate and stops at
+    /// the first key past the prefix.
+    pub fn get_keys_with_pattern(&self, pattern: &[u8]) -> Result<Vec<Entry>> {
+        let now = now_micros()?;
+        Ok(self
+            .merged()?
+            .range(Bound::Included(pattern), Bound::Unbounded)
+            .take_while(|e| e.key.starts_with(pattern))
+            .filter(|e| e.is_live(now))
+            .cloned()
+            .collect())
+    }
+
+    /// The live entries where `start <= key < end`, in key order. An empty
+    /// `start` reads from the first key and an empty `end` up to the last.
+    pub fn range(&self, start: &[u8], end: &[u8]) -> Result<Vec<Entry>> {
+        Ok(live_range(&*self.merged()?, start, end, now_micros()?).cloned().collect())
+    }
+
+    /// Clears `out` and fills it with the live entries where `start <= key < end`,
+    /// so callers can reuse one buffer across requests.
+    pub fn range_into(&self, start: &[u8], end: &[u8], out: &mut Vec<Entry>) -> Result<()> {
+        out.clear();
+        out.extend(live_range(&*self.merged()?, start, end, now_micros()?).cloned());
+        Ok(())
+    }
+
+    /// Collects live entries where `start <= key < end` until their combined
+    /// key and value bytes would exceed `max_total_bytes`. The flag is `true`
+    /// when the result was cut short, in which case the caller can continue
+    /// from just after the last key returned.
+    pub fn range_bounded(&self, start: &[u8], end: &[u8], max_total_bytes: usize) -> Result<(Vec<Entry>, bool)> {
+        let merged = self.merged()?;
+        let mut entries = Vec::new();
+        let mut total = 0;
+        for entry in live_range(&merged, start, end, now_micros()?) {
+            total += entry.key.len() + entry.value.as_ref().map_or(0, |v| v.len());
+            if total > max_total_bytes {
+                return Ok((entries, true));
+            }
+            entries.push(entry.clone());
+        }
+        Ok((entries, false))
+    }
+
+    /// Applies `f` to each live entry where `start <= key < end`, e.g. to pull
+    /// out part of a composite key without cloning whole entries.
+    pub fn range_map<T>(&self, start: &[u8], end: &[u8], f: impl Fn(&Entry) -> T) -> Result<Vec<T>> {
+        Ok(live_range(&*self.merged()?, start, end, now_micros()?).map(f).collect())
+    }
+
+    /// The live keys in key order, without their values.
+    pub fn keys(&self) -> Result<Vec<Vec<u8>>> {
+        let now = now_micros()?;
+        Ok(self
+            .merged()?
+            .get_all()
+            .filter(|e| e.is_live(now))
+            .map(|e| e.key.clone())
+            .collect())
+    }
+
+    /// Number of live keys; tombstones and expired keys are not counted.
+    pub fn len(&self) -> Result<usize> {
+        Ok(self.merged()?.len(now_micros()?))
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// The live entries in key order. Unless segments were flushed out of the
+    /// MemTable, each is cloned as it is yielded.
+    pub fn iter(&self) -> Result<impl Iterator<Item = Entry> + '_> {
+        let entries: Box<dyn Iterator<Item = Entry>> = match self.merged()? {
+            Cow::Borrowed(mem_table) => Box::new(mem_table.get_all().cloned()),
+            Cow::Owned(mem_table) => Box::new(mem_table.into_entries()),
+        };
+        let now = now_micros()?;
+        Ok(entries.filter(move |e| e.is_live(now)))
+    }
+
+    /// Every tombstoned key with its delete timestamp, in key order. Handy when
+    /// working out why the store isn't shrinking.
+    pub fn tombstones(&self) -> Result<Vec<(Vec<u8>, u128)>> {
+        Ok(self
+            .merged()?
+            .get_all()
+            .filter(|e| e.deleted)
+            .map(|e| (e.key.clone(), e.timestamp))
+            .collect())
+    }
+
+    pub fn count_tombstones(&self) -> Result<usize> {
+        Ok(self.merged()?.get_all().filter(|e| e.deleted).count())
+    }
+
+    /// Keys whose expiry has passed but that were not deleted, in key order,
+    /// flushed ones included. They are hidden from every read, but their
+    /// records take up memory and disk until `compact`.
+    pub fn expired_keys(&self) -> Result<Vec<Vec<u8>>> {
+        let now = now_micros()?;
+        Ok(self
+            .merged()?
+            .get_all()
+            .filter(|e| !e.deleted && e.is_expired(now))
+            .map(|e| e.key.clone())
+            .collect())
+    }
+
+    /// Tombstones those of `keys` that are still expired, with a single
+    /// commit, and returns how many that was. Keys written again since they
+    /// were listed by `expired_keys` are left alone. The tombstones stay, like
+    /// any other, until `compact` drops them along with the expired records.
+    pub fn remove_expired(&mut self, keys: &[Vec<u8>]) -> Result<usize> {
+        let now = now_micros()?;
+        let mut expired: Vec<&[u8]> = Vec::new();
+        for key in keys {
+            if self.find(key)?.is_some_and(|e| !e.deleted && e.is_expired(now)) {
+                expired.push(key);
+            }
+        }
+        if expired.is_empty() {
+            return Ok(0);
+        }
+
+        self.delete_batch(&expired)?;
+        Ok(expired.len())
+    }
+
+    /// Every live key with its timestamp, in key order. Replicas can diff their
+    /// digests to find out which values need transferring.
+    pub fn key_digest(&self) -> Result<Vec<(Vec<u8>, u128)>> {
+        let now = now_micros()?;
+        Ok(self
+            .merged()?
+            .get_all()
+            .filter(|e| e.is_live(now))
+            .map(|e| (e.key.clone(), e.timestamp))
+            .collect())
+    }
+
+    /// Sets `key` in the column family `cf`, creating the family on first
+    /// use. Each family is a keyspace of its own, with its own MemTable and
+    /// segments in the subdirectory `cf` of the database directory, and is
+    /// reopened along with the database, in the same `OpenMode`. Families
+    /// share the database's encryption key and follow its sync, compression,
+    /// flush and quota settings; the quota applies to each family on its own.
+    pub fn set_cf(&mut self, cf: &str, key: &[u8], value: &[u8]) -> Result<()> {
+        self.family(cf)?.set(key, value)
+    }
+
+    /// Like `get`, within the column family `cf`; `None` if it doesn't exist.
+    pub fn get_cf(&self, cf: &str, key: &[u8]) -> Result<Option<Entry>> {
+        match self.families.get(cf) {
+            Some(family) => family.get(key),
+            None => Ok(None),
+        }
+    }
+
+    pub fn delete_cf(&mut self, cf: &str, key: &[u8]) -> Result<()> {
+        self.family(cf)?.delete(key)
+    }
+
+    /// The names of the column families, in order.
+    pub fn column_families(&self) -> impl Iterator<Item = &str> {
+        self.families.keys().map(String::as_str)
+    }
+
+    fn family(&mut self, cf: &str) -> Result<&mut Db> {
+        if !self.families.contains_key(cf) {
+            if cf.is_empty() || cf == "." || cf == ".." || cf.contains(['/', '\\']) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid column family name {:?}", cf),
+                )
+                .into());
+            }
+            self.storage()?;
+
+            let mut family = Db::open(
+                DbConfig::new(self.dir.join(cf))
+                    .with_encryption_key(self.encryption_key)
+                    .with_segment_naming(self.segment_naming)
+                    .with_segment_store(self.store.clone()),
+            )?;
+            family.set_sync(self.sync_on_commit);
+            family.set_compression_threshold(self.compression_threshold);
+            family.set_max_memtable_bytes(self.max_memtable_bytes);
+            family.set_max_total_bytes(self.max_total_bytes);
+            family.set_value_cache_capacity(self.value_cache_capacity);
+            family.set_parallel_segment_reads(self.parallel_segment_reads);
+            family.set_absent_cache_capacity(self.absent_cache_capacity);
+            self.families.insert(cf.to_owned(), family);
+        }
+        Ok(self.families.get_mut(cf).unwrap())
+    }
+
+    /// Returns the live entries whose value satisfies `value_pred`, in key order.
+    /// This walks every entry.
+    pub fn scan(&self, value_pred: impl Fn(&[u8]) -> bool) -> Result<Vec<Entry>> {
+        let now = now_micros()?;
+        Ok(self
+            .merged()?
+            .get_all()
+            .filter(|e| e.is_live(now) && e.value.as_deref().is_some_and(|v| value_pred(v)))
+            .cloned()
+            .collect())
+    }
+
+    /// Returns up to `limit` entries (tombstones included) with `timestamp > after`,
+    /// oldest first, plus the cursor to pass as `after` for the next page.
+    /// The cursor is `None` once there are no newer entries left. A page never
+    /// ends between two entries sharing a timestamp, so it may exceed `limit`.
+    pub fn changes_page(&self, after: u128, limit: usize) -> Result<(Vec<Entry>, Option<u128>)> {
+        let merged = self.merged()?;
+        let mut changes: Vec<&Entry> = merged
+            .get_all()
+            .filter(|e| e.timestamp > after)
+            .collect();
+        changes.sort_by_key(|e| e.timestamp);
+
+        let mut end = limit.min(changes.len());
+        while end > 0 && end < changes.len() && changes[end].timestamp == changes[end - 1].timestamp {
+            end += 1;
+        }
+
+        let cursor = if end < changes.len() {
+            Some(end.checked_sub(1).map_or(after, |last| changes[last].timestamp))
+        } else {
+            None
+        };
+        Ok((changes[..end].iter().map(|e| (*e).clone()).collect(), cursor))
+    }
+
+    /// Returns up to `limit` live entries with `key < before` (all keys when
+    /// `None`), largest key first, plus the cursor to pass as `before` for the
+    /// next page: the smallest key returned, or `None` once nothing is left.
+    pub fn scan_rev(&self, before: Option<&[u8]>, limit: usize) -> Result<(Vec<Entry>, Option<Vec<u8>>)> {
+        let end = before.map_or(Bound::Unbounded, Bound::Excluded);
+        let now = now_micros()?;
+        let mut page: Vec<Entry> = self
+            .merged()?
+            .range(Bound::Unbounded, end)
+            .rev()
+            .filter(|e| e.is_live(now))
+            .take(limit + 1)
+            .cloned()
+            .collect();
+
+        let cursor = if page.len() > limit {
+            page.truncate(limit);
+            page.last().map(|e| e.key.clone())
+        } else {
+            None
+        };
+        Ok((page, cursor))
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.validate_key(key)?;
+        let superseded = self.check_quota([(key, None)])?;
+
+        let timestamp = now_micros()?;
+
+        self.storage()?.delete(key, timestamp)?;
+
+        self.commit(timestamp)?;
+
+        self.mem_table.delete(key, timestamp);
+        self.flushed_bytes -= superseded;
+
+        self.flush_if_full()
+    }
+
+    /// Counts every record across all segments, superseded versions and
+    /// tombstones included. Compared with the live key count this shows how
+    /// much compaction would reclaim.
+    pub fn total_records(&self) -> Result<u64> {
+        let mut total = 0;
+        for file in self.segment_files()? {
+            total += StorageIterator::open_in(&*self.store, &file, None)?.count_records()?;
+        }
+        Ok(total)
+    }
+
+    /// Releases MemTable memory held by tombstones, e.g. after deleting many
+    /// keys. Every delete is committed to storage before it reaches the
+    /// MemTable, so this is safe at any time: deleted keys stay deleted across
+    /// restarts. Dropped tombstones are no longer visible in memory though,
+    /// not to `get` with tombstone visibility on and not to `changes_page`.
+    ///
+    /// Once segments have been sealed by a MemTable flush, tombstones are what
+    /// hide the older values in them, so they are kept.
+    pub fn shrink(&mut self) {
+        if self.sealed.is_empty() {
+            self.mem_table.shrink_to_fit();
+        }
+    }
+
+    /// Tombstones every live entry written before `cutoff` and returns how many
+    /// were removed, implementing a simple retention window. The tombstones
+    /// are written like `delete_batch` writes them: all or none.
+    pub fn purge_older_than(&mut self, cutoff: u128) -> Result<usize> {
+        let expired: Vec<Vec<u8>> = self
+            .merged()?
+            .get_all()
+            .filter(|e| !e.deleted && e.timestamp < cutoff)
+            .map(|e| e.key.clone())
+            .collect();
+        if expired.is_empty() {
+            return Ok(0);
+        }
+
+        let keys: Vec<&[u8]> = expired.iter().map(Vec::as_slice).collect();
+        self.delete_batch(&keys)?;
+        Ok(expired.len())
+    }
+
+    pub fn get_snapshot(&self) -> Result<Vec<u8>> {
+        let mut snapshot: Vec<u8> = Vec::new();
+        encode_snapshot_header(&mut snapshot);
+        for data in self.merged()?.get_all() {
+            if !data.deleted {
+                encode_snapshot_record(data, &mut snapshot);
+            }
+        }
+        Ok(snapshot)
+    }
+
+    /// Returns the next piece of a snapshot: live entries with keys after
+    /// `cursor` (from the start when `None`), in key order, up to `max_bytes`
+    /// of snapshot data. At least one entry is returned so a transfer always
+    /// makes progress. The second value is the cursor to resume from, `None`
+    /// once the snapshot is complete. Concatenated chunks form a snapshot
+    /// accepted by `set_snapshot`; the first chunk carries its header.
+    pub fn snapshot_chunk(&self, cursor: Option<Vec<u8>>, max_bytes: usize) -> Result<(Vec<u8>, Option<Vec<u8>>)> {
+        let start = cursor.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+
+        let mut chunk: Vec<u8> = Vec::new();
+        if cursor.is_none() {
+            encode_snapshot_header(&mut chunk);
+        }
+        let mut last = None;
+        for data in self.merged()?.range(start, Bound::Unbounded).filter(|e| !e.deleted) {
+            let record = snapshot_record_len(data);
+            if last.is_some() && chunk.len() + record > max_bytes {
+                return Ok((chunk, last));
+            }
+            encode_snapshot_record(data, &mut chunk);
+            last = Some(data.key.clone());
+        }
+        Ok((chunk, None))
+    }
+
+    /// Loads a snapshot produced by `get_snapshot` or `snapshot_chunk`. Every
+    /// record is checked before any of it is kept: a snapshot without the
+    /// snapshot header or from another format version fails with
+    /// `DbError::InvalidFormat`, a truncated or damaged one with
+    /// `DbError::Corrupt`, and either leaves the database as it was.
+    pub fn set_snapshot(&mut self, raw_data: Vec<u8>) -> Result<()> {
+        let mut rest = snapshot_records(&raw_data)?;
+        let storage = self.storage()?;
+        storage.commit()?;
+
+        while !rest.is_empty() {
+            let record = framed_len(rest)
+                .filter(|&len| len <= rest.len() as u64)
+                .map(|len| rest.split_at(len as usize));
+            let appended = match record {
+                Some((record, tail)) => storage.append_raw(record).map(|_| tail),
+                None => Err(io::Error::new(io::ErrorKind::InvalidData, "truncated record")),
+            };
+            match appended {
+                Ok(tail) => rest = tail,
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    storage.rollback()?;
+                    let offset = raw_data.len() - rest.len();
+                    return Err(DbError::Corrupt(format!("snapshot at byte {}: {}", offset, e)));
+                }
+                Err(e) => {
+                    storage.rollback()?;
+                    return Err(e.into());
+                }
+            }
+        }
+        storage.commit()?;
+
+        let files = scan_dir_in(&*self.store, &self.dir)?;
+        let mut iterator = StorageIterator::open_in(&*self.store, files.last().unwrap(), self.encryption_key.as_ref())?;
+        let data: Vec<Entry> = iterator.by_ref().collect();
+        check_decrypted(&iterator, files.last().unwrap())?;
+        // loaded entries are not held to the quota
+        let superseded = self.check_quota(data.iter().map(|entry| (&entry.key[..], None)))?;
+        for entry in data {
+            self.mem_table.insert(entry);
+        }
+        self.flushed_bytes -= superseded;
+        self.generation += 1;
+
+        Ok(())
+    }
+
+    /// Writes `get_snapshot` to `path`, preceded by a header holding a magic
+    /// number, the snapshot file version and the entry count.
+    pub fn save_snapshot(&self, path: &Path) -> Result<()> {
+        let snapshot = self.get_snapshot()?;
+        let count = count_snapshot_records(snapshot_records(&snapshot)?);
+        let mut file = Vec::with_capacity(SNAPSHOT_FILE_HEADER_SIZE + snapshot.len());
+        file.extend_from_slice(SNAPSHOT_FILE_MAGIC);
+        file.extend_from_slice(&SNAPSHOT_FILE_VERSION.to_le_bytes());
+        file.extend_from_slice(&count.to_le_bytes());
+        file.extend_from_slice(&snapshot);
+        fs::write(path, file)?;
+        Ok(())
+    }
+
+    /// Loads a file written by `save_snapshot`. A file that is not a snapshot
+    /// of a known version fails with `DbError::InvalidFormat`, and one whose
+    /// records don't match the entry count with `DbError::Corrupt`; nothing is
+    /// loaded in either case.
+    pub fn load_snapshot(&mut self, path: &Path) -> Result<()> {
+        let mut file = fs::read(path)?;
+        if file.len() < SNAPSHOT_FILE_HEADER_SIZE || &file[..8] != SNAPSHOT_FILE_MAGIC {
+            return Err(DbError::InvalidFormat(format!("{} is not a snapshot file", path.display())));
+        }
+        let version = u16::from_le_bytes(file[8..10].try_into().unwrap());
+        if version != SNAPSHOT_FILE_VERSION {
+            return Err(DbError::InvalidFormat(format!("unsupported snapshot file version {}", version)));
+        }
+        let expected = u64::from_le_bytes(file[10..SNAPSHOT_FILE_HEADER_SIZE].try_into().unwrap());
+
+        let snapshot = file.split_off(SNAPSHOT_FILE_HEADER_SIZE);
+        let found = count_snapshot_records(snapshot_records(&snapshot)?);
+        if found != expected {
+            return Err(DbError::Corrupt(format!(
+                "snapshot file holds {} entries, its header claims {}",
+                found, expected
+            )));
+        }
+        self.set_snapshot(snapshot)
+    }
+
+    /// Merges every segment in the directory into a single new one holding
+    /// only the newest version of each live key; tombstones are dropped along
+    /// with the values they hid. The new segment is written under a temporary
+    /// name, which replay ignores, and only renamed into place once it is
+    /// synced. The old segments are removed after that, oldest first, so a
+    /// crash at any point leaves data that replays to the same state.
+    pub fn compact(&mut self) -> Result<()> {
+        self.compact_segments()?;
+        for family in self.families.values_mut() {
+            family.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Compacts step by step until `budget` is used up, so a compaction never
+    /// stalls writers for long; call it again to continue. Each step merges
+    /// the two oldest segments into one that takes the newer one's name,
+    /// keeping tombstones and expired records, since newer segments may still
+    /// depend on them. The last step merges the remaining sealed segment with
+    /// the active one like `compact` does, dropping both. At least one step
+    /// runs per call. Progress lives in the segments themselves, so a
+    /// compaction also continues after reopening with `OpenMode::Reopen`.
+    /// Column families are left to `compact`.
+    pub fn compact_incremental(&mut self, budget: Duration) -> Result<CompactionProgress> {
+        let started = Instant::now();
+        self.storage()?.commit()?;
+
+        let mut merged = 0;
+        while !self.sealed.is_empty() {
+            if merged > 0 && started.elapsed() >= budget {
+                break;
+            }
+            if self.sealed.len() == 1 {
+                self.compact_segments()?;
+            } else {
+                self.merge_oldest_segments()?;
+            }
+            merged += 1;
+        }
+        Ok(CompactionProgress {
+            merged,
+            remaining: self.sealed.len(),
+        })
+    }
+
+    /// Merges the two oldest sealed segments into one, published in place of
+    /// the newer of them, then removes the older.
+    fn merge_oldest_segments(&mut self) -> Result<()> {
+        let files = [self.sealed[0].path.clone(), self.sealed[1].path.clone()];
+        let Replayed {
+            mem_table,
+            compressed_keys,
+            ..
+        } = replay(&*self.store, &files, self.encryption_key.as_ref(), &mut |_| {})?;
+
+        let mut storage = self.configure(Storage::replacing_in(self.store.clone(), &files[1])?);
+        write_mem_table(&mut storage, &mem_table, &compressed_keys)?;
+        storage.publish()?;
+        self.store.remove(&files[0])?;
+
+        let mut filter = BloomFilter::new(mem_table.get_all().len());
+        for entry in mem_table.get_all() {
+            filter.insert(&entry.key);
+        }
+        self.sealed.remove(0);
+        self.sealed[0].filter = filter;
+        Ok(())
+    }
+
+    /// `compact` without the column families.
+    fn compact_segments(&mut self) -> Result<()> {
+        self.storage()?.commit()?;
+
+        let files = scan_dir_in(&*self.store, &self.dir)?;
+        let Replayed {
+            mut mem_table,
+            segments,
+            compressed_keys,
+            ..
+        } = replay(&*self.store, &files, self.encryption_key.as_ref(), &mut |_| {})?;
+        mem_table.shrink_to_fit();
+        mem_table.remove_expired(now_micros()?);
+
+        let mut storage = self.new_temp_storage()?;
+        write_mem_table(&mut storage, &mem_table, &compressed_keys)?;
+        storage.publish()?;
+
+        drop(std::mem::replace(self.storage()?, storage));
+        for file in &segments {
+            self.store.remove(file)?;
+        }
+
+        self.mem_table = mem_table;
+        self.compressed_keys = compressed_keys;
+        self.sealed.clear();
+        self.clear_caches();
+        self.flushed_bytes = 0;
+        self.generation += 1;
+        Ok(())
+    }
+
+    pub fn purge_database(&mut self) -> Result<()> {
+        self.storage()?.purge_storage()?;
+        for segment in self.sealed.drain(..) {
+            self.store.remove(&segment.path)?;
+        }
+        self.clear_caches();
+        self.mem_table.purge_mem_table();
+        self.flushed_bytes = 0;
+        self.generation += 1;
+
+        for family in self.families.values_mut() {
+            family.purge_database()?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes every MemTable entry, tombstones included, to `storage`, keeping
+/// `compressed_keys` compressed. The caller commits.
+fn write_mem_table(storage: &mut Storage, mem_table: &MemTable, compressed_keys: &HashSet<Vec<u8>>) -> Result<()> {
+    for entry in mem_table.get_all() {
+        storage.set_entry(entry, compressed_keys.contains(&entry.key))?;
+    }
+    Ok(())
+}
+
+/// The entries of `mem_table` live at `now` where `start <= key < end`, in
+/// key order. An empty `end` reads up to the last key, as in `Db::range`.
+fn live_range<'a>(
+    mem_table: &'a MemTable,
+    start: &'a [u8],
+    end: &'a [u8],
+    now: u128,
+) -> impl Iterator<Item = &'a Entry> {
+    let end = if end.is_empty() {
+        Bound::Unbounded
+    } else {
+        Bound::Excluded(end)
+    };
+    mem_table
+        .range(Bound::Included(start), end)
+        .filter(move |e| e.is_live(now))
+}
+
+const SNAPSHOT_MAGIC: &[u8; 4] = b"RRSN";
+/// Snapshots carry records in the storage layout, so this must be bumped
+/// whenever that layout changes.
+const SNAPSHOT_VERSION: u16 = 1;
+
+const SNAPSHOT_FILE_MAGIC: &[u8; 8] = b"RRDBSNAP";
+const SNAPSHOT_FILE_VERSION: u16 = 1;
+/// Magic, version and entry count.
+const SNAPSHOT_FILE_HEADER_SIZE: usize = 8 + 2 + 8;
+
+fn encode_snapshot_header(snapshot: &mut Vec<u8>) {
+    snapshot.extend_from_slice(SNAPSHOT_MAGIC);
+    snapshot.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+}
+
+/// Checks the magic and version at the start of `snapshot` and returns the
+/// records that follow them.
+fn snapshot_records(snapshot: &[u8]) -> Result<&[u8]> {
+    let body = snapshot
+        .strip_prefix(&SNAPSHOT_MAGIC[..])
+        .ok_or_else(|| DbError::InvalidFormat("not a snapshot".to_owned()))?;
+    let version = body
+        .get(..2)
+        .map(|version| u16::from_le_bytes(version.try_into().unwrap()))
+        .ok_or_else(|| DbError::InvalidFormat("truncated snapshot header".to_owned()))?;
+    if version != SNAPSHOT_VERSION {
+        return Err(DbError::InvalidFormat(format!("unsupported snapshot version {}", version)));
+    }
+    Ok(&body[2..])
+}
+
+/// Number of complete records at the start of `snapshot`.
+fn count_snapshot_records(mut snapshot: &[u8]) -> u64 {
+    let mut count = 0;
+    while let Some(len) = framed_len(snapshot).filter(|&len| len <= snapshot.len() as u64) {
+        snapshot = &snapshot[len as usize..];
+        count += 1;
+    }
+    count
+}
+
+fn snapshot_record_len(data: &Entry) -> usize {
+    let expiry = data.expires_at.map_or(0, |_| EXPIRY_SIZE);
+    record_len(data.key.len(), data.value.as_ref().map_or(0, |v| v.len())) + expiry
+}
+
+fn encode_snapshot_record(data: &Entry, snapshot: &mut Vec<u8>) {
+    let flags = if data.deleted { FLAG_DELETED } else { 0 };
+    encode_record(
+        &data.key,
+        data.value.as_deref().map(Vec::as_slice).unwrap_or_default(),
+        flags,
+        data.timestamp,
+        data.expires_at,
+        snapshot,
+    );
+}
+
+/// Removes segments a crash left under their temporary name. They were never
+/// published, so the segments they were meant to replace are still there.
+fn remove_temp_files(store: &dyn SegmentStore, dir: &Path) -> Result<()> {
+    for path in store.list(dir)? {
+        if path.extension().is_some_and(|ext| ext == TEMP_EXTENSION) {
+            store.remove(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Opens the column families of the database in `dir`, one per subdirectory.
+fn open_families(
+    store: &dyn SegmentStore,
+    dir: &Path,
+    open: impl Fn(PathBuf) -> Result<Db>,
+) -> Result<BTreeMap<String, Db>> {
+    let mut families = BTreeMap::new();
+    for path in store.list_dirs(dir)? {
+        let name = path.file_name().and_then(|name| name.to_str()).map(str::to_owned);
+        if let Some(name) = name {
+            families.insert(name, open(path)?);
+        }
+    }
+    Ok(families)
+}
+
+/// Merges `segments`, the oldest segments of a directory, into the newest of
+/// them and removes the others, or renames them as `OpenMode::RewriteKeepingOld`
+/// says. The merged segment keeps its position in replay order. Returns a
+/// Bloom filter over its keys.
+fn merge_oldest(
+    store: &Arc<dyn SegmentStore>,
+    segments: &[PathBuf],
+    key: Option<&EncryptionKey>,
+    mode: OpenMode,
+) -> Result<BloomFilter> {
+    let (newest, older) = segments.split_last().expect("no segments to merge");
+    let Replayed {
+        mem_table,
+        compressed_keys,
+        ..
+    } = replay(&**store, segments, key, &mut |_| {})?;
+
+    let mut storage = Storage::replacing_in(store.clone(), newest)?;
+    storage.set_encryption_key(key);
+    write_mem_table(&mut storage, &mem_table, &compressed_keys)?;
+    if mode == OpenMode::RewriteKeepingOld {
+        let mut old = store.create_writer(&newest.with_extension("old"))?;
+        io::copy(&mut store.open_reader(newest)?, &mut old)?;
+        old.sync()?;
+    }
+    storage.publish()?;
+
+    for file in older {
+        if mode == OpenMode::RewriteKeepingOld {
+            store.rename(file, &file.with_extension("old"))?;
+        } else {
+            store.remove(file)?;
+        }
+    }
+
+    let mut filter = BloomFilter::new(mem_table.get_all().len());
+    for entry in mem_table.get_all() {
+        filter.insert(&entry.key);
+    }
+    Ok(filter)
+}
+
+/// What `replay` recovered from a set of segment files.
+struct Replayed {
+    mem_table: MemTable,
+    /// The files that were actually replayed; foreign files are left out.
+    segments: Vec<PathBuf>,
+    /// A Bloom filter over the keys of each of `segments`.
+    filters: Vec<BloomFilter>,
+    /// Live keys whose newest value was stored compressed.
+    compressed_keys: HashSet<Vec<u8>>,
+}
+
+/// Fails with `DbError::Encryption` if `iterator` stopped at a record it could
+/// not decrypt, since what follows it was never read.
+fn check_decrypted(iterator: &StorageIterator, path: &Path) -> Result<()> {
+    match iterator.undecryptable_at() {
+        Some(offset) => Err(DbError::Encryption(format!(
+            "cannot decrypt the record at byte {} of {}: missing or wrong encryption key",
+            offset,
+            path.display()
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Replays `files` in order. Files without a storage header are skipped.
+fn replay(
+    store: &dyn SegmentStore,
+    files: &[PathBuf],
+    key: Option<&EncryptionKey>,
+    on_event: &mut impl FnMut(RecoveryEvent),
+) -> Result<Replayed> {
+    let mut mem_table = MemTable::new();
+    let mut segments = Vec::new();
+    let mut filters = Vec::new();
+    let mut compressed_keys = HashSet::new();
+
+    let mut previous: Option<(&PathBuf, u128)> = None;
+
+    for file in files {
+        let mut iterator = match StorageIterator::open_in(store, file, key) {
+            Ok(iterator) => iterator,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                on_event(RecoveryEvent::ForeignFileSkipped { path: file.clone() });
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        on_event(RecoveryEvent::SegmentOpened { path: file.clone() });
+        segments.push(file.clone());
+
+        let mut records = 0;
+        let mut min_timestamp = u128::MAX;
+        let mut max_timestamp = 0;
+        let mut keys = Vec::new();
+        while let Some((entry, compressed)) = iterator.next_record() {
+            min_timestamp = min_timestamp.min(entry.timestamp);
+            max_timestamp = max_timestamp.max(entry.timestamp);
+
+            let key = entry.key.clone();
+            let applied = mem_table.insert_if_newer(entry);
+            if applied && compressed {
+                compressed_keys.insert(key.clone());
+            } else if applied {
+                compressed_keys.remove(&key);
+            }
+            keys.push(key);
+            records += 1;
+        }
+        check_decrypted(&iterator, file)?;
+
+        let mut filter = BloomFilter::new(keys.len());
+        for key in &keys {
+            filter.insert(key);
+        }
+        filters.push(filter);
+
+        on_event(RecoveryEvent::SegmentReplayed {
+            path: file.clone(),
+            records,
+        });
+
+        let file_len = iterator.file_len();
+        if let Some(offset) = iterator.corrupted_at() {
+            on_event(RecoveryEvent::CorruptionDetected {
+                path: file.clone(),
+                offset,
+            });
+        } else if iterator.current_offset() < file_len {
+            on_event(RecoveryEvent::TruncationDetected {
+                path: file.clone(),
+                offset: iterator.current_offset(),
+                file_len,
+            });
+        }
+
+        if records > 0 {
+            if let Some((previous_path, previous_max)) = previous {
+                if previous_max > min_timestamp {
+                    on_event(RecoveryEvent::TimestampOverlap {
+                        previous: previous_path.clone(),
+                        previous_max,
+                        next: file.clone(),
+                        next_min: min_timestamp,
+                    });
+                }
+            }
+            previous = Some((file, max_timestamp));
+        }
+    }
+
+    Ok(Replayed {
+        mem_table,
+        segments,
+        filters,
+        compressed_keys,
+    })
+}
+
+const DEFAULT_INGEST_THRESHOLD: usize = 1 << 20;
+
+/// Buffers writes for `Db` and commits them in groups, bounding both memory
+/// use and the number of flushes. Whatever is still buffered is committed
+/// when the ingestor is dropped; call `finish` to observe that final error.
+pub struct Ingestor<'a> {
+    db: &'a mut Db,
+    pending: Vec<(Vec<u8>, Vec<u8>)>,
+    pending_bytes: usize,
+    threshold: usize,
+    commits: usize,
+}
+
+impl Ingestor<'_> {
+    /// Sets how many buffered key and value bytes trigger a commit.
+    pub fn with_threshold(mut self, bytes: usize) -> Self {
+        self.threshold = bytes;
+        self
+    }
+
+    pub fn push(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.pending_bytes += key.len() + value.len();
+        self.pending.push((key.to_owned(), value.to_owned()));
+
+        if self.pending_bytes >= self.threshold {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Commits everything buffered so far.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        self.db.write_values(&self.pending)?;
+        self.pending.clear();
+        self.pending_bytes = 0;
+        self.commits += 1;
+        Ok(())
+    }
+
+    /// Number of group commits performed so far.
+    pub fn commits(&self) -> usize {
+        self.commits
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.flush()
+    }
+}
+
+impl Drop for Ingestor<'_> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+pub(crate) fn now_micros() -> Result<u128> {
+    Ok(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(io::Error::other)?
+        .as_micros())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs, io,
+        path::PathBuf,
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime},
+    };
+
+    use rand::Rng;
+
+    use crate::{
+        batch::WriteBatch,
+        entry::Entry,
+        error::DbError,
+        segment_store::SegmentNaming,
+        stats::DbStats,
+        storage::Storage,
+        storage_iterator::StorageIterator,
+        utils::{create_dir, remove_dir, scan_dir},
+    };
+
+    use super::{now_micros, CompactionProgress, Db, DbConfig, OpenMode, RecoveryEvent};
+
+    #[test]
+    fn new_reports_storage_errors() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+
+        // the directory was never created
+        let err = Db::new(path.clone()).err().unwrap();
+        assert_eq!(Some(io::ErrorKind::NotFound), err.io_kind());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn init_engine() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+
+        create_dir(&path).unwrap();
+
+        let mut db = Db::new(path).unwrap();
+
+        let key1 = b"Hello".to_owned();
+        let value1 = *b"World!";
+
+        db.set(&key1, &value1).unwrap();
+
+        assert_eq!(b"Hello".to_owned().to_vec(), db.get(&key1).unwrap().unwrap().key);
+
+        let key2 = b"Name".to_owned();
+        let value2 = *b"Vahid";
+
+        db.set(&key2, &value2).unwrap();
+
+        assert_eq!(
+            b"Vahid".to_owned().to_vec(),
+            *db.get(&key2).unwrap().unwrap().value.unwrap()
+        );
+
+        db.delete(&key1).unwrap();
+
+        assert_eq!(None, db.get(&key1).unwrap());
+        assert!(db.get_raw(&key1).unwrap().unwrap().deleted);
+
+        // Clean up
+        remove_dir(&db.dir).expect("Error: could not remove the directory");
+    }
+
+    #[test]
+    fn read_from_multiple_files() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+
+        create_dir(&path).unwrap();
+
+        let mut storage = Storage::new(&path).unwrap();
+
+        let key1 = b"Hello".to_owned();
+        let value1 = *b"World!";
+        let timestamp1 = now_micros().unwrap();
+        storage
+            .set(&key1, &value1, false, timestamp1)
+            .expect("Error: could not write in the file");
+
+        let key2 = b"Name".to_owned();
+        let value2 = *b"Vahid";
+        let timestamp2 = now_micros().unwrap();
+        storage
+            .set(&key2, &value2, false, timestamp2)
+            .expect("Error: could not write in the file");
+
+        let key3 = b"gg".to_owned();
+        let value3 = *b"wp";
+        let timestamp3 = now_micros().unwrap();
+        storage
+            .set(&key3, &value3, false, timestamp3)
+            .expect("Error: could not write in the file");
+
+        let key4 = b"Name".to_owned();
+        let timestamp4 = now_micros().unwrap();
+        storage
+            .delete(&key4, timestamp4)
+            .expect("Error: could not complete delete operation");
+
+        storage.commit().expect("Error: could not flush the file");
+
+        drop(storage);
+
+        let mut storage = Storage::new(&path).unwrap();
+
+        let key5 = b"Hello".to_owned();
+        let value5 = *b"RUST";
+        let timestamp5 = now_micros().unwrap();
+        storage
+            .set(&key5, &value5, false, timestamp5)
+            .expect("Error: could not write in the file");
+
+        let key6 = b"gg".to_owned();
+        let timestamp6 = now_micros().unwrap();
+        storage
+            .delete(&key6, timestamp6)
+            .expect("Error: could not write in the file");
+
+        storage.commit().unwrap();
+
+        drop(storage);
+
+        let db = Db::init_from_existing(path).unwrap();
+
+        // see a key exists
+        assert_eq!(None, db.get(&key3).unwrap());
+
+        assert_eq!(b"Hello".to_owned().to_vec(), db.get(&key5).unwrap().unwrap().key);
+        assert_eq!(
+            b"RUST".to_owned().to_vec(),
+            *db.get(&key5).unwrap().unwrap().value.unwrap()
+        );
+
+        // check the new storage file
+        let files = scan_dir(&db.dir).unwrap();
+        let str_iter = StorageIterator::new(&files[files.len() - 1]).unwrap();
+
+        let data: Vec<Entry> = str_iter.collect();
+
+        assert_eq!(3, data.len());
+
+        // Clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_init_from(){
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+        let key1 = b"Hello".to_owned();
+        let value1 = *b"World!";
+
+        db.set(&key1, &value1).unwrap();
+
+        assert_eq!(b"Hello".to_owned().to_vec(), db.get(&key1).unwrap().unwrap().key);
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_get_keys_from(){
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        let pattern = b"log_".to_owned();
+        assert_eq!(0, db.get_keys_with_pattern(&pattern).unwrap().len());
+
+        let key1 = b"log_1".to_owned();
+        let value1 = *b"data1!";
+        db.set(&key1, &value1).unwrap();
+
+        let key2 = b"log_2".to_owned();
+        let value2 = *b"data2!";
+        db.set(&key2, &value2).unwrap();
+
+        let key1 = b"test_log_1".to_owned();
+        let value1 = *b"test data1!";
+        db.set(&key1, &value1).unwrap();
+
+        let key2 = b"test_log_2".to_owned();
+        let value2 = *b"test data2!";
+        db.set(&key2, &value2).unwrap();
+        
+        let pattern = b"log_".to_owned();
+        assert_eq!(2, db.get_keys_with_pattern(&pattern).unwrap().len());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_get_keys_with_pattern() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        for key in [&b"a"[..], b"log_1", b"log_2", b"log_3", b"logs", b"z"] {
+            db.set(key, b"data").unwrap();
+        }
+        db.delete(b"log_2").unwrap();
+
+        assert_eq!(5, db.get_keys_with_pattern(b"").unwrap().len());
+
+        let keys: Vec<Vec<u8>> = db.get_keys_with_pattern(b"log_").unwrap().into_iter().map(|e| e.key).collect();
+        assert_eq!(vec![b"log_1".to_vec(), b"log_3".to_vec()], keys);
+
+        assert!(db.get_keys_with_pattern(b"m").unwrap().is_empty());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_multi_get() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        db.set(b"Hello", b"World!").unwrap();
+        db.set(b"Name", b"Vahid").unwrap();
+        db.delete(b"Name").unwrap();
+        db.set_tombstone_visibility(true);
+
+        let found = db.multi_get(&[b"Name", b"Hello", b"missing", b"Hello"]).unwrap();
+        let values: Vec<Option<Vec<u8>>> = found.into_iter().map(|e| e.and_then(|e| e.value).map(Arc::unwrap_or_clone)).collect();
+        assert_eq!(vec![None, Some(b"World!".to_vec()), None, Some(b"World!".to_vec())], values);
+
+        assert!(db.multi_get(&[]).unwrap().is_empty());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_contains_key() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        db.set(b"Hello", b"World!").unwrap();
+        db.set(b"Name", b"Vahid").unwrap();
+        db.delete(b"Name").unwrap();
+
+        assert!(db.contains_key(b"Hello").unwrap());
+        assert!(!db.contains_key(b"Name").unwrap());
+        assert!(!db.contains_key(b"missing").unwrap());
+
+        // keys flushed to a sealed segment are still found
+        db.set_max_memtable_bytes(Some(0));
+        db.set(b"gg", b"wp").unwrap();
+        assert!(db.contains_key(b"gg").unwrap());
+        assert!(db.contains_key(b"Hello").unwrap());
+        assert!(!db.contains_key(b"Name").unwrap());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_len() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+        assert!(db.is_empty().unwrap());
+
+        db.set(b"Hello", b"World!").unwrap();
+        db.set(b"Name", b"Vahid").unwrap();
+        db.set(b"gg", b"wp").unwrap();
+        db.delete(b"Name").unwrap();
+        db.set_with_ttl(b"session", b"expired", Duration::ZERO).unwrap();
+
+        assert_eq!(2, db.len().unwrap());
+        assert!(!db.is_empty().unwrap());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        db.set(b"Name", b"Vahid").unwrap();
+        db.set(b"gg", b"wp").unwrap();
+        db.set(b"Hello", b"World!").unwrap();
+        db.set(b"a", b"1").unwrap();
+        db.delete(b"a").unwrap();
+        db.set_with_ttl(b"session", b"expired", Duration::ZERO).unwrap();
+
+        let entries: Vec<(Vec<u8>, Option<Vec<u8>>)> = db.iter().unwrap().map(|e| (e.key, e.value.map(Arc::unwrap_or_clone))).collect();
+        assert_eq!(
+            vec![
+                (b"Hello".to_vec(), Some(b"World!".to_vec())),
+                (b"Name".to_vec(), Some(b"Vahid".to_vec())),
+                (b"gg".to_vec(), Some(b"wp".to_vec())),
+            ],
+            entries
+        );
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_file() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        db.set(b"Hello", b"World!").unwrap();
+        db.set(b"Name", b"Vahid").unwrap();
+        db.set(b"gg", b"wp").unwrap();
+        db.delete(b"gg").unwrap();
+
+        let file = db.dir.with_extension("snapshot");
+        db.save_snapshot(&file).unwrap();
+
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut restored = Db::init_from_existing(path).unwrap();
+        restored.load_snapshot(&file).unwrap();
+        assert_eq!(b"World!".to_vec(), *restored.get(b"Hello").unwrap().unwrap().value.unwrap());
+        assert_eq!(b"Vahid".to_vec(), *restored.get(b"Name").unwrap().unwrap().value.unwrap());
+        assert!(restored.get(b"gg").unwrap().is_none());
+
+        // a wrong entry count or magic is rejected before anything is loaded
+        let mut raw = fs::read(&file).unwrap();
+        raw[10] = 3;
+        fs::write(&file, &raw).unwrap();
+        assert!(matches!(restored.load_snapshot(&file), Err(DbError::Corrupt(_))));
+        raw[0] = b'X';
+        fs::write(&file, &raw).unwrap();
+        assert!(matches!(restored.load_snapshot(&file), Err(DbError::InvalidFormat(_))));
+
+        // clean up
+        fs::remove_file(&file).unwrap();
+        remove_dir(&db.dir).unwrap();
+        remove_dir(&restored.dir).unwrap();
+    }
+
+    #[test]
+    fn test_get_timestamp() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        assert_eq!(None, db.get_timestamp(b"Hello").unwrap());
+
+        assert!(db.set_with_timestamp(b"Hello", b"World!", 100).unwrap());
+        assert_eq!(Some(100), db.get_timestamp(b"Hello").unwrap());
+
+        db.set(b"Hello", b"again").unwrap();
+        let written = db.get_timestamp(b"Hello").unwrap().unwrap();
+        assert!(written > 100);
+        assert_eq!(db.get(b"Hello").unwrap().unwrap().timestamp, written);
+
+        // deletes are stamped too
+        db.delete(b"Hello").unwrap();
+        assert!(db.get_timestamp(b"Hello").unwrap().unwrap() >= written);
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_instant_set(){
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+        let key1 = b"Hello".to_owned();
+        let value1 = *b"World!";
+
+        db.set(&key1, &value1).unwrap();
+
+        assert_eq!(b"Hello".to_owned().to_vec(), db.get(&key1).unwrap().unwrap().key);
+
+        let mut e = db.get(&key1).unwrap().unwrap();
+        let mut new_k: Vec<u8> = Vec::new();
+        new_k.extend_from_slice("Hello1!".as_bytes());
+        e.key = new_k.to_owned();
+        let mut new_val: Vec<u8> = Vec::new();
+        new_val.extend_from_slice("Hello2u2!".as_bytes());
+        e.value = Some(Arc::new(new_val));
+
+        db.instant_set(&mut e).unwrap();
+
+        assert_eq!(b"Hello1!".to_owned().to_vec(), db.get(&new_k).unwrap().unwrap().key);
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn snapshot_test() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+
+        create_dir(&path).unwrap();
+
+        let mut db = Db::new(path).unwrap();
+
+        let key1 = b"Hello".to_owned();
+        let value1 = *b"World!";
+
+        db.set(&key1, &value1).unwrap();
+
+        assert_eq!(b"Hello".to_owned().to_vec(), db.get(&key1).unwrap().unwrap().key);
+
+        let key2 = b"Name".to_owned();
+        let value2 = *b"Vahid";
+
+        db.set(&key2, &value2).unwrap();
+
+        assert_eq!(
+            b"Vahid".to_owned().to_vec(),
+            *db.get(&key2).unwrap().unwrap().value.unwrap()
+        );
+
+        db.delete(&key1).unwrap();
+
+        assert_eq!(None, db.get(&key1).unwrap());
+
+        let snapshot = db.get_snapshot().unwrap();
+
+        // remove dir
+        remove_dir(&db.dir).unwrap();
+        drop(db);
+
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        create_dir(&path).unwrap();
+        let mut db = Db::new(path).unwrap();
+
+        db.set_snapshot(snapshot).unwrap();
+
+        assert_eq!(
+            b"Vahid".to_owned().to_vec(),
+            *db.get(&key2).unwrap().unwrap().value.unwrap()
+        );
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_key_validator() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        db.set_key_validator(Some(Arc::new(|key: &[u8]| key.contains(&b':'))));
+
+        let err = db.set(b"no-separator", b"value").unwrap_err();
+        assert!(matches!(err, DbError::InvalidKey));
+        assert_eq!(None, db.get(b"no-separator").unwrap());
+
+        let err = db.delete(b"no-separator").unwrap_err();
+        assert!(matches!(err, DbError::InvalidKey));
+
+        db.set(b"user:1", b"value").unwrap();
+        assert_eq!(b"value".to_vec(), *db.get(b"user:1").unwrap().unwrap().value.unwrap());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_on_commit() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        let committed = Arc::new(Mutex::new(Vec::new()));
+        let sink = committed.clone();
+        db.on_commit(Arc::new(move |timestamp| sink.lock().unwrap().push(timestamp)));
+
+        db.set(b"key1", b"value1").unwrap();
+        db.set(b"key2", b"value2").unwrap();
+        db.delete(b"key1").unwrap();
+
+        let committed = committed.lock().unwrap();
+        assert_eq!(3, committed.len());
+        assert!(committed.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(db.get(b"key2").unwrap().unwrap().timestamp, committed[1]);
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        db.set(b"a", &[0x01, 0x10]).unwrap();
+        db.set(b"b", &[0x02, 0x01]).unwrap();
+        db.set(b"c", &[0x01]).unwrap();
+        db.set(b"d", &[0x01, 0x20]).unwrap();
+        db.delete(b"d").unwrap();
+
+        let matching = db.scan(|value| value.first() == Some(&0x01)).unwrap();
+        let keys: Vec<Vec<u8>> = matching.into_iter().map(|e| e.key).collect();
+        assert_eq!(vec![b"a".to_vec(), b"c".to_vec()], keys);
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_range_into() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        for key in [b"a", b"b", b"c", b"d", b"e"] {
+            db.set(key, b"value").unwrap();
+        }
+        db.delete(b"d").unwrap();
+
+        let mut out = Vec::new();
+        db.range_into(b"a", b"c", &mut out).unwrap();
+        assert_eq!(2, out.len());
+
+        db.range_into(b"c", b"f", &mut out).unwrap();
+        let keys: Vec<Vec<u8>> = out.iter().map(|e| e.key.clone()).collect();
+        assert_eq!(vec![b"c".to_vec(), b"e".to_vec()], keys);
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_or_create_new_dir() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let (db, created) = Db::open_or_create(path).unwrap();
+
+        assert!(created);
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_or_create_existing_dir() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path.clone()).unwrap();
+        db.set(b"Hello", b"World!").unwrap();
+        drop(db);
+
+        let (db, created) = Db::open_or_create(path).unwrap();
+
+        assert!(!created);
+        assert_eq!(b"World!".to_vec(), *db.get(b"Hello").unwrap().unwrap().value.unwrap());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_set_after() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        db.set(b"parent", b"1").unwrap();
+        let barrier = db.get(b"parent").unwrap().unwrap().timestamp;
+
+        let timestamp = db.set_after(b"child", b"2", barrier).unwrap();
+        assert!(timestamp > barrier);
+        assert_eq!(timestamp, db.get(b"child").unwrap().unwrap().timestamp);
+
+        // a barrier in the future still gets honoured
+        let future = timestamp + 1_000_000;
+        let timestamp = db.set_after(b"child", b"3", future).unwrap();
+        assert_eq!(future + 1, timestamp);
+
+        let err = db.set_after(b"child", b"4", u128::MAX).unwrap_err();
+        assert_eq!(Some(io::ErrorKind::InvalidInput), err.io_kind());
+        assert_eq!(timestamp, db.get(b"child").unwrap().unwrap().timestamp);
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_changes_page() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        for (idx, key) in [b"e", b"d", b"c", b"b", b"a"].iter().enumerate() {
+            let mut entry = Entry {
+                key: key.to_vec(),
+                value: Some(Arc::new(b"value".to_vec())),
+                timestamp: 100 + idx as u128,
+                deleted: false,
+                expires_at: None,
+            };
+            db.instant_set(&mut entry).unwrap();
+        }
+
+        let (page, cursor) = db.changes_page(0, 2).unwrap();
+        assert_eq!(vec![100, 101], page.iter().map(|e| e.timestamp).collect::<Vec<_>>());
+        assert_eq!(Some(101), cursor);
+
+        let (page, cursor) = db.changes_page(cursor.unwrap(), 2).unwrap();
+        assert_eq!(vec![102, 103], page.iter().map(|e| e.timestamp).collect::<Vec<_>>());
+        assert_eq!(Some(103), cursor);
+
+        let (page, cursor) = db.changes_page(cursor.unwrap(), 2).unwrap();
+        assert_eq!(vec![b"a".to_vec()], page.iter().map(|e| e.key.clone()).collect::<Vec<_>>());
+        assert_eq!(None, cursor);
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_set_compressed() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path.clone()).unwrap();
+
+        let blob = b"compressible ".repeat(4096);
+        db.set_compressed(b"blob", &blob).unwrap();
+        db.set(b"Hello", b"World!").unwrap();
+
+        let files = scan_dir(&path).unwrap();
+        assert!((fs::metadata(&files[0]).unwrap().len() as usize) < blob.len() / 10);
+
+        assert_eq!(blob, *db.get(b"blob").unwrap().unwrap().value.unwrap());
+        assert_eq!(b"World!".to_vec(), *db.get(b"Hello").unwrap().unwrap().value.unwrap());
+        drop(db);
+
+        // values stay compressed when the segment is rewritten on open
+        let db = Db::init_from_existing(path.clone()).unwrap();
+        let files = scan_dir(&path).unwrap();
+        assert!((fs::metadata(&files[0]).unwrap().len() as usize) < blob.len() / 10);
+        assert_eq!(blob, *db.get(b"blob").unwrap().unwrap().value.unwrap());
+        assert_eq!(b"World!".to_vec(), *db.get(b"Hello").unwrap().unwrap().value.unwrap());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_instant_set_keeps_timestamp() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path.clone()).unwrap();
+
+        let mut entry = Entry {
+            key: b"Hello".to_vec(),
+            value: Some(Arc::new(b"World!".to_vec())),
+            timestamp: 42,
+            deleted: false,
+            expires_at: None,
+        };
+        db.instant_set(&mut entry).unwrap();
+        assert_eq!(Some(entry), db.get(b"Hello").unwrap());
+
+        let mut tombstone = Entry {
+            key: b"Hello".to_vec(),
+            value: None,
+            timestamp: 43,
+            deleted: true,
+            expires_at: None,
+        };
+        db.instant_set(&mut tombstone).unwrap();
+        assert_eq!(None, db.get(b"Hello").unwrap());
+        drop(db);
+
+        // both writes were stored with their original timestamps
+        let mut db = Db::init_from_existing(path).unwrap();
+        db.set_tombstone_visibility(true);
+        assert_eq!(Some(tombstone), db.get(b"Hello").unwrap());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_memtable_flush() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path.clone()).unwrap();
+
+        // each entry takes 2 + 5 + 16 + 1 = 24 bytes, so every fifth write flushes
+        db.set_max_memtable_bytes(Some(100));
+        for idx in 0..12u8 {
+            db.set(&[b'k', idx], b"value").unwrap();
+        }
+        db.delete(&[b'k', 3]).unwrap();
+        db.set(&[b'k', 4], b"newer").unwrap();
+
+        assert_eq!(2, db.sealed.len());
+        assert_eq!(3, scan_dir(&path).unwrap().len());
+        for idx in (0..12u8).filter(|idx| ![3, 4].contains(idx)) {
+            assert_eq!(b"value".to_vec(), *db.get(&[b'k', idx]).unwrap().unwrap().value.unwrap());
+        }
+        assert_eq!(None, db.get(&[b'k', 3]).unwrap());
+        assert_eq!(b"newer".to_vec(), *db.get(&[b'k', 4]).unwrap().unwrap().value.unwrap());
+
+        // the Bloom filters keep lookups of absent keys out of the sealed
+        // segments, bar the odd false positive
+        let reads = || db.disk_reads();
+        let before = reads();
+        for idx in 100..200u8 {
+            assert_eq!(None, db.get(&[b'k', idx]).unwrap());
+        }
+        assert!(reads() - before <= 5, "{} of 200 filter checks passed", reads() - before);
+        drop(db);
+
+        // reopening merges the sealed segments back
+        let db = Db::init_from_existing(path).unwrap();
+        assert_eq!(11, db.keys().unwrap().len());
+        assert_eq!(None, db.get(&[b'k', 3]).unwrap());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_value_cache() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let config = DbConfig::new(path.clone())
+            .with_max_memtable_bytes(0)
+            .with_value_cache_capacity(2);
+        let mut db = Db::open(config).unwrap();
+        for key in [&b"a"[..], b"b", b"c"] {
+            db.set(key, b"old").unwrap();
+        }
+        let value = |db: &Db, key: &[u8]| db.get(key).unwrap().map(|entry| entry.value.unwrap().to_vec());
+
+        // the second read is served from the cache
+        let before = db.disk_reads();
+        assert_eq!(Some(b"old".to_vec()), value(&db, b"a"));
+        let after = db.disk_reads();
+        assert!(after > before);
+        assert_eq!(Some(b"old".to_vec()), value(&db, b"a"));
+        assert_eq!(after, db.disk_reads());
+
+        // writes are not hidden by the cached entries
+        db.set(b"a", b"new").unwrap();
+        assert_eq!(Some(b"new".to_vec()), value(&db, b"a"));
+        db.delete(b"a").unwrap();
+        assert_eq!(None, value(&db, b"a"));
+
+        // reading two other keys evicts "a"
+        assert_eq!(Some(b"old".to_vec()), value(&db, b"b"));
+        assert_eq!(Some(b"old".to_vec()), value(&db, b"c"));
+        let before = db.disk_reads();
+        assert_eq!(None, value(&db, b"a"));
+        assert!(db.disk_reads() > before);
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_absent_cache() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let config = DbConfig::new(path.clone()).with_absent_cache_capacity(16);
+        let mut db = Db::open(config).unwrap();
+        for idx in 0..100u32 {
+            db.set(format!("key{}", idx).as_bytes(), b"value").unwrap();
+        }
+        db.set_max_memtable_bytes(Some(0));
+        db.set(b"Hello", b"World!").unwrap();
+
+        // an absent key the Bloom filter lets through is read for once
+        let absent = (0..10_000u32)
+            .map(|idx| format!("absent{}", idx).into_bytes())
+            .find(|key| {
+                let before = db.disk_reads();
+                assert_eq!(None, db.get(key).unwrap());
+                db.disk_reads() > before
+            })
+            .unwrap();
+        let before = db.disk_reads();
+        assert_eq!(None, db.get(&absent).unwrap());
+        assert_eq!(before, db.disk_reads());
+
+        // writing the key makes it found again, also once it is flushed
+        db.set(&absent, b"present").unwrap();
+        assert_eq!(b"present".to_vec(), *db.get(&absent).unwrap().unwrap().value.unwrap());
+
+        // clean up
+        remove_dir(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parallel_segment_reads() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path.clone()).unwrap();
+
+        // three segments hold "Hello", the newest one "third"
+        db.set_max_memtable_bytes(Some(0));
+        for value in [&b"first"[..], b"second", b"third"] {
+            db.set(b"Hello", value).unwrap();
+            db.set(b"Name", value).unwrap();
+        }
+        db.delete(b"Name").unwrap();
+        assert!(db.sealed.len() >= 3);
+
+        let sequential = (db.get(b"Hello").unwrap(), db.get(b"Name").unwrap(), db.get(b"absent").unwrap());
+        let before = db.disk_reads();
+        db.set_parallel_segment_reads(true);
+        let parallel = (db.get(b"Hello").unwrap(), db.get(b"Name").unwrap(), db.get(b"absent").unwrap());
+        assert_eq!(sequential, parallel);
+        assert_eq!(b"third".to_vec(), *parallel.0.unwrap().value.unwrap());
+        assert_eq!(None, parallel.1);
+
+        // every candidate segment was read, not just the newest
+        assert!(db.disk_reads() - before >= 6);
+
+        // clean up
+        remove_dir(&path).unwrap();
+    }
+
+    #[test]
+    fn test_set_compressed_flushes() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path.clone()).unwrap();
+
+        db.set_max_memtable_bytes(Some(0));
+        db.set_compressed(b"Hello", b"World!").unwrap();
+        db.set_compressed(b"Name", b"Vahid").unwrap();
+
+        assert_eq!(2, db.sealed.len());
+        assert_eq!(3, scan_dir(&path).unwrap().len());
+        assert_eq!(b"World!".to_vec(), *db.get(b"Hello").unwrap().unwrap().value.unwrap());
+        assert_eq!(b"Vahid".to_vec(), *db.get(b"Name").unwrap().unwrap().value.unwrap());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_instant_set_flushes() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path.clone()).unwrap();
+
+        db.set_max_memtable_bytes(Some(0));
+        let mut entry = Entry {
+            key: b"Hello".to_vec(),
+            value: Some(Arc::new(b"World!".to_vec())),
+            timestamp: 42,
+            deleted: false,
+            expires_at: None,
+        };
+        db.instant_set(&mut entry).unwrap();
+        let mut tombstone = Entry {
+            key: b"Name".to_vec(),
+            value: None,
+            timestamp: 43,
+            deleted: true,
+            expires_at: None,
+        };
+        db.instant_set(&mut tombstone).unwrap();
+
+        assert_eq!(2, db.sealed.len());
+        assert_eq!(3, scan_dir(&path).unwrap().len());
+        assert_eq!(Some(entry), db.get(b"Hello").unwrap());
+        assert_eq!(Some(tombstone), db.get_raw(b"Name").unwrap());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    /// A database with most of its history flushed into sealed segments:
+    /// live `a = 1`, `b = 2` and `e = 1`, with `c` deleted within the sealed
+    /// segments and `d` deleted by a tombstone still in the MemTable.
+    fn flushed_db() -> Db {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        // every write flushes
+        db.set_max_memtable_bytes(Some(0));
+        db.set(b"a", b"1").unwrap();
+        db.set(b"b", b"1").unwrap();
+        db.set(b"c", b"1").unwrap();
+        db.delete(b"c").unwrap();
+        db.set(b"d", b"1").unwrap();
+
+        db.set_max_memtable_bytes(None);
+        db.set(b"b", b"2").unwrap();
+        db.delete(b"d").unwrap();
+        db.set(b"e", b"1").unwrap();
+        assert_eq!(5, db.sealed.len());
+        db
+    }
+
+    fn keys_of(entries: &[Entry]) -> Vec<Vec<u8>> {
+        entries.iter().map(|e| e.key.clone()).collect()
+    }
+
+    #[test]
+    fn test_ranges_after_flush() {
+        let db = flushed_db();
+        let live = vec![b"a".to_vec(), b"b".to_vec(), b"e".to_vec()];
+
+        let entries = db.range(b"", b"").unwrap();
+        assert_eq!(live, keys_of(&entries));
+        assert_eq!(b"2".to_vec(), *entries[1].value.clone().unwrap());
+        assert_eq!(vec![b"b".to_vec()], keys_of(&db.range(b"b", b"e").unwrap()));
+
+        let mut out = Vec::new();
+        db.range_into(b"a", b"z", &mut out).unwrap();
+        assert_eq!(live, keys_of(&out));
+
+        // each entry takes 2 bytes
+        let (entries, truncated) = db.range_bounded(b"a", b"z", 4).unwrap();
+        assert_eq!(vec![b"a".to_vec(), b"b".to_vec()], keys_of(&entries));
+        assert!(truncated);
+
+        assert_eq!(vec![b'a', b'b', b'e'], db.range_map(b"a", b"z", |e| e.key[0]).unwrap());
+        assert_eq!(live, keys_of(&db.get_keys_with_pattern(b"").unwrap()));
+
+        // an empty end reads up to the last key
+        db.range_into(b"", b"", &mut out).unwrap();
+        assert_eq!(live, keys_of(&out));
+        let (entries, truncated) = db.range_bounded(b"b", b"", usize::MAX).unwrap();
+        assert_eq!(vec![b"b".to_vec(), b"e".to_vec()], keys_of(&entries));
+        assert!(!truncated);
+        assert_eq!(vec![b'a', b'b', b'e'], db.range_map(b"", b"", |e| e.key[0]).unwrap());
+        assert!(db.get_keys_with_pattern(b"c").unwrap().is_empty());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_iteration_after_flush() {
+        let db = flushed_db();
+        let live = vec![b"a".to_vec(), b"b".to_vec(), b"e".to_vec()];
+
+        assert_eq!(live, db.keys().unwrap());
+        assert_eq!(3, db.len().unwrap());
+        assert!(!db.is_empty().unwrap());
+        assert_eq!(live, keys_of(&db.iter().unwrap().collect::<Vec<_>>()));
+        assert_eq!(vec![b"a".to_vec(), b"e".to_vec()], keys_of(&db.scan(|v| v == b"1").unwrap()));
+
+        let (page, cursor) = db.scan_rev(None, 2).unwrap();
+        assert_eq!(vec![b"e".to_vec(), b"b".to_vec()], keys_of(&page));
+        assert_eq!(Some(b"b".to_vec()), cursor);
+
+        let tombstones: Vec<Vec<u8>> = db.tombstones().unwrap().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(vec![b"c".to_vec(), b"d".to_vec()], tombstones);
+        assert_eq!(2, db.count_tombstones().unwrap());
+
+        let digest: Vec<Vec<u8>> = db.key_digest().unwrap().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(live, digest);
+
+        // one change per key, the newest
+        let (changes, cursor) = db.changes_page(0, 10).unwrap();
+        assert_eq!(None, cursor);
+        assert_eq!(5, changes.len());
+        assert!(changes.iter().any(|e| e.key == b"b" && e.value.as_deref().map(Vec::as_slice) == Some(&b"2"[..])));
+
+        let stats = db.stats().unwrap();
+        assert_eq!(3, stats.live_keys);
+        assert_eq!(2, stats.tombstones);
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_snapshots_after_flush() {
+        let mut range = rand::thread_rng();
+        let db = flushed_db();
+        let live = vec![b"a".to_vec(), b"b".to_vec(), b"e".to_vec()];
+
+        let snapshot = db.get_snapshot().unwrap();
+        assert_eq!((snapshot.clone(), None), db.snapshot_chunk(None, usize::MAX).unwrap());
+
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut restored = Db::init_from_existing(path).unwrap();
+        restored.set_snapshot(snapshot).unwrap();
+        assert_eq!(live, restored.keys().unwrap());
+        assert_eq!(b"2".to_vec(), *restored.get(b"b").unwrap().unwrap().value.unwrap());
+
+        let file = db.dir.with_extension("snapshot");
+        db.save_snapshot(&file).unwrap();
+        restored.purge_database().unwrap();
+        restored.load_snapshot(&file).unwrap();
+        assert_eq!(live, restored.keys().unwrap());
+
+        // clean up
+        fs::remove_file(&file).unwrap();
+        remove_dir(&db.dir).unwrap();
+        remove_dir(&restored.dir).unwrap();
+    }
+
+    #[test]
+    fn test_purge_older_than_after_flush() {
+        let mut db = flushed_db();
+
+        // the tombstones are flushed like any other write
+        db.set_max_memtable_bytes(Some(0));
+        assert_eq!(3, db.purge_older_than(u128::MAX).unwrap());
+        assert_eq!(6, db.sealed.len());
+        assert!(db.mem_table.get_all().next().is_none());
+        assert!(db.is_empty().unwrap());
+        assert_eq!(None, db.get(b"a").unwrap());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_sealed_read_error() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        db.set_max_memtable_bytes(Some(0));
+        db.set(b"Hello", b"World!").unwrap();
+        std::fs::remove_file(&db.sealed[0].path).unwrap();
+
+        // a segment that cannot be read is an error, not a miss
+        let err = db.get(b"Hello").unwrap_err();
+        assert_eq!(Some(io::ErrorKind::NotFound), err.io_kind());
+        assert!(db.get_raw(b"Hello").is_err());
+        assert!(db.contains_key(b"Hello").is_err());
+        assert!(db.multi_get(&[b"Hello"]).is_err());
+        assert!(db.get_timestamp(b"Hello").is_err());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_flush() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path.clone()).unwrap();
+
+        db.set(b"Hello", b"World!").unwrap();
+        db.set(b"Name", b"Vahid").unwrap();
+        db.flush().unwrap();
+        db.set(b"gg", b"wp").unwrap();
+        db.delete(b"Hello").unwrap();
+
+        let files = scan_dir(&path).unwrap();
+        assert_eq!(2, files.len());
+        let keys = |file| StorageIterator::new(file).unwrap().map(|e| e.key).collect::<Vec<_>>();
+        assert_eq!(vec![b"Hello".to_vec(), b"Name".to_vec()], keys(&files[0]));
+        assert_eq!(vec![b"gg".to_vec(), b"Hello".to_vec()], keys(&files[1]));
+
+        assert_eq!(None, db.get(b"Hello").unwrap());
+        assert_eq!(b"Vahid".to_vec(), *db.get(b"Name").unwrap().unwrap().value.unwrap());
+        assert_eq!(2, db.len().unwrap());
+        drop(db);
+
+        let db = Db::init_from_existing(path).unwrap();
+        assert_eq!(None, db.get(b"Hello").unwrap());
+        assert_eq!(vec![b"Name".to_vec(), b"gg".to_vec()], db.keys().unwrap());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        db.set(b"Hello", b"World!").unwrap();
+        db.set(b"Name", b"Vahid").unwrap();
+        db.set(b"gg", b"wp").unwrap();
+        db.delete(b"Name").unwrap();
+        db.set_with_ttl(b"session", b"expired", Duration::ZERO).unwrap();
+        db.flush().unwrap();
+
+        // expired keys are not live
+        let disk_bytes = scan_dir(&db.dir).unwrap().iter().map(|f| fs::metadata(f).unwrap().len()).sum();
+        assert_eq!(
+            DbStats {
+                live_keys: 2,
+                tombstones: 1,
+                memtable_bytes: (5 + 6 + 16 + 1) + (4 + 16 + 1) + (2 + 2 + 16 + 1) + (7 + 7 + 16 + 1),
+                files: 2,
+                disk_bytes,
+                generation: 5,
+            },
+            db.stats().unwrap()
+        );
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+
+        // a directory that cannot be listed is an error, not zero files
+        assert_eq!(Some(io::ErrorKind::NotFound), db.stats().unwrap_err().io_kind());
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path.clone()).unwrap();
+
+        // a, b and c go to a sealed segment, the updates to the active one
+        db.set_max_memtable_bytes(Some(60));
+        db.set(b"a", b"old").unwrap();
+        db.set(b"b", b"old").unwrap();
+        db.set(b"c", b"old").unwrap();
+        db.set_max_memtable_bytes(None);
+        db.set(b"a", b"new").unwrap();
+        db.delete(b"b").unwrap();
+        db.set(b"d", b"new").unwrap();
+        assert_eq!(2, scan_dir(&path).unwrap().len());
+
+        db.compact().unwrap();
+
+        // the compacted segment was published under its segment name
+        let files = scan_dir(&path).unwrap();
+        assert_eq!(1, files.len());
+        assert_eq!(1, fs::read_dir(&path).unwrap().count());
+        assert_eq!(files[0], db.storage.as_ref().unwrap().path());
+        let records: Vec<(Vec<u8>, Vec<u8>)> = StorageIterator::new(&files[0])
+            .unwrap()
+            .map(|e| (e.key, Arc::unwrap_or_clone(e.value.unwrap())))
+            .collect();
+        assert_eq!(
+            vec![
+                (b"a".to_vec(), b"new".to_vec()),
+                (b"c".to_vec(), b"old".to_vec()),
+                (b"d".to_vec(), b"new".to_vec()),
+            ],
+            records
+        );
+
+        // the compacted segment is the active one
+        db.set(b"e", b"new").unwrap();
+        assert_eq!(1, scan_dir(&path).unwrap().len());
+        assert_eq!(None, db.get(b"b").unwrap());
+        assert_eq!(b"old".to_vec(), *db.get(b"c").unwrap().unwrap().value.unwrap());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_compact_incremental() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path.clone()).unwrap();
+
+        // every write is flushed into a sealed segment of its own
+        db.set_max_memtable_bytes(Some(0));
+        for idx in 0..6u8 {
+            db.set(&[b'k', idx % 3], &[idx]).unwrap();
+        }
+        db.delete(&[b'k', 1]).unwrap();
+        assert_eq!(7, db.sealed.len());
+
+        // a budget of zero still makes one step
+        let progress = db.compact_incremental(Duration::ZERO).unwrap();
+        assert_eq!(CompactionProgress { merged: 1, remaining: 6 }, progress);
+        assert_eq!(7, scan_dir(&path).unwrap().len());
+        assert_eq!(vec![3], *db.get(&[b'k', 0]).unwrap().unwrap().value.unwrap());
+        drop(db);
+
+        // the merged segments stay merged across a reopen
+        let mut db = Db::init_from_existing_with_mode(path.clone(), OpenMode::Reopen).unwrap();
+        assert_eq!(6, db.sealed.len());
+        for remaining in [5, 4] {
+            let progress = db.compact_incremental(Duration::ZERO).unwrap();
+            assert_eq!(CompactionProgress { merged: 1, remaining }, progress);
+        }
+        let progress = db.compact_incremental(Duration::from_secs(3600)).unwrap();
+        assert_eq!(CompactionProgress { merged: 4, remaining: 0 }, progress);
+
+        // the same as a full compaction
+        assert_eq!(1, scan_dir(&path).unwrap().len());
+        assert_eq!(2, db.total_records().unwrap());
+        assert_eq!(vec![3], *db.get(&[b'k', 0]).unwrap().unwrap().value.unwrap());
+        assert_eq!(None, db.get(&[b'k', 1]).unwrap());
+        assert_eq!(vec![5], *db.get(&[b'k', 2]).unwrap().unwrap().value.unwrap());
+
+        let progress = db.compact_incremental(Duration::ZERO).unwrap();
+        assert_eq!(CompactionProgress { merged: 0, remaining: 0 }, progress);
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_scan_rev() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        for idx in 0..8u8 {
+            db.set(&[b'k', idx], b"value").unwrap();
+        }
+        db.delete(&[b'k', 5]).unwrap();
+
+        let mut pages = Vec::new();
+        let mut cursor = None;
+        loop {
+            let (page, next) = db.scan_rev(cursor.as_deref(), 3).unwrap();
+            pages.push(page.iter().map(|e| e.key[1]).collect::<Vec<_>>());
+            cursor = next;
+            if cursor.is_none() {
+                break;
+            }
+        }
+
+        assert_eq!(vec![vec![7, 6, 4], vec![3, 2, 1], vec![0]], pages);
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_tombstone_visibility() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        db.set(b"Hello", b"World!").unwrap();
+        db.delete(b"Hello").unwrap();
+
+        assert_eq!(None, db.get(b"Hello").unwrap());
+
+        db.set_tombstone_visibility(true);
+        let tombstone = db.get(b"Hello").unwrap().unwrap();
+        assert!(tombstone.deleted);
+        assert_eq!(None, tombstone.value);
+
+        db.set_tombstone_visibility(false);
+        assert_eq!(None, db.get(b"Hello").unwrap());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_purge_older_than() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        for (key, timestamp) in [(b"old1", 100), (b"old2", 200), (b"new1", 300)] {
+            let mut entry = Entry {
+                key: key.to_vec(),
+                value: Some(Arc::new(b"value".to_vec())),
+                timestamp,
+                deleted: false,
+                expires_at: None,
+            };
+            db.instant_set(&mut entry).unwrap();
+        }
+
+        // a failed tombstone rolls back the ones written before it
+        db.storage.as_mut().unwrap().fail_after_writes(1);
+        assert!(db.purge_older_than(250).is_err());
+        assert!(db.get(b"old1").unwrap().is_some());
+        assert_eq!(3, db.total_records().unwrap());
+        db.storage.as_mut().unwrap().fail_after_writes(usize::MAX);
+
+        assert_eq!(2, db.purge_older_than(250).unwrap());
+
+        assert_eq!(None, db.get(b"old1").unwrap());
+        assert_eq!(None, db.get(b"old2").unwrap());
+        assert_eq!(b"value".to_vec(), *db.get(b"new1").unwrap().unwrap().value.unwrap());
+
+        // the purge survives a reopen
+        let path = db.dir.clone();
+        drop(db);
+        let db = Db::init_from_existing(path).unwrap();
+        assert_eq!(None, db.get(b"old1").unwrap());
+        assert!(db.get(b"new1").unwrap().is_some());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_total_records() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        for idx in 0..5u8 {
+            db.set(b"key", &[idx]).unwrap();
+        }
+
+        assert_eq!(5, db.total_records().unwrap());
+        assert_eq!(1, db.get_keys_with_pattern(b"").unwrap().len());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_latency_stats() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        db.set(b"before", b"enabled").unwrap();
+        assert_eq!(None, db.latency_stats());
+
+        db.collect_latencies(true);
+        for idx in 0..10u8 {
+            db.set(&[idx], b"value").unwrap();
+        }
+        db.delete(&[0]).unwrap();
+
+        let stats = db.latency_stats().unwrap();
+        assert_eq!(10, stats.set.samples);
+        assert_eq!(11, stats.flush.samples);
+        assert!(stats.set.max >= stats.set.min);
+        assert!(stats.set.total > Duration::ZERO);
+
+        db.collect_latencies(false);
+        assert_eq!(None, db.latency_stats());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_recovery_callback() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+
+        create_dir(&path).unwrap();
+
+        let mut storage = Storage::new(&path).unwrap();
+        let timestamp = SystemTime::now().elapsed().unwrap().as_micros();
+        storage
+            .set(b"Hello", b"World!", false, timestamp)
+            .expect("Error: could not write in the file");
+        storage
+            .set(b"Name", b"Vahid", false, timestamp)
+            .expect("Error: could not write in the file");
+        storage.commit().expect("Error: could not flush the file");
+
+        // a record cut off in the middle of its key
+        let mut torn = Vec::new();
+        torn.extend_from_slice(&5u64.to_le_bytes());
+        torn.push(0);
+        torn.extend_from_slice(&3u64.to_le_bytes());
+        torn.extend_from_slice(b"gg");
+        storage.write_all(torn).unwrap();
+        drop(storage);
+
+        let segment = scan_dir(&path).unwrap().remove(0);
+
+        let mut events = Vec::new();
+        let db = Db::open_with_recovery_callback(path, |event| events.push(event)).unwrap();
+
+        assert_eq!(
+            vec![
+                RecoveryEvent::SegmentOpened {
+                    path: segment.clone()
+                },
+                RecoveryEvent::SegmentReplayed {
+                    path: segment.clone(),
+                    records: 2
+                },
+                RecoveryEvent::TruncationDetected {
+                    path: segment,
+                    offset: 10 + 48 + 46,
+                    file_len: 10 + 48 + 46 + 19
+                },
+            ],
+            events
+        );
+        assert_eq!(b"Vahid".to_vec(), *db.get(b"Name").unwrap().unwrap().value.unwrap());
+
+        // Clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_truncated_segment() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+
+        create_dir(&path).unwrap();
+
+        let mut storage = Storage::new(&path).unwrap();
+        storage.set(b"Hello", b"World!", false, 100).unwrap();
+        storage.set(b"Name", b"Vahid", false, 200).unwrap();
+        storage.set(b"gg", b"wp", false, 300).unwrap();
+        storage.commit().unwrap();
+        drop(storage);
+
+        let segment = scan_dir(&path).unwrap().remove(0);
+        let ends = [10 + 48, 10 + 48 + 46, 10 + 48 + 46 + 41];
+        let bytes = fs::read(&segment).unwrap();
+        assert_eq!(ends[2], bytes.len() as u64);
+
+        // a cut anywhere past the header drops the partial record, as a crash
+        // midway through a write leaves it; a cut header is not a segment
+        let file = fs::OpenOptions::new().write(true).open(&segment).unwrap();
+        for len in (0..ends[2]).rev() {
+            file.set_len(len).unwrap();
+            match Db::open_segment(segment.clone()) {
+                Ok(db) => {
+                    let complete = ends.iter().filter(|&&end| end <= len).count();
+                    assert_eq!(complete, db.len().unwrap(), "cut at {}", len);
+                }
+                Err(e) => {
+                    assert!(len < 10, "cut at {}: {}", len, e);
+                    assert!(matches!(e, DbError::InvalidFormat(_)));
+                }
+            }
+        }
+
+        // opening keeps the complete records and rewrites without the rest
+        fs::write(&segment, &bytes[..ends[0] as usize + 20]).unwrap();
+        let db = Db::init_from_existing(path.clone()).unwrap();
+        assert_eq!(b"World!".to_vec(), *db.get(b"Hello").unwrap().unwrap().value.unwrap());
+        assert_eq!(None, db.get(b"Name").unwrap());
+        let files = scan_dir(&path).unwrap();
+        assert_eq!(ends[0], fs::metadata(&files[0]).unwrap().len());
+
+        // Clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_mode_reopen() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+
+        // a and b end up in a sealed segment, c in the active one
+        let mut db = Db::init_from_existing(path.clone()).unwrap();
+        db.set_max_memtable_bytes(Some(40));
+        db.set(b"a", b"old").unwrap();
+        db.set(b"b", b"old").unwrap();
+        db.set_max_memtable_bytes(None);
+        db.set(b"c", b"old").unwrap();
+        drop(db);
+        let files = scan_dir(&path).unwrap();
+        assert_eq!(2, files.len());
+
+        let mut db = Db::init_from_existing_with_mode(path.clone(), OpenMode::Reopen).unwrap();
+        assert_eq!(files, scan_dir(&path).unwrap());
+        db.set(b"a", b"new").unwrap();
+        db.delete(b"b").unwrap();
+        assert_eq!(files, scan_dir(&path).unwrap());
+        drop(db);
+
+        // the writes went to the newest segment
+        let records = StorageIterator::new(&files[1]).unwrap().count();
+        assert_eq!(3, records);
+
+        for mode in [OpenMode::Reopen, OpenMode::Rewrite] {
+            let db = Db::init_from_existing_with_mode(path.clone(), mode).unwrap();
+            assert_eq!(b"new".to_vec(), *db.get(b"a").unwrap().unwrap().value.unwrap());
+            assert_eq!(None, db.get(b"b").unwrap());
+            assert_eq!(b"old".to_vec(), *db.get(b"c").unwrap().unwrap().value.unwrap());
+        }
+        assert_eq!(1, scan_dir(&path).unwrap().len());
+
+        // clean up
+        remove_dir(&path).unwrap();
+    }
+
+    #[test]
+    fn test_open_mode_reopen_then_flush() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+
+        // one segment per open, oldest first
+        for key in [&b"old"[..], b"mid", b"new"] {
+            let mut db = Db::init_from_existing_with_mode(path.clone(), OpenMode::Reopen).unwrap();
+            db.set(key, b"value").unwrap();
+            db.flush().unwrap();
+        }
+        assert_eq!(4, scan_dir(&path).unwrap().len());
+
+        // the flush clears the MemTable, the kept segments still hold the keys
+        let mut db = Db::init_from_existing_with_mode(path.clone(), OpenMode::Reopen).unwrap();
+        db.set_max_memtable_bytes(Some(0));
+        db.set(b"newest", b"value").unwrap();
+        for key in [&b"old"[..], b"mid", b"new", b"newest"] {
+            assert!(db.contains_key(key).unwrap(), "{:?} is missing", key);
+        }
+        assert_eq!(4, db.len().unwrap());
+        assert_eq!(4, db.keys().unwrap().len());
+
+        // clean up
+        remove_dir(&path).unwrap();
+    }
+
+    #[test]
+    fn test_leftover_temp_segment() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+
+        let mut db = Db::init_from_existing(path.clone()).unwrap();
+        db.set(b"Hello", b"World!").unwrap();
+        drop(db);
+        let files = scan_dir(&path).unwrap();
+
+        // a crash while rewriting left a complete but unpublished segment
+        let mut temp = Storage::new_temp(&path).unwrap();
+        temp.set(b"Hello", b"World!", false, 1).unwrap();
+        temp.set(b"ghost", b"value", false, 1).unwrap();
+        temp.commit().unwrap();
+        let temp_path = temp.path().to_owned();
+        drop(temp);
+        assert_eq!(files, scan_dir(&path).unwrap());
+
+        let db = Db::init_from_existing(path.clone()).unwrap();
+        assert!(!temp_path.exists());
+        assert_eq!(None, db.get(b"ghost").unwrap());
+        assert_eq!(b"World!".to_vec(), *db.get(b"Hello").unwrap().unwrap().value.unwrap());
+        assert_eq!(1, fs::read_dir(&path).unwrap().count());
+        drop(db);
+
+        // old segments can be kept around, out of the way of replay
+        let files = scan_dir(&path).unwrap();
+        let db = Db::init_from_existing_with_mode(path.clone(), OpenMode::RewriteKeepingOld).unwrap();
+        assert!(files[0].with_extension("old").exists());
+        assert_eq!(1, scan_dir(&path).unwrap().len());
+        assert_eq!(b"World!".to_vec(), *db.get(b"Hello").unwrap().unwrap().value.unwrap());
+
+        // clean up
+        remove_dir(&path).unwrap();
+    }
+
+    #[test]
+    fn test_open_with_config() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+
+        let config = DbConfig::new(path.clone())
+            .with_sync(true)
+            .with_max_total_bytes(30)
+            .with_open_mode(OpenMode::Reopen);
+        let mut db = Db::open(config.clone()).unwrap();
+        assert!(db.sync_on_commit);
+
+        db.set(b"Hello", b"World!").unwrap();
+        let err = db.set(b"Name", b"Vahid").unwrap_err();
+        assert!(matches!(err, DbError::QuotaExceeded { quota: 30, .. }));
+        drop(db);
+
+        let db = Db::open(config).unwrap();
+        assert_eq!(b"World!".to_vec(), *db.get(b"Hello").unwrap().unwrap().value.unwrap());
+
+        // clean up
+        remove_dir(&path).unwrap();
+    }
+
+    #[test]
+    fn test_max_merged_segments_on_open() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+
+        // one segment per write
+        let mut db = Db::init_from_existing_with_mode(path.clone(), OpenMode::Reopen).unwrap();
+        db.set_max_memtable_bytes(Some(0));
+        for key in [&b"a"[..], b"b", b"c", b"d", b"e"] {
+            db.set(key, b"old").unwrap();
+        }
+        db.set(b"a", b"new").unwrap();
+        drop(db);
+        let files = scan_dir(&path).unwrap();
+
+        // the oldest two are merged into the place of the second one
+        let config = DbConfig::new(path.clone()).with_max_merged_segments_on_open(2);
+        let mut db = Db::open(config.clone()).unwrap();
+        assert_eq!(files[1..], scan_dir(&path).unwrap()[..]);
+        assert_eq!(b"new".to_vec(), *db.get(b"a").unwrap().unwrap().value.unwrap());
+
+        // the remaining segments are still read once the MemTable is flushed
+        db.set_max_memtable_bytes(Some(0));
+        db.set(b"f", b"old").unwrap();
+        for key in [&b"b"[..], b"c", b"d", b"e", b"f"] {
+            assert_eq!(b"old".to_vec(), *db.get(key).unwrap().unwrap().value.unwrap());
+        }
+        assert_eq!(b"new".to_vec(), *db.get(b"a").unwrap().unwrap().value.unwrap());
+        drop(db);
+        let files = scan_dir(&path).unwrap();
+
+        // each open merges two more
+        let db = Db::open(config).unwrap();
+        assert_eq!(files[1..], scan_dir(&path).unwrap()[..]);
+        assert_eq!(6, db.len().unwrap());
+        assert_eq!(b"new".to_vec(), *db.get(b"a").unwrap().unwrap().value.unwrap());
+
+        // clean up
+        remove_dir(&path).unwrap();
+    }
+
+    #[test]
+    fn test_sequenced_segment_names() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let names = |path: &PathBuf| -> Vec<String> {
+            scan_dir(path)
+                .unwrap()
+                .iter()
+                .map(|file| file.file_name().unwrap().to_str().unwrap().to_owned())
+                .collect()
+        };
+
+        let config = DbConfig::new(path.clone()).with_segment_naming(SegmentNaming::Sequenced);
+        let mut db = Db::open(config.clone().with_max_memtable_bytes(0)).unwrap();
+        db.set(b"Hello", b"World!").unwrap();
+        db.set_max_memtable_bytes(None);
+        db.set(b"Hello", b"Again!").unwrap();
+        drop(db);
+
+        // the first segment holds the older value, the second the newer one
+        let files = names(&path);
+        assert_eq!(2, files.len());
+        for (file, sequence) in files.iter().zip(["000001-", "000002-"]) {
+            assert!(file.starts_with(sequence), "{} does not start with {}", file, sequence);
+            assert!(file[sequence.len()..].parse::<u128>().is_ok(), "{} has no timestamp", file);
+        }
+
+        // replayed in order, and the rewritten segment continues the count
+        let db = Db::open(config).unwrap();
+        assert_eq!(b"Again!".to_vec(), *db.get(b"Hello").unwrap().unwrap().value.unwrap());
+        let files = names(&path);
+        assert_eq!(1, files.len());
+        assert!(files[0].starts_with("000003-"), "{}", files[0]);
+
+        // clean up
+        remove_dir(&path).unwrap();
+    }
+
+    #[test]
+    fn test_skip_foreign_files() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+
+        let mut db = Db::init_from_existing(path.clone()).unwrap();
+        db.set(b"Hello", b"World!").unwrap();
+        drop(db);
+
+        // named like a segment, but without a storage header
+        let foreign = path.join("1");
+        fs::write(&foreign, b"this directory holds a database").unwrap();
+        // not named like a segment at all
+        let readme = path.join("README");
+        fs::write(&readme, b"this directory holds a database").unwrap();
+
+        let mut events = Vec::new();
+        let db = Db::open_with_recovery_callback(path, |event| events.push(event)).unwrap();
+        assert!(events.contains(&RecoveryEvent::ForeignFileSkipped {
+            path: foreign.clone()
+        }));
+        assert!(!events.iter().any(|event| format!("{:?}", event).contains("README")));
+        assert_eq!(b"World!".to_vec(), *db.get(b"Hello").unwrap().unwrap().value.unwrap());
+
+        // the foreign files are neither replayed nor removed
+        assert!(foreign.exists());
+        assert!(readme.exists());
+        assert!(Db::open_segment(foreign).is_err());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_ingest() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        let mut ingestor = db.ingest().with_threshold(64);
+        for idx in 0..100u32 {
+            ingestor
+                .push(format!("key-{}", idx).as_bytes(), &idx.to_le_bytes())
+                .unwrap();
+        }
+        assert!(ingestor.commits() > 1);
+        drop(ingestor);
+
+        for idx in 0..100u32 {
+            let entry = db.get(format!("key-{}", idx).as_bytes()).unwrap().unwrap();
+            assert_eq!(idx.to_le_bytes().to_vec(), *entry.value.unwrap());
+        }
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_shrink() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        for idx in 0..20u8 {
+            db.set(&[idx], b"value").unwrap();
+        }
+        for idx in 2..20u8 {
+            db.delete(&[idx]).unwrap();
+        }
+
+        db.shrink();
+
+        assert_eq!(2, db.get_keys_with_pattern(b"").unwrap().len());
+        assert_eq!(b"value".to_vec(), *db.get(&[1]).unwrap().unwrap().value.unwrap());
+        assert_eq!(None, db.get(&[5]).unwrap());
+
+        // deletes are still durable after the tombstones left memory
+        let path = db.dir.clone();
+        drop(db);
+        let db = Db::init_from_existing(path).unwrap();
+        assert_eq!(None, db.get(&[5]).unwrap());
+        assert!(db.get(&[0]).unwrap().is_some());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_key_digest() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        db.set(b"a", b"1").unwrap();
+        db.set(b"b", b"2").unwrap();
+        db.set(b"c", b"3").unwrap();
+        db.delete(b"b").unwrap();
+
+        let digest = db.key_digest().unwrap();
+        assert_eq!(
+            vec![
+                (b"a".to_vec(), db.get(b"a").unwrap().unwrap().timestamp),
+                (b"c".to_vec(), db.get(b"c").unwrap().unwrap().timestamp),
+            ],
+            digest
+        );
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path.clone()).unwrap();
+        db.set(b"Hello", b"World!").unwrap();
+        drop(db);
+
+        let files = scan_dir(&path).unwrap();
+        for file in &files {
+            let mut permissions = fs::metadata(file).unwrap().permissions();
+            permissions.set_readonly(true);
+            fs::set_permissions(file, permissions).unwrap();
+        }
+
+        let mut db = Db::open_read_only(path.clone()).unwrap();
+
+        assert_eq!(b"World!".to_vec(), *db.get(b"Hello").unwrap().unwrap().value.unwrap());
+
+        assert!(matches!(db.set(b"Name", b"Vahid"), Err(DbError::ReadOnly)));
+        assert!(matches!(db.delete(b"Hello"), Err(DbError::ReadOnly)));
+        assert!(matches!(db.set_cf("users", b"Name", b"Vahid"), Err(DbError::ReadOnly)));
+        assert_eq!(files, scan_dir(&path).unwrap());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_max_total_bytes() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        // every 1-byte key with a 10-byte value accounts for 28 bytes
+        db.set_max_total_bytes(Some(3 * 28));
+        db.set(b"a", &[0; 10]).unwrap();
+        db.set(b"b", &[0; 10]).unwrap();
+        db.set(b"c", &[0; 10]).unwrap();
+
+        let err = db.set(b"d", &[0; 10]).unwrap_err();
+        assert!(matches!(err, DbError::QuotaExceeded { quota: 84, requested: 112 }));
+        assert_eq!(None, db.get(b"d").unwrap());
+
+        let err = db.set(b"a", &[0; 11]).unwrap_err();
+        assert!(matches!(err, DbError::QuotaExceeded { quota: 84, requested: 85 }));
+
+        // shrinking overwrites and deletes still succeed and free up room
+        db.set(b"a", &[0; 5]).unwrap();
+        db.delete(b"b").unwrap();
+        db.set(b"b", &[0; 15]).unwrap();
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_max_total_bytes_after_flush() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        db.set_max_total_bytes(Some(3 * 28));
+        db.set_max_memtable_bytes(Some(0));
+        db.set(b"a", &[0; 10]).unwrap();
+        db.set(b"b", &[0; 10]).unwrap();
+        db.set(b"c", &[0; 10]).unwrap();
+
+        // flushed keys still count against the quota
+        let err = db.set(b"d", &[0; 10]).unwrap_err();
+        assert!(matches!(err, DbError::QuotaExceeded { quota: 84, requested: 112 }));
+
+        // overwriting a flushed key only counts what its value grows by
+        db.set(b"a", &[1; 10]).unwrap();
+        db.set(b"b", &[1; 5]).unwrap();
+        db.delete(b"c").unwrap();
+        db.set(b"c", &[1; 15]).unwrap();
+        let err = db.set(b"b", &[1; 6]).unwrap_err();
+        assert!(matches!(err, DbError::QuotaExceeded { quota: 84, requested: 85 }));
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_range_map() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        db.set(b"user:1:name", b"Vahid").unwrap();
+        db.set(b"user:2:name", b"Rust").unwrap();
+        db.set(b"user:3:name", b"Db").unwrap();
+        db.set(b"zone:1", b"eu").unwrap();
+        db.delete(b"user:2:name").unwrap();
+
+        let ids = db.range_map(b"user:", b"user;", |e| e.key[5]).unwrap();
+        assert_eq!(vec![b'1', b'3'], ids);
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_get_swr_fresh() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        db.set(b"Hello", b"World!").unwrap();
+
+        let entry = db
+            .get_swr(b"Hello", Duration::from_secs(60), |_| panic!("fresh values are not refreshed"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(b"World!".to_vec(), *entry.value.unwrap());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_get_swr_stale() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        let mut entry = Entry {
+            key: b"Hello".to_vec(),
+            value: Some(Arc::new(b"stale".to_vec())),
+            timestamp: 1,
+            deleted: false,
+            expires_at: None,
+        };
+        db.instant_set(&mut entry).unwrap();
+
+        let entry = db
+            .get_swr(b"Hello", Duration::from_secs(60), |key| {
+                assert_eq!(b"Hello", key);
+                Ok(Some(b"fresh".to_vec()))
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(b"stale".to_vec(), *entry.value.unwrap());
+        assert_eq!(b"fresh".to_vec(), *db.get(b"Hello").unwrap().unwrap().value.unwrap());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_get_swr_absent() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        let entry = db
+            .get_swr(b"Hello", Duration::ZERO, |_| panic!("absent keys are not refreshed"))
+            .unwrap();
+        assert_eq!(None, entry);
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_replay_keeps_newest() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+
+        create_dir(&path).unwrap();
+
+        let mut storage = Storage::new(&path).unwrap();
+        storage.set(b"Hello", b"new", false, 200).unwrap();
+        storage.delete(b"Name", 300).unwrap();
+        storage.commit().unwrap();
+        drop(storage);
+
+        // replayed later, but written earlier
+        let mut storage = Storage::new(&path).unwrap();
+        storage.set(b"Hello", b"old", false, 100).unwrap();
+        storage.set(b"Name", b"Vahid", false, 250).unwrap();
+        storage.commit().unwrap();
+        drop(storage);
+
+        for _ in 0..2 {
+            let db = Db::init_from_existing(path.clone()).unwrap();
+            assert_eq!(b"new".to_vec(), *db.get(b"Hello").unwrap().unwrap().value.unwrap());
+            assert_eq!(Some(200), db.get_timestamp(b"Hello").unwrap());
+            assert_eq!(None, db.get(b"Name").unwrap());
+            assert_eq!(Some(300), db.get_timestamp(b"Name").unwrap());
+        }
+
+        // Clean up
+        remove_dir(&path).unwrap();
+    }
+
+    #[test]
+    fn test_timestamp_overlap() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+
+        create_dir(&path).unwrap();
+
+        let mut storage = Storage::new(&path).unwrap();
+        storage
+            .set(b"Hello", b"World!", false, 200)
+            .expect("Error: could not write in the file");
+        storage
+            .set(b"Name", b"Vahid", false, 300)
+            .expect("Error: could not write in the file");
+        storage.commit().expect("Error: could not flush the file");
+        drop(storage);
+
+        let mut storage = Storage::new(&path).unwrap();
+        storage
+            .set(b"gg", b"wp", false, 100)
+            .expect("Error: could not write in the file");
+        storage.commit().expect("Error: could not flush the file");
+        drop(storage);
+
+        let files = scan_dir(&path).unwrap();
+
+        let mut overlaps = Vec::new();
+        let db = Db::open_with_recovery_callback(path, |event| {
+            if let RecoveryEvent::TimestampOverlap { .. } = event {
+                overlaps.push(event);
+            }
+        })
+        .unwrap();
+
+        assert_eq!(
+            vec![RecoveryEvent::TimestampOverlap {
+                previous: files[0].clone(),
+                previous_max: 300,
+                next: files[1].clone(),
+                next_min: 100,
+            }],
+            overlaps
+        );
+
+        // Clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_set_batch_atomic() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path.clone()).unwrap();
+
+        db.set(b"a", b"old").unwrap();
+
+        let large = vec![7; 64 * 1024];
+        let batch: Vec<(&[u8], &[u8])> = vec![
+            (b"a", b"new"),
+            (b"b", &large),
+            (b"c", &large),
+            (b"d", b"value"),
+        ];
+
+        db.storage.as_mut().unwrap().fail_after_writes(3);
+        assert!(db.set_batch_atomic(&batch).is_err());
+
+        assert_eq!(b"old".to_vec(), *db.get(b"a").unwrap().unwrap().value.unwrap());
+        assert_eq!(None, db.get(b"b").unwrap());
+        assert_eq!(1, db.total_records().unwrap());
+
+        db.storage.as_mut().unwrap().fail_after_writes(usize::MAX);
+        db.set_batch_atomic(&batch).unwrap();
+        assert_eq!(b"new".to_vec(), *db.get(b"a").unwrap().unwrap().value.unwrap());
+        assert_eq!(b"value".to_vec(), *db.get(b"d").unwrap().unwrap().value.unwrap());
+
+        drop(db);
+        let db = Db::init_from_existing(path).unwrap();
+        assert_eq!(large, *db.get(b"c").unwrap().unwrap().value.unwrap());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_set_batch() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path.clone()).unwrap();
+
+        let commits = Arc::new(Mutex::new(0));
+        let sink = commits.clone();
+        db.on_commit(Arc::new(move |_| *sink.lock().unwrap() += 1));
+
+        let owned: Vec<(Vec<u8>, Vec<u8>)> = (0..1000)
+            .map(|i| (format!("key{:04}", i).into_bytes(), format!("value{}", i).into_bytes()))
+            .collect();
+        let pairs: Vec<(&[u8], &[u8])> = owned.iter().map(|(k, v)| (&k[..], &v[..])).collect();
+
+        db.set_batch(&pairs).unwrap();
+        assert_eq!(1, *commits.lock().unwrap());
+        assert_eq!(1000, db.keys().unwrap().len());
+        assert_eq!(b"value999".to_vec(), *db.get(b"key0999").unwrap().unwrap().value.unwrap());
+
+        let keys: Vec<&[u8]> = pairs.iter().take(500).map(|(k, _)| *k).collect();
+        db.delete_batch(&keys).unwrap();
+        assert_eq!(2, *commits.lock().unwrap());
+        assert_eq!(None, db.get(b"key0000").unwrap());
+        assert_eq!(500, db.keys().unwrap().len());
+
+        // a failed batch deletes nothing
+        let keys: Vec<&[u8]> = pairs.iter().skip(500).map(|(k, _)| *k).collect();
+        db.storage.as_mut().unwrap().fail_after_writes(10);
+        assert!(db.delete_batch(&keys).is_err());
+        assert_eq!(500, db.keys().unwrap().len());
+        db.storage.as_mut().unwrap().fail_after_writes(usize::MAX);
+
+        drop(db);
+        let db = Db::init_from_existing(path).unwrap();
+        assert_eq!(500, db.keys().unwrap().len());
+        assert_eq!(b"value500".to_vec(), *db.get(b"key0500").unwrap().unwrap().value.unwrap());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_encryption_at_rest() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let key = [42; 32];
+
+        let mut db = Db::init_from_existing_with_key(path.clone(), Some(key)).unwrap();
+        db.set(b"Hello", b"World!").unwrap();
+        db.set(b"Name", b"Vahid").unwrap();
+        db.delete(b"Name").unwrap();
+        let snapshot = db.get_snapshot().unwrap();
+        drop(db);
+
+        let contains = |haystack: &[u8], needle: &[u8]| haystack.windows(needle.len()).any(|w| w == needle);
+        for file in scan_dir(&path).unwrap() {
+            let bytes = fs::read(&file).unwrap();
+            assert!(!contains(&bytes, b"Hello"));
+            assert!(!contains(&bytes, b"World!"));
+            assert!(!contains(&bytes, b"Name"));
         }
-        keys.sort();
-        keys
-    }
 
-    pub fn delete(&mut self, key: &[u8]) -> io::Result<()> {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
-            .as_micros();
+        // without the key, or with the wrong one, opening fails and leaves
+        // the files alone
+        let files = scan_dir(&path).unwrap();
+        let err = Db::init_from_existing(path.clone()).unwrap_err();
+        assert!(matches!(err, DbError::Encryption(_)));
+        let err = Db::init_from_existing_with_key(path.clone(), Some([7; 32])).unwrap_err();
+        assert!(matches!(err, DbError::Encryption(_)));
+        assert_eq!(files, scan_dir(&path).unwrap());
 
-        self.storage.delete(key, timestamp)?;
+        let db = Db::init_from_existing_with_key(path.clone(), Some(key)).unwrap();
+        assert_eq!(b"World!".to_vec(), *db.get(b"Hello").unwrap().unwrap().value.unwrap());
+        assert_eq!(None, db.get(b"Name").unwrap());
 
-        self.storage.commit()?;
-
-        self.mem_table.delete(key, timestamp);
+        // snapshots travel in plaintext but are encrypted once restored
+        let restored_path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut restored = Db::init_from_existing_with_key(restored_path, Some(key)).unwrap();
+        restored.set_snapshot(snapshot).unwrap();
+        assert_eq!(b"World!".to_vec(), *restored.get(b"Hello").unwrap().unwrap().value.unwrap());
+        for file in scan_dir(&restored.dir).unwrap() {
+            assert!(!contains(&fs::read(&file).unwrap(), b"World!"));
+        }
 
-        Ok(())
+        // clean up
+        remove_dir(&db.dir).unwrap();
+        remove_dir(&restored.dir).unwrap();
     }
 
-    pub fn get_snapshot(&mut self) -> Vec<u8> {
-        let entries = self.mem_table.get_all();
-        let mut snapshot: Vec<u8> = Vec::new();
-        for data in entries {
-            if !data.deleted {
-                snapshot.extend_from_slice(&(data.key.len() as u64).to_le_bytes());
-                snapshot.extend_from_slice(&(data.deleted as u8).to_le_bytes());
-                snapshot
-                    .extend_from_slice(&(data.value.as_ref().unwrap().len() as u64).to_le_bytes());
-
-                snapshot.extend_from_slice(&(data.key));
-                snapshot.extend_from_slice(&(data.value.as_ref().unwrap()));
-                snapshot.extend_from_slice(&(data.timestamp.to_le_bytes()));
-            }
-        }
-        snapshot
-    }
+    #[test]
+    fn test_set_with_ttl() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path.clone()).unwrap();
 
-    pub fn set_snapshot(&mut self, raw_data: Vec<u8>) -> io::Result<()> {
-        self.storage.write_all(raw_data)?;
-        let files = scan_dir(&self.dir)?;
-        let data: Vec<Entry> = StorageIterator::new(&files.last().unwrap())?.collect();
-        for entry in data {
-            self.mem_table
-                .set_or_insert(&entry.key, &entry.value.unwrap(), entry.timestamp);
-        }
+        db.set_with_ttl(b"session", b"short", Duration::from_millis(20)).unwrap();
+        db.set_with_ttl(b"cache", b"long", Duration::from_secs(3600)).unwrap();
+        db.set(b"Hello", b"World!").unwrap();
 
-        Ok(())
-    }
+        assert_eq!(b"short".to_vec(), *db.get(b"session").unwrap().unwrap().value.unwrap());
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(None, db.get(b"session").unwrap());
+        assert_eq!(b"long".to_vec(), *db.get(b"cache").unwrap().unwrap().value.unwrap());
+        assert_eq!(None, db.get(b"Hello").unwrap().unwrap().expires_at);
 
-    pub fn purge_database(&mut self) -> io::Result<()> {
-        self.storage.purge_storage()?;
-        self.mem_table.purge_mem_table();
-        Ok(())
+        // the expiry survives a reopen
+        drop(db);
+        let mut db = Db::init_from_existing(path).unwrap();
+        assert_eq!(None, db.get(b"session").unwrap());
+        assert!(db.get(b"cache").unwrap().unwrap().expires_at.is_some());
+
+        // compaction drops the expired key altogether
+        assert_eq!(vec![b"Hello".to_vec(), b"cache".to_vec()], db.keys().unwrap());
+        assert_eq!(3, db.total_records().unwrap());
+        db.compact().unwrap();
+        assert_eq!(vec![b"Hello".to_vec(), b"cache".to_vec()], db.keys().unwrap());
+        assert_eq!(2, db.total_records().unwrap());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
     }
-}
 
-#[cfg(test)]
-mod test {
-    use std::{path::PathBuf, time::SystemTime};
+    #[test]
+    fn test_extend_ttl() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
 
-    use rand::Rng;
+        db.set_with_ttl(b"session", b"value", Duration::from_secs(60)).unwrap();
+        let expires_at = db.get(b"session").unwrap().unwrap().expires_at.unwrap();
+        assert!(db.extend_ttl(b"session", Duration::from_secs(3600)).unwrap());
+        let extended = db.get(b"session").unwrap().unwrap();
+        assert_eq!(b"value".to_vec(), *extended.value.unwrap());
+        assert!(extended.expires_at.unwrap() > expires_at);
 
-    use crate::{
-        entry::Entry,
-        storage::Storage,
-        storage_iterator::StorageIterator,
-        utils::{create_dir, remove_dir, scan_dir},
-    };
+        // absent and expired keys are left alone
+        let records = db.total_records().unwrap();
+        assert!(!db.extend_ttl(b"missing", Duration::from_secs(3600)).unwrap());
+        db.set_with_ttl(b"expired", b"value", Duration::ZERO).unwrap();
+        assert!(!db.extend_ttl(b"expired", Duration::from_secs(3600)).unwrap());
+        assert_eq!(None, db.get(b"expired").unwrap());
+        assert_eq!(records + 1, db.total_records().unwrap());
 
-    use super::Db;
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
 
     #[test]
-    fn init_engine() {
+    fn test_write_batch() {
         let mut range = rand::thread_rng();
         let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path.clone()).unwrap();
 
-        create_dir(&path).unwrap();
+        db.set(b"gone", b"soon").unwrap();
 
-        let mut db = Db::new(path);
+        let mut batch = WriteBatch::new();
+        batch
+            .set(b"Hello", b"World!")
+            .delete(b"Hello")
+            .set(b"Name", b"Vahid")
+            .set(b"Name", b"Mohsseni")
+            .delete(b"gg")
+            .set(b"gg", b"wp")
+            .delete(b"gone");
+        db.write(batch).unwrap();
 
-        let key1 = b"Hello".to_owned();
-        let value1 = *b"World!";
+        let check = |db: &Db| {
+            assert_eq!(None, db.get(b"Hello").unwrap());
+            assert_eq!(b"Mohsseni".to_vec(), *db.get(b"Name").unwrap().unwrap().value.unwrap());
+            assert_eq!(b"wp".to_vec(), *db.get(b"gg").unwrap().unwrap().value.unwrap());
+            assert_eq!(None, db.get(b"gone").unwrap());
+        };
+        check(&db);
 
-        db.set(&key1, &value1).unwrap();
+        // the same state is rebuilt from disk
+        drop(db);
+        let db = Db::init_from_existing(path).unwrap();
+        check(&db);
 
-        assert_eq!(b"Hello".to_owned().to_vec(), db.get(&key1).unwrap().key);
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
 
-        let key2 = b"Name".to_owned();
-        let value2 = *b"Vahid";
+    #[test]
+    fn test_generation() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
 
-        db.set(&key2, &value2).unwrap();
+        let initial = db.generation();
 
-        assert_eq!(
-            b"Vahid".to_owned().to_vec(),
-            db.get(&key2).unwrap().value.unwrap()
-        );
+        db.set(b"Hello", b"World!").unwrap();
+        let (entry, after_set) = db.get_with_generation(b"Hello").unwrap().unwrap();
+        assert_eq!(b"World!".to_vec(), *entry.value.unwrap());
+        assert!(after_set > initial);
+        assert_eq!(after_set, db.generation());
 
-        db.delete(&key1).unwrap();
+        db.delete(b"Hello").unwrap();
+        assert!(db.generation() > after_set);
+        assert_eq!(None, db.get_with_generation(b"Hello").unwrap());
 
-        assert_eq!(true, db.get(&key1).unwrap().deleted);
+        // reads don't move the generation
+        let current = db.generation();
+        db.get(b"Hello").unwrap();
+        assert_eq!(current, db.generation());
 
-        // Clean up
-        remove_dir(&db.dir).expect("Error: could not remove the directory");
+        // clean up
+        remove_dir(&db.dir).unwrap();
     }
 
     #[test]
-    fn read_from_multiple_files() {
+    fn test_open_segment() {
         let mut range = rand::thread_rng();
         let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
 
         create_dir(&path).unwrap();
 
         let mut storage = Storage::new(&path).unwrap();
-
-        let key1 = b"Hello".to_owned();
-        let value1 = *b"World!";
-        let timestamp1 = SystemTime::now().elapsed().unwrap().as_micros();
         storage
-            .set(&key1, &value1, false, timestamp1)
+            .set(b"Hello", b"World!", false, 100)
             .expect("Error: could not write in the file");
+        storage.commit().expect("Error: could not flush the file");
+        drop(storage);
 
-        let key2 = b"Name".to_owned();
-        let value2 = *b"Vahid";
-        let timestamp2 = SystemTime::now().elapsed().unwrap().as_micros();
+        let mut storage = Storage::new(&path).unwrap();
         storage
-            .set(&key2, &value2, false, timestamp2)
+            .set(b"Name", b"Vahid", false, 200)
             .expect("Error: could not write in the file");
+        storage.delete(b"gg", 200).expect("Error: could not complete delete operation");
+        storage.commit().expect("Error: could not flush the file");
+        drop(storage);
 
-        let key3 = b"gg".to_owned();
-        let value3 = *b"wp";
-        let timestamp3 = SystemTime::now().elapsed().unwrap().as_micros();
-        storage
-            .set(&key3, &value3, false, timestamp3)
-            .expect("Error: could not write in the file");
+        let files = scan_dir(&path).unwrap();
+        let mut db = Db::open_segment(files[1].clone()).unwrap();
 
-        let key4 = b"Name".to_owned();
-        let timestamp4 = SystemTime::now().elapsed().unwrap().as_micros();
-        storage
-            .delete(&key4, timestamp4)
-            .expect("Error: could not complete delete operation");
+        assert_eq!(None, db.get(b"Hello").unwrap());
+        assert_eq!(b"Vahid".to_vec(), *db.get(b"Name").unwrap().unwrap().value.unwrap());
+        assert!(matches!(db.set(b"Hello", b"again"), Err(DbError::ReadOnly)));
+        assert_eq!(files, scan_dir(&path).unwrap());
 
-        storage.commit().expect("Error: could not flush the file");
+        // counts only cover the opened segment
+        assert_eq!(2, db.total_records().unwrap());
+        assert_eq!(1, db.stats().unwrap().files);
 
-        drop(storage);
+        // Clean up
+        remove_dir(&path).unwrap();
+    }
 
-        let mut storage = Storage::new(&path).unwrap();
+    #[test]
+    fn test_set_with_timestamp() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
 
-        let key5 = b"Hello".to_owned();
-        let value5 = *b"RUST";
-        let timestamp5 = SystemTime::now().elapsed().unwrap().as_micros();
-        storage
-            .set(&key5, &value5, false, timestamp5)
-            .expect("Error: could not write in the file");
+        assert!(db.set_with_timestamp(b"Hello", b"World!", 100).unwrap());
+        assert!(!db.set_with_timestamp(b"Hello", b"World!", 100).unwrap());
+        assert!(!db.set_with_timestamp(b"Hello", b"older", 50).unwrap());
+        assert_eq!(1, db.total_records().unwrap());
 
-        let key6 = b"gg".to_owned();
-        let timestamp6 = SystemTime::now().elapsed().unwrap().as_micros();
-        storage
-            .delete(&key6, timestamp6)
-            .expect("Error: could not write in the file");
+        let entry = db.get(b"Hello").unwrap().unwrap();
+        assert_eq!(100, entry.timestamp);
+        assert_eq!(b"World!".to_vec(), *entry.value.unwrap());
 
-        storage.commit().unwrap();
+        assert!(db.set_with_timestamp(b"Hello", b"newer", 150).unwrap());
+        assert_eq!(b"newer".to_vec(), *db.get(b"Hello").unwrap().unwrap().value.unwrap());
 
-        drop(storage);
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
 
+    #[test]
+    fn test_set_snapshot_corrupt() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
         let mut db = Db::init_from_existing(path).unwrap();
 
-        // see a key exists
-        assert_eq!(b"gg".to_owned().to_vec(), db.get(&key3).unwrap().key);
-        assert_eq!(None, db.get(&key3).unwrap().value);
+        db.set(b"Hello", b"World!").unwrap();
+        db.set(b"Name", b"Vahid").unwrap();
+        let snapshot = db.get_snapshot().unwrap();
 
-        assert_eq!(b"Hello".to_owned().to_vec(), db.get(&key5).unwrap().key);
-        assert_eq!(
-            b"RUST".to_owned().to_vec(),
-            db.get(&key5).unwrap().value.unwrap()
-        );
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut restored = Db::init_from_existing(path.clone()).unwrap();
 
-        // check the new storage file
-        let files = scan_dir(&db.dir).unwrap();
-        let str_iter = StorageIterator::new(&files[files.len() - 1]).unwrap();
+        // cut off in the middle of the second record
+        let truncated = snapshot[..snapshot.len() - 5].to_vec();
+        let err = restored.set_snapshot(truncated).unwrap_err();
+        assert!(matches!(err, DbError::Corrupt(_)));
+        assert_eq!(None, restored.get(b"Hello").unwrap());
 
-        let data: Vec<Entry> = str_iter.collect();
+        // a flipped bit fails the checksum
+        let mut damaged = snapshot.clone();
+        damaged[6 + 20] ^= 0x01;
+        let err = restored.set_snapshot(damaged).unwrap_err();
+        assert!(matches!(err, DbError::Corrupt(_)));
 
-        assert_eq!(3, data.len());
+        // so does a snapshot from another format version
+        let mut newer = snapshot.clone();
+        newer[4] += 1;
+        let err = restored.set_snapshot(newer).unwrap_err();
+        assert!(matches!(err, DbError::InvalidFormat(_)));
+        let err = restored.set_snapshot(snapshot[6..].to_vec()).unwrap_err();
+        assert!(matches!(err, DbError::InvalidFormat(_)));
 
-        // Clean up
+        // nothing from the failed attempts reached the file
+        restored.set_snapshot(snapshot.clone()).unwrap();
+        drop(restored);
+        let restored = Db::init_from_existing(path).unwrap();
+        assert_eq!(snapshot, restored.get_snapshot().unwrap());
+
+        // clean up
         remove_dir(&db.dir).unwrap();
+        remove_dir(&restored.dir).unwrap();
     }
 
     #[test]
-    fn test_init_from(){
+    fn test_snapshot_chunk() {
         let mut range = rand::thread_rng();
         let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
         let mut db = Db::init_from_existing(path).unwrap();
-        let key1 = b"Hello".to_owned();
-        let value1 = *b"World!";
 
-        db.set(&key1, &value1).unwrap();
+        for idx in 0..10u8 {
+            db.set(&[b'k', idx], b"value").unwrap();
+        }
+        db.delete(&[b'k', 4]).unwrap();
+
+        // each record is 8 + 1 + 8 + 2 + 5 + 16 + 4 = 44 bytes, after a 6
+        // byte snapshot header
+        let (first, cursor) = db.snapshot_chunk(None, 100).unwrap();
+        assert_eq!(6 + 88, first.len());
+        assert_eq!(Some(vec![b'k', 1]), cursor);
+
+        // this chunk gets lost in transit and is requested again
+        let (lost, _) = db.snapshot_chunk(cursor.clone(), 100).unwrap();
+        let (second, mut cursor) = db.snapshot_chunk(cursor, 100).unwrap();
+        assert_eq!(lost, second);
+
+        let mut snapshot = [first, second].concat();
+        while let Some(resume) = cursor {
+            let (chunk, next) = db.snapshot_chunk(Some(resume), 100).unwrap();
+            snapshot.extend_from_slice(&chunk);
+            cursor = next;
+        }
+        assert_eq!(db.get_snapshot().unwrap(), snapshot);
 
-        assert_eq!(b"Hello".to_owned().to_vec(), db.get(&key1).unwrap().key);
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut restored = Db::init_from_existing(path).unwrap();
+        restored.set_snapshot(snapshot).unwrap();
+        assert_eq!(db.keys().unwrap(), restored.keys().unwrap());
+        assert_eq!(9, restored.total_records().unwrap());
 
         // clean up
         remove_dir(&db.dir).unwrap();
+        remove_dir(&restored.dir).unwrap();
     }
 
     #[test]
-    fn test_get_keys_from(){
+    fn test_tombstones() {
         let mut range = rand::thread_rng();
         let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
         let mut db = Db::init_from_existing(path).unwrap();
 
-        let pattern = b"log_".to_owned();
-        assert_eq!(0, db.get_keys_with_pattern(&pattern).len());
+        for key in [b"a", b"b", b"c", b"d"] {
+            db.set(key, b"value").unwrap();
+        }
+        db.delete(b"c").unwrap();
+        db.delete(b"a").unwrap();
+        db.delete(b"missing").unwrap();
 
-        let key1 = b"log_1".to_owned();
-        let value1 = *b"data1!";
-        db.set(&key1, &value1).unwrap();
+        db.set_tombstone_visibility(true);
+        let expected: Vec<(Vec<u8>, u128)> = [b"a".as_slice(), b"c", b"missing"]
+            .iter()
+            .map(|key| (key.to_vec(), db.get(key).unwrap().unwrap().timestamp))
+            .collect();
 
-        let key2 = b"log_2".to_owned();
-        let value2 = *b"data2!";
-        db.set(&key2, &value2).unwrap();
+        assert_eq!(expected, db.tombstones().unwrap());
+        assert_eq!(3, db.count_tombstones().unwrap());
 
-        let key1 = b"test_log_1".to_owned();
-        let value1 = *b"test data1!";
-        db.set(&key1, &value1).unwrap();
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
 
-        let key2 = b"test_log_2".to_owned();
-        let value2 = *b"test data2!";
-        db.set(&key2, &value2).unwrap();
-        
-        let pattern = b"log_".to_owned();
-        assert_eq!(2, db.get_keys_with_pattern(&pattern).len());
+    #[test]
+    fn test_value_validator() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        db.set_value_validator(Some(Arc::new(|value: &[u8]| {
+            let value = std::str::from_utf8(value).map_err(|e| e.to_string())?.trim();
+            if value.starts_with('{') && value.ends_with('}') {
+                Ok(())
+            } else {
+                Err("value is not a JSON object".to_string())
+            }
+        })));
+
+        let err = db.set(b"config", b"not json").unwrap_err();
+        assert!(matches!(&err, DbError::InvalidValue { reason } if reason == "value is not a JSON object"));
+        assert_eq!("invalid value: value is not a JSON object", err.to_string());
+        assert_eq!(None, db.get(b"config").unwrap());
+
+        db.set(b"config", br#"{"enabled": true}"#).unwrap();
+        assert_eq!(
+            br#"{"enabled": true}"#.to_vec(),
+            *db.get(b"config").unwrap().unwrap().value.unwrap()
+        );
 
         // clean up
         remove_dir(&db.dir).unwrap();
     }
 
     #[test]
-    fn test_instant_set(){
+    fn test_range() {
         let mut range = rand::thread_rng();
         let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
         let mut db = Db::init_from_existing(path).unwrap();
-        let key1 = b"Hello".to_owned();
-        let value1 = *b"World!";
 
-        db.set(&key1, &value1).unwrap();
+        for key in [b"b", b"d", b"f", b"h", b"j"] {
+            db.set(key, b"value").unwrap();
+        }
+        db.delete(b"f").unwrap();
+        db.set_with_ttl(b"e", b"expired", Duration::ZERO).unwrap();
 
-        assert_eq!(b"Hello".to_owned().to_vec(), db.get(&key1).unwrap().key);
+        let keys = |entries: Vec<Entry>| -> Vec<Vec<u8>> { entries.into_iter().map(|e| e.key).collect() };
 
-        let mut e = db.get(&key1).unwrap();
-        let mut new_k: Vec<u8> = Vec::new();
-        new_k.extend_from_slice("Hello1!".as_bytes());
-        e.key = new_k.to_owned();
-        let mut new_val: Vec<u8> = Vec::new();
-        new_val.extend_from_slice("Hello2u2!".as_bytes());
-        e.value = Some(new_val);
+        assert_eq!(vec![b"b".to_vec(), b"d".to_vec()], keys(db.range(b"b", b"f").unwrap()));
+        assert!(db.range(b"d", b"d").unwrap().is_empty());
+        assert!(db.range(b"k", b"z").unwrap().is_empty());
 
-        db.instant_set(&mut e).unwrap();
+        // bounds between existing keys, the tombstoned f and expired e are skipped
+        assert_eq!(vec![b"d".to_vec(), b"h".to_vec()], keys(db.range(b"c", b"i").unwrap()));
 
-        assert_eq!(b"Hello1!".to_owned().to_vec(), db.get(&new_k).unwrap().key);
+        // open-ended bounds
+        assert_eq!(vec![b"b".to_vec(), b"d".to_vec()], keys(db.range(b"", b"e").unwrap()));
+        assert_eq!(vec![b"h".to_vec(), b"j".to_vec()], keys(db.range(b"g", b"").unwrap()));
+        assert_eq!(4, db.range(b"", b"").unwrap().len());
 
         // clean up
         remove_dir(&db.dir).unwrap();
     }
 
     #[test]
-    fn snapshot_test() {
+    fn test_range_bounded() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        // 1-byte keys with 9-byte values: 10 bytes per entry
+        for key in [b"a", b"b", b"c", b"d", b"e"] {
+            db.set(key, &[0; 9]).unwrap();
+        }
+
+        let (entries, truncated) = db.range_bounded(b"a", b"z", 25).unwrap();
+        assert!(truncated);
+        assert_eq!(2, entries.len());
+
+        let (entries, truncated) = db.range_bounded(b"c", b"z", 30).unwrap();
+        assert!(!truncated);
+        assert_eq!(3, entries.len());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_replay_interleaved_writers() {
         let mut range = rand::thread_rng();
         let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
 
         create_dir(&path).unwrap();
 
-        let mut db = Db::new(path);
+        // two logical writers appending to the same segment
+        let mut storage = Storage::new(&path).unwrap();
+        storage.set(b"Hello", b"a1", false, 100).unwrap();
+        storage.set(b"Hello", b"b1", false, 400).unwrap();
+        storage.set(b"Hello", b"a2", false, 300).unwrap();
+        storage.delete(b"Name", 250).unwrap();
+        storage.set(b"Name", b"Vahid", false, 150).unwrap();
+        storage.set(b"gg", b"wp", false, 200).unwrap();
+        storage.delete(b"gg", 120).unwrap();
+        storage.commit().unwrap();
+        drop(storage);
 
-        let key1 = b"Hello".to_owned();
-        let value1 = *b"World!";
+        let db = Db::init_from_existing(path).unwrap();
 
-        db.set(&key1, &value1).unwrap();
+        let hello = db.get(b"Hello").unwrap().unwrap();
+        assert_eq!(b"b1".to_vec(), *hello.value.unwrap());
+        assert_eq!(400, hello.timestamp);
+        assert_eq!(None, db.get(b"Name").unwrap());
+        assert_eq!(b"wp".to_vec(), *db.get(b"gg").unwrap().unwrap().value.unwrap());
 
-        assert_eq!(b"Hello".to_owned().to_vec(), db.get(&key1).unwrap().key);
+        // Clean up
+        remove_dir(&db.dir).unwrap();
+    }
 
-        let key2 = b"Name".to_owned();
-        let value2 = *b"Vahid";
+    #[test]
+    fn test_merge() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
 
-        db.set(&key2, &value2).unwrap();
+        let increment = |old: Option<&[u8]>| {
+            let count = old.map_or(0, |bytes| u64::from_le_bytes(bytes.try_into().unwrap()));
+            (count + 1).to_le_bytes().to_vec()
+        };
+        for _ in 0..5 {
+            db.merge(b"counter", increment).unwrap();
+        }
+        assert_eq!(5u64.to_le_bytes().to_vec(), *db.get(b"counter").unwrap().unwrap().value.unwrap());
 
-        assert_eq!(
-            b"Vahid".to_owned().to_vec(),
-            db.get(&key2).unwrap().value.unwrap()
-        );
+        // a deleted key starts over
+        db.delete(b"counter").unwrap();
+        db.merge(b"counter", increment).unwrap();
+        assert_eq!(1u64.to_le_bytes().to_vec(), *db.get(b"counter").unwrap().unwrap().value.unwrap());
 
-        db.delete(&key1).unwrap();
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
 
-        assert_eq!(true, db.get(&key1).unwrap().deleted);
+        // absent keys only match `None`
+        assert!(!db.compare_and_swap(b"Hello", Some(b"World!"), b"v1").unwrap());
+        assert!(db.compare_and_swap(b"Hello", None, b"v1").unwrap());
+        assert!(!db.compare_and_swap(b"Hello", None, b"v2").unwrap());
 
-        let snapshot = db.get_snapshot();
+        // the swap only happens on a match
+        assert!(!db.compare_and_swap(b"Hello", Some(b"v0"), b"v2").unwrap());
+        assert_eq!(b"v1".to_vec(), *db.get(b"Hello").unwrap().unwrap().value.unwrap());
+        assert!(db.compare_and_swap(b"Hello", Some(b"v1"), b"v2").unwrap());
+        assert_eq!(b"v2".to_vec(), *db.get(b"Hello").unwrap().unwrap().value.unwrap());
 
-        // remove dir
+        // deleted keys count as absent
+        db.delete(b"Hello").unwrap();
+        assert!(db.compare_and_swap(b"Hello", None, b"v3").unwrap());
+
+        // clean up
         remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn test_column_families() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path.clone()).unwrap();
+
+        db.set(b"Hello", b"default").unwrap();
+        db.set_cf("users", b"Hello", b"World!").unwrap();
+        db.set_cf("sessions", b"Hello", b"Vahid").unwrap();
+        db.delete_cf("sessions", b"Hello").unwrap();
+
+        assert_eq!(b"default".to_vec(), *db.get(b"Hello").unwrap().unwrap().value.unwrap());
+        assert_eq!(b"World!".to_vec(), *db.get_cf("users", b"Hello").unwrap().unwrap().value.unwrap());
+        assert_eq!(None, db.get_cf("sessions", b"Hello").unwrap());
+        assert_eq!(None, db.get_cf("missing", b"Hello").unwrap());
+        assert_eq!(vec![b"Hello".to_vec()], db.keys().unwrap());
+
+        let err = db.set_cf("../escape", b"Hello", b"World!").unwrap_err();
+        assert_eq!(Some(io::ErrorKind::InvalidInput), err.io_kind());
+
+        // families are found again on reopen
         drop(db);
+        let db = Db::init_from_existing(path.clone()).unwrap();
+        assert_eq!(vec!["sessions", "users"], db.column_families().collect::<Vec<_>>());
+        assert_eq!(b"default".to_vec(), *db.get(b"Hello").unwrap().unwrap().value.unwrap());
+        assert_eq!(b"World!".to_vec(), *db.get_cf("users", b"Hello").unwrap().unwrap().value.unwrap());
+        assert_eq!(None, db.get_cf("sessions", b"Hello").unwrap());
+        drop(db);
+
+        // and take the settings the database is opened with
+        let families = scan_dir(&path.join("users")).unwrap();
+        let config = DbConfig::new(path.clone())
+            .with_sync(true)
+            .with_max_total_bytes(30)
+            .with_open_mode(OpenMode::Reopen);
+        let mut db = Db::open(config).unwrap();
+        assert_eq!(families, scan_dir(&path.join("users")).unwrap());
+        assert!(db.families["users"].sync_on_commit);
+        let err = db.set_cf("users", b"Name", b"Vahid").unwrap_err();
+        assert!(matches!(err, DbError::QuotaExceeded { quota: 30, .. }));
+        drop(db);
+
+        // also when opened read-only
+        let db = Db::open_read_only(path).unwrap();
+        assert_eq!(b"World!".to_vec(), *db.get_cf("users", b"Hello").unwrap().unwrap().value.unwrap());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
 
+    #[test]
+    fn test_append() {
+        let mut range = rand::thread_rng();
         let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
-        create_dir(&path).unwrap();
-        let mut db = Db::new(path);
+        let mut db = Db::init_from_existing(path).unwrap();
 
-        db.set_snapshot(snapshot).unwrap();
+        assert_eq!(4, db.append(b"log", b"one,").unwrap());
+        assert_eq!(8, db.append(b"log", b"two,").unwrap());
+        assert_eq!(13, db.append(b"log", b"three").unwrap());
+        assert_eq!(b"one,two,three".to_vec(), *db.get(b"log").unwrap().unwrap().value.unwrap());
 
-        assert_eq!(
-            b"Vahid".to_owned().to_vec(),
-            db.get(&key2).unwrap().value.unwrap()
-        );
+        db.delete(b"log").unwrap();
+        assert_eq!(5, db.append(b"log", b"fresh").unwrap());
 
         // clean up
         remove_dir(&db.dir).unwrap();
@@ -1,56 +1,736 @@
-use std::{sync::{Arc, Mutex}, path::PathBuf, io};
+use std::{
+    collections::HashMap,
+    io,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, PoisonError, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
 
-use crate::{db::Db, entry::Entry};
+use crate::{
+    batch::WriteBatch,
+    config::DbConfig,
+    db::{Db, EncryptionKey},
+    entry::Entry,
+    error::{DbError, Result},
+    stats::DbStats,
+};
 
 #[derive(Clone)]
 pub struct DBEngine {
-    pub database: Arc<Mutex<Db>>,
+    pub database: Arc<RwLock<Db>>,
+    in_flight: Arc<Mutex<HashMap<Vec<u8>, Arc<Flight>>>>,
+    compaction_wanted: Arc<AtomicBool>,
+}
+
+/// Expired keys the sweeper tombstones before it asks for a compaction.
+const SWEEP_COMPACTION_THRESHOLD: usize = 1024;
+
+/// Handle to the thread started by `DBEngine::start_expiry_sweeper`; dropping
+/// it stops the thread and waits for it to finish.
+pub struct ExpirySweeper {
+    control: Arc<(Mutex<SweeperControl>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// What the sweeper thread is asked to do, guarded together with its wakeup.
+#[derive(Default)]
+struct SweeperControl {
+    stopped: bool,
+    /// Sweeps asked for by `ExpirySweeper::tick`, and how many of them ran.
+    requested: u64,
+    done: u64,
+}
+
+impl ExpirySweeper {
+    /// The sweeper thread, e.g. to look up its name.
+    pub fn thread(&self) -> &thread::Thread {
+        self.thread.as_ref().expect("the thread is only taken when dropped").thread()
+    }
+
+    /// Makes the thread sweep right away instead of at the end of its
+    /// interval, and waits until that sweep is done.
+    pub fn tick(&self) {
+        let (control, wake) = &*self.control;
+        let mut guard = control.lock().unwrap();
+        guard.requested += 1;
+        let ticket = guard.requested;
+        wake.notify_all();
+        let _done = wake.wait_while(guard, |c| c.done < ticket && !c.stopped).unwrap();
+    }
+}
+
+impl Drop for ExpirySweeper {
+    fn drop(&mut self) {
+        let (control, wake) = &*self.control;
+        control.lock().unwrap().stopped = true;
+        wake.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// How `DBEngine::start_expiry_sweeper_with` sets up the sweeper thread.
+#[derive(Debug, Clone)]
+pub struct SweeperConfig {
+    name: String,
+    #[cfg(feature = "affinity")]
+    cpu: Option<usize>,
+}
+
+impl Default for SweeperConfig {
+    fn default() -> SweeperConfig {
+        SweeperConfig {
+            name: "expiry-sweeper".to_owned(),
+            #[cfg(feature = "affinity")]
+            cpu: None,
+        }
+    }
+}
+
+impl SweeperConfig {
+    /// A thread named `expiry-sweeper`, free to run on any CPU.
+    pub fn new() -> SweeperConfig {
+        SweeperConfig::default()
+    }
+
+    /// Names the thread, as debuggers and `top -H` show it.
+    pub fn with_thread_name(mut self, name: impl Into<String>) -> SweeperConfig {
+        self.name = name.into();
+        self
+    }
+
+    /// Pins the thread to CPU `cpu`. This is best effort: only Linux is
+    /// supported, and should pinning fail, e.g. because there is no such CPU
+    /// or the process may not use it, the thread runs unpinned.
+    #[cfg(feature = "affinity")]
+    pub fn with_cpu_affinity(mut self, cpu: usize) -> SweeperConfig {
+        self.cpu = Some(cpu);
+        self
+    }
+}
+
+/// Pins the calling thread to `cpu`, ignoring failures.
+#[cfg(all(feature = "affinity", target_os = "linux"))]
+fn pin_to_cpu(cpu: usize) {
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return;
+    }
+    // SAFETY: the set is plain data, zeroed before use and passed with its size.
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set);
+    }
+}
+
+#[cfg(all(feature = "affinity", not(target_os = "linux")))]
+fn pin_to_cpu(_cpu: usize) {}
+
+/// A loader's outcome as handed to waiters; `DbError` itself isn't `Clone`, so
+/// waiters get an `Io` error with the same kind and message.
+type SharedResult = Result<Option<Entry>, (io::ErrorKind, String)>;
+
+/// A load in progress for one key; waiters block until the loader publishes
+/// its result.
+#[derive(Default)]
+struct Flight {
+    result: Mutex<Option<SharedResult>>,
+    done: Condvar,
+}
+
+impl Flight {
+    fn wait(&self) -> Result<Option<Entry>> {
+        let mut result = self.result.lock().unwrap();
+        while result.is_none() {
+            result = self.done.wait(result).unwrap();
+        }
+        result
+            .clone()
+            .unwrap()
+            .map_err(|(kind, message)| DbError::Io(io::Error::new(kind, message)))
+    }
+
+    fn finish(&self, result: &Result<Option<Entry>>) {
+        let shared = match result {
+            Ok(entry) => Ok(entry.clone()),
+            Err(DbError::Io(e)) => Err((e.kind(), e.to_string())),
+            Err(e) => Err((io::ErrorKind::Other, e.to_string())),
+        };
+        *self.result.lock().unwrap() = Some(shared);
+        self.done.notify_all();
+    }
+}
+
+/// A load started by `get_or_load`. However the load ends, dropping this
+/// takes the key out of `in_flight` and, if the loader panicked before a
+/// result was published, hands waiters an error instead of leaving them
+/// blocked.
+struct Loading<'a> {
+    in_flight: &'a Mutex<HashMap<Vec<u8>, Arc<Flight>>>,
+    key: &'a [u8],
+    flight: Arc<Flight>,
+}
+
+impl Drop for Loading<'_> {
+    fn drop(&mut self) {
+        // nothing panics while holding the map, but this may run while unwinding
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(PoisonError::into_inner);
+        in_flight.remove(self.key);
+        drop(in_flight);
+
+        if self.flight.result.lock().unwrap().is_none() {
+            self.flight.finish(&Err(io::Error::other("the loader panicked").into()));
+        }
+    }
 }
 
 impl DBEngine {
-    pub fn new(dir: PathBuf) -> io::Result<Self>{
-        Ok(Self { database: Arc::new(Mutex::new(Db::init_from_existing(dir)?)) })
+    pub fn new(dir: PathBuf) -> Result<Self>{
+        Ok(Self::from_db(Db::init_from_existing(dir)?))
+    }
+
+    /// Like `new` for a database encrypted at rest, see
+    /// `Db::init_from_existing_with_key`.
+    pub fn new_with_key(dir: PathBuf, key: Option<EncryptionKey>) -> Result<Self> {
+        Ok(Self::from_db(Db::init_from_existing_with_key(dir, key)?))
+    }
+
+    /// Opens the database described by `config`, see `Db::open`.
+    pub fn open(config: DbConfig) -> Result<Self> {
+        Ok(Self::from_db(Db::open(config)?))
     }
 
-    pub fn set(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
-        let mut db = self.database.lock().unwrap();
+    fn from_db(db: Db) -> Self {
+        Self {
+            database: Arc::new(RwLock::new(db)),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            compaction_wanted: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut db = self.database.write().unwrap();
         db.set(key, value)?;
         Ok(())
     }
 
-    pub fn instant_set(&mut self, entry: &mut Entry) -> io::Result<()> {
-        let mut db = self.database.lock().unwrap();
+    /// Runs `Db::merge` under the write lock, so concurrent merges of a key
+    /// never lose an update.
+    pub fn merge(&mut self, key: &[u8], f: impl FnOnce(Option<&[u8]>) -> Vec<u8>) -> Result<()> {
+        let mut db = self.database.write().unwrap();
+        db.merge(key, f)
+    }
+
+    /// Runs `Db::compare_and_swap` under the write lock, so of two concurrent
+    /// swaps expecting the same value only one succeeds.
+    pub fn compare_and_swap(&mut self, key: &[u8], expected: Option<&[u8]>, new: &[u8]) -> Result<bool> {
+        let mut db = self.database.write().unwrap();
+        db.compare_and_swap(key, expected, new)
+    }
+
+    pub fn instant_set(&mut self, entry: &mut Entry) -> Result<()> {
+        let mut db = self.database.write().unwrap();
         db.instant_set(entry)?;
         Ok(())
     }
 
-    pub fn get(&mut self, key: &[u8]) -> Option<Entry> {
-        let mut db = self.database.lock().unwrap();
+    pub fn set_tombstone_visibility(&mut self, visible: bool) {
+        let mut db = self.database.write().unwrap();
+        db.set_tombstone_visibility(visible);
+    }
+
+    pub fn set_sync(&mut self, sync: bool) {
+        let mut db = self.database.write().unwrap();
+        db.set_sync(sync);
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Entry>> {
+        let db = self.database.read().unwrap();
         db.get(key)
     }
 
-    pub fn get_keys_with_pattern(&mut self, pattern: &[u8]) -> Vec<Entry> {
-        let mut db = self.database.lock().unwrap();
+    /// Like `get`, but only the value, shared with the MemTable: callers on
+    /// any thread get the same allocation instead of a copy each.
+    pub fn get_shared(&self, key: &[u8]) -> Result<Option<Arc<Vec<u8>>>> {
+        Ok(self.get(key)?.and_then(|entry| entry.value))
+    }
+
+    pub fn get_timestamp(&self, key: &[u8]) -> Result<Option<u128>> {
+        let db = self.database.read().unwrap();
+        db.get_timestamp(key)
+    }
+
+    /// Resolves all of `keys` under a single read lock; see `Db::multi_get`.
+    pub fn multi_get(&self, keys: &[&[u8]]) -> Result<Vec<Option<Entry>>> {
+        let db = self.database.read().unwrap();
+        db.multi_get(keys)
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        let db = self.database.read().unwrap();
+        db.contains_key(key)
+    }
+
+    /// Returns the entry for `key`, calling `loader` to fetch and store the
+    /// value when the key is missing. Concurrent calls for the same missing key
+    /// share a single `loader` invocation: the first caller loads while the
+    /// others wait for its result, errors included.
+    pub fn get_or_load(
+        &self,
+        key: &[u8],
+        loader: impl FnOnce() -> io::Result<Option<Vec<u8>>>,
+    ) -> Result<Option<Entry>> {
+        let loading = {
+            // in_flight is always locked before the database
+            let mut in_flight = self.in_flight.lock().unwrap();
+            if let Some(entry) = self.database.read().unwrap().get(key)? {
+                return Ok(Some(entry));
+            }
+            if let Some(flight) = in_flight.get(key).cloned() {
+                drop(in_flight);
+                return flight.wait();
+            }
+            let flight = Arc::new(Flight::default());
+            in_flight.insert(key.to_owned(), flight.clone());
+            Loading {
+                in_flight: &self.in_flight,
+                key,
+                flight,
+            }
+        };
+
+        let result = loader().map_err(DbError::from).and_then(|value| match value {
+            Some(value) => {
+                let mut db = self.database.write().unwrap();
+                db.set(key, &value)?;
+                db.get(key)
+            }
+            None => Ok(None),
+        });
+
+        loading.flight.finish(&result);
+        result
+    }
+
+    pub fn get_keys_with_pattern(&self, pattern: &[u8]) -> Result<Vec<Entry>> {
+        let db = self.database.read().unwrap();
         db.get_keys_with_pattern(pattern)
     }
 
-    pub fn delete(&mut self, key: &[u8]) -> io::Result<()> {
-        let mut db = self.database.lock().unwrap();
+    pub fn scan(&self, value_pred: impl Fn(&[u8]) -> bool) -> Result<Vec<Entry>> {
+        let db = self.database.read().unwrap();
+        db.scan(value_pred)
+    }
+
+    /// Copies just the live keys under a brief lock, so callers can enumerate
+    /// them without blocking writers for the whole walk or copying values.
+    pub fn keys_snapshot(&self) -> Result<Vec<Vec<u8>>> {
+        let db = self.database.read().unwrap();
+        db.keys()
+    }
+
+    pub fn len(&self) -> Result<usize> {
+        let db = self.database.read().unwrap();
+        db.len()
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        let db = self.database.read().unwrap();
+        db.is_empty()
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        let mut db = self.database.write().unwrap();
         db.delete(key)
     }
 
-    pub fn get_snapshot(&mut self) -> Vec<u8> {
-        let mut db = self.database.lock().unwrap();
+    pub fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        let mut db = self.database.write().unwrap();
+        db.write(batch)
+    }
+
+    pub fn get_snapshot(&self) -> Result<Vec<u8>> {
+        let db = self.database.read().unwrap();
         db.get_snapshot()
     }
 
-    pub fn set_snapshot(&mut self, raw_data: Vec<u8>) -> io::Result<()> { 
-        let mut db = self.database.lock().unwrap();
+    pub fn set_snapshot(&mut self, raw_data: Vec<u8>) -> Result<()> { 
+        let mut db = self.database.write().unwrap();
         db.set_snapshot(raw_data)
     }
 
-    pub fn purge_database(&mut self) -> io::Result<()> {
-        let mut db = self.database.lock().unwrap();
+    pub fn purge_database(&mut self) -> Result<()> {
+        let mut db = self.database.write().unwrap();
         db.purge_database()
     }
+
+    pub fn flush(&mut self) -> Result<()> {
+        let mut db = self.database.write().unwrap();
+        db.flush()
+    }
+
+    pub fn stats(&self) -> Result<DbStats> {
+        let db = self.database.read().unwrap();
+        db.stats()
+    }
+
+    pub fn compact(&mut self) -> Result<()> {
+        let mut db = self.database.write().unwrap();
+        db.compact()?;
+        self.compaction_wanted.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Whether the expiry sweeper has tombstoned enough keys since the last
+    /// `compact` that compacting would reclaim real space.
+    pub fn compaction_wanted(&self) -> bool {
+        self.compaction_wanted.load(Ordering::Relaxed)
+    }
+
+    /// Starts a thread that tombstones expired keys every `interval`, flushed
+    /// ones included. This frees no memory or disk by itself: the tombstones
+    /// and the expired records both stay until `compact`, which is why
+    /// `compaction_wanted` turns true once enough keys were tombstoned.
+    /// Expired keys are listed under the read lock and tombstoned in one batch
+    /// under the write lock, so writers are only held up briefly. The thread
+    /// runs until the returned handle is dropped; `ExpirySweeper::tick` makes
+    /// it sweep in between.
+    pub fn start_expiry_sweeper(&self, interval: Duration) -> ExpirySweeper {
+        self.start_expiry_sweeper_with(interval, SweeperConfig::default())
+    }
+
+    /// Like `start_expiry_sweeper`, with the thread set up as `config` says.
+    /// Panics like `thread::spawn` if the thread cannot be started, and if
+    /// the name holds a NUL byte.
+    pub fn start_expiry_sweeper_with(&self, interval: Duration, config: SweeperConfig) -> ExpirySweeper {
+        let control = Arc::new((Mutex::new(SweeperControl::default()), Condvar::new()));
+        let engine = self.clone();
+        let signal = control.clone();
+
+        let spawned = thread::Builder::new().name(config.name).spawn(move || {
+            #[cfg(feature = "affinity")]
+            if let Some(cpu) = config.cpu {
+                pin_to_cpu(cpu);
+            }
+
+            let (control, wake) = &*signal;
+            let mut tombstoned = 0;
+            loop {
+                let guard = control.lock().unwrap();
+                let (guard, _) = wake
+                    .wait_timeout_while(guard, interval, |c| !c.stopped && c.done == c.requested)
+                    .unwrap();
+                if guard.stopped {
+                    return;
+                }
+                let requested = guard.requested;
+                drop(guard);
+
+                // a failed sweep is retried on the next round
+                let expired = engine.database.read().unwrap().expired_keys().unwrap_or_default();
+                if !expired.is_empty() {
+                    tombstoned += engine.database.write().unwrap().remove_expired(&expired).unwrap_or(0);
+                }
+                if tombstoned >= SWEEP_COMPACTION_THRESHOLD {
+                    engine.compaction_wanted.store(true, Ordering::Relaxed);
+                    tombstoned = 0;
+                }
+
+                control.lock().unwrap().done = requested;
+                wake.notify_all();
+            }
+        });
+
+        ExpirySweeper {
+            control,
+            thread: Some(spawned.expect("failed to spawn thread")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io,
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc, Arc, Barrier,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use rand::Rng;
+
+    use crate::utils::remove_dir;
+
+    use super::{DBEngine, SweeperConfig};
+
+    #[test]
+    fn test_keys_snapshot() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut engine = DBEngine::new(path.clone()).unwrap();
+
+        engine.set(b"b", b"2").unwrap();
+        engine.set(b"a", b"1").unwrap();
+        engine.set(b"c", b"3").unwrap();
+        engine.delete(b"b").unwrap();
+
+        assert_eq!(vec![b"a".to_vec(), b"c".to_vec()], engine.keys_snapshot().unwrap());
+
+        // clean up
+        remove_dir(&path).unwrap();
+    }
+
+    #[test]
+    fn test_get_shared() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut engine = DBEngine::new(path.clone()).unwrap();
+
+        engine.set(b"blob", &[7; 4096]).unwrap();
+        let first = engine.get_shared(b"blob").unwrap().unwrap();
+        let second = thread::spawn({
+            let engine = engine.clone();
+            move || engine.get_shared(b"blob").unwrap().unwrap()
+        })
+        .join()
+        .unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(vec![7; 4096], *first);
+        assert_eq!(None, engine.get_shared(b"missing").unwrap());
+
+        // clean up
+        remove_dir(&path).unwrap();
+    }
+
+    #[test]
+    fn test_get_or_load_panicking_loader() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let engine = DBEngine::new(path.clone()).unwrap();
+
+        let (started, loading) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let first = {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                engine.get_or_load(b"Hello", || {
+                    started.send(()).unwrap();
+                    released.recv().unwrap();
+                    panic!("loader failed");
+                })
+            })
+        };
+        loading.recv().unwrap();
+
+        let second = {
+            let engine = engine.clone();
+            thread::spawn(move || engine.get_or_load(b"Hello", || Ok(Some(b"second".to_vec()))))
+        };
+        // let the second caller start waiting on the first load
+        thread::sleep(Duration::from_millis(50));
+        release.send(()).unwrap();
+
+        assert!(first.join().is_err());
+        let err = second.join().unwrap().unwrap_err();
+        assert_eq!(Some(io::ErrorKind::Other), err.io_kind());
+        assert!(engine.in_flight.lock().unwrap().is_empty());
+
+        // the key can be loaded again
+        let entry = engine.get_or_load(b"Hello", || Ok(Some(b"World!".to_vec()))).unwrap().unwrap();
+        assert_eq!(b"World!".to_vec(), *entry.value.unwrap());
+
+        // clean up
+        remove_dir(&path).unwrap();
+    }
+
+    #[test]
+    fn test_get_or_load_single_flight() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let engine = DBEngine::new(path.clone()).unwrap();
+
+        let loads = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(16));
+        let handles: Vec<_> = (0..16)
+            .map(|_| {
+                let engine = engine.clone();
+                let loads = loads.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    engine.get_or_load(b"Hello", || {
+                        loads.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(50));
+                        Ok(Some(b"World!".to_vec()))
+                    })
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            let entry = handle.join().unwrap().unwrap().unwrap();
+            assert_eq!(b"World!".to_vec(), *entry.value.unwrap());
+        }
+        assert_eq!(1, loads.load(Ordering::SeqCst));
+
+        // clean up
+        remove_dir(&path).unwrap();
+    }
+
+    #[test]
+    fn test_expiry_sweeper() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut engine = DBEngine::new(path.clone()).unwrap();
+
+        {
+            let mut db = engine.database.write().unwrap();
+            for idx in 0..10u32 {
+                // the first half is flushed into sealed segments
+                db.set_max_memtable_bytes((idx < 5).then_some(0));
+                db.set_with_ttl(format!("session{}", idx).as_bytes(), b"value", Duration::ZERO)
+                    .unwrap();
+            }
+        }
+        engine.set(b"Hello", b"World!").unwrap();
+        assert_eq!(10, engine.database.read().unwrap().expired_keys().unwrap().len());
+
+        // the interval never passes, only the tick sweeps
+        let sweeper = engine.start_expiry_sweeper(Duration::from_secs(3600));
+        sweeper.tick();
+        assert_eq!(Some("expiry-sweeper"), sweeper.thread().name());
+
+        assert_eq!(vec![b"Hello".to_vec()], engine.keys_snapshot().unwrap());
+        assert_eq!(10, engine.database.read().unwrap().count_tombstones().unwrap());
+        assert!(engine.database.read().unwrap().expired_keys().unwrap().is_empty());
+        assert!(!engine.compaction_wanted());
+
+        // dropping the handle stops the thread
+        drop(sweeper);
+
+        // clean up
+        remove_dir(&path).unwrap();
+    }
+
+    #[test]
+    fn test_expiry_sweeper_config() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut engine = DBEngine::new(path.clone()).unwrap();
+        engine.set(b"Hello", b"World!").unwrap();
+
+        let config = SweeperConfig::new().with_thread_name("sweeper-test");
+        let sweeper = engine.start_expiry_sweeper_with(Duration::from_secs(3600), config);
+        sweeper.tick();
+        assert_eq!(Some("sweeper-test"), sweeper.thread().name());
+        assert_eq!(vec![b"Hello".to_vec()], engine.keys_snapshot().unwrap());
+        drop(sweeper);
+
+        // clean up
+        remove_dir(&path).unwrap();
+    }
+
+    #[cfg(all(feature = "affinity", target_os = "linux"))]
+    #[test]
+    fn test_expiry_sweeper_affinity() {
+        use std::os::unix::thread::JoinHandleExt;
+
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let engine = DBEngine::new(path.clone()).unwrap();
+
+        let size = std::mem::size_of::<libc::cpu_set_t>();
+        // SAFETY: the sets are plain data, passed with their size.
+        let mut allowed: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        assert_eq!(0, unsafe { libc::sched_getaffinity(0, size, &mut allowed) });
+        let cpu = (0..libc::CPU_SETSIZE as usize)
+            .find(|&cpu| unsafe { libc::CPU_ISSET(cpu, &allowed) })
+            .unwrap();
+
+        let config = SweeperConfig::new().with_cpu_affinity(cpu);
+        let sweeper = engine.start_expiry_sweeper_with(Duration::from_secs(3600), config);
+        // the thread pins itself before its first sweep
+        sweeper.tick();
+        let thread = sweeper.thread.as_ref().unwrap().as_pthread_t();
+        let mut pinned: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        assert_eq!(0, unsafe { libc::pthread_getaffinity_np(thread, size, &mut pinned) });
+        assert_eq!(1, unsafe { libc::CPU_COUNT(&pinned) });
+        assert!(unsafe { libc::CPU_ISSET(cpu, &pinned) });
+        drop(sweeper);
+
+        // clean up
+        remove_dir(&path).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_compare_and_swap() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let engine = DBEngine::new(path.clone()).unwrap();
+        let barrier = Arc::new(Barrier::new(8));
+
+        let claims: Vec<_> = (0..8u8)
+            .map(|idx| {
+                let mut engine = engine.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    engine.compare_and_swap(b"leader", None, &[idx]).unwrap()
+                })
+            })
+            .collect();
+
+        let won: Vec<bool> = claims.into_iter().map(|claim| claim.join().unwrap()).collect();
+        assert_eq!(1, won.iter().filter(|&&won| won).count());
+        let winner = won.iter().position(|&won| won).unwrap() as u8;
+        assert_eq!(vec![winner], *engine.get(b"leader").unwrap().unwrap().value.unwrap());
+
+        // clean up
+        remove_dir(&path).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_readers() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut engine = DBEngine::new(path.clone()).unwrap();
+        engine.set(b"Hello", b"World!").unwrap();
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let engine = engine.clone();
+                thread::spawn(move || {
+                    for _ in 0..200 {
+                        assert!(engine.get(b"Hello").unwrap().is_some());
+                        engine.get_keys_with_pattern(b"key").unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        let mut writer = engine.clone();
+        let writer = thread::spawn(move || {
+            for idx in 0..200u32 {
+                writer.set(format!("key{}", idx).as_bytes(), b"value").unwrap();
+            }
+        });
+
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(200, engine.get_keys_with_pattern(b"key").unwrap().len());
+
+        // clean up
+        remove_dir(&path).unwrap();
+    }
 }
@@ -1,26 +1,47 @@
+use std::sync::Arc;
+
 use bincode::{Decode, Encode};
 use bytes::BytesMut;
 
-#[derive(Debug, Encode, Decode, PartialEq, PartialOrd, Eq, Clone)]
+use crate::error::Result;
+
+#[derive(Debug, Encode, Decode, PartialEq, Eq, Clone)]
 pub struct Entry {
     pub key: Vec<u8>,
-    pub value: Option<Vec<u8>>,
+    /// Shared, so cloning an entry out of the MemTable doesn't copy the bytes.
+    pub value: Option<Arc<Vec<u8>>>,
     pub timestamp: u128,
     pub deleted: bool,
+    /// When the entry stops being visible, in microseconds since the epoch.
+    pub expires_at: Option<u128>,
 }
 
 impl Entry {
-    pub fn serialize(&self) -> BytesMut {
+    /// Whether the entry has an expiry at or before `now`.
+    pub fn is_expired(&self, now: u128) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+
+    /// Whether the entry is neither deleted nor expired at `now`.
+    pub fn is_live(&self, now: u128) -> bool {
+        !self.deleted && !self.is_expired(now)
+    }
+
+    pub fn serialize(&self) -> Result<BytesMut> {
         let mut s = BytesMut::new();
-        s.extend_from_slice(&bincode::encode_to_vec(self, bincode::config::standard()).unwrap());
-        s
+        s.extend_from_slice(&bincode::encode_to_vec(self, bincode::config::standard())?);
+        Ok(s)
+    }
+
+    pub fn deserialize(data: BytesMut) -> Result<Self> {
+        let d: Self = bincode::decode_from_slice(&data, bincode::config::standard())?.0;
+        Ok(d)
     }
+}
 
-    pub fn deserialize(data: BytesMut) -> Self {
-        let d: Self = bincode::decode_from_slice(&data, bincode::config::standard())
-            .unwrap()
-            .0;
-        d
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
     }
 }
 
@@ -0,0 +1,98 @@
+use std::{error::Error, fmt, io};
+
+/// `Result` with `DbError` as the default error type.
+pub type Result<T, E = DbError> = std::result::Result<T, E>;
+
+/// Errors returned by the public `Db`, `DBEngine` and `TieredDb` APIs.
+#[derive(Debug)]
+pub enum DbError {
+    /// The underlying file system failed.
+    Io(io::Error),
+    /// Stored or transferred data is damaged, e.g. truncated or failing its
+    /// checksum.
+    Corrupt(String),
+    /// An entry could not be encoded or decoded.
+    Serialization(Box<dyn Error + Send + Sync>),
+    /// A file or byte stream is not in a format this version understands.
+    InvalidFormat(String),
+    /// An encrypted record could not be decrypted: the encryption key is
+    /// missing or wrong.
+    Encryption(String),
+    /// A write would grow the live data past `Db::set_max_total_bytes`. Holds
+    /// the quota and the size the write asked for, both in bytes.
+    QuotaExceeded { quota: usize, requested: usize },
+    /// A key was rejected by the validator installed with
+    /// `Db::set_key_validator`.
+    InvalidKey,
+    /// A value was rejected by the validator installed with
+    /// `Db::set_value_validator`, for the reason it gave.
+    InvalidValue { reason: String },
+    /// A write reached a database opened with `Db::open_read_only` or
+    /// `Db::open_segment`.
+    ReadOnly,
+}
+
+impl DbError {
+    /// The I/O error kind, for `Io` errors.
+    pub fn io_kind(&self) -> Option<io::ErrorKind> {
+        match self {
+            DbError::Io(e) => Some(e.kind()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Io(e) => e.fmt(f),
+            DbError::Corrupt(msg) => write!(f, "corrupt data: {}", msg),
+            DbError::Serialization(e) => write!(f, "serialization error: {}", e),
+            DbError::InvalidFormat(msg) => write!(f, "invalid format: {}", msg),
+            DbError::Encryption(msg) => write!(f, "encryption error: {}", msg),
+            DbError::QuotaExceeded { quota, requested } => {
+                write!(f, "write needs {} bytes, over the quota of {}", requested, quota)
+            }
+            DbError::InvalidKey => write!(f, "key rejected by the key validator"),
+            DbError::InvalidValue { reason } => write!(f, "invalid value: {}", reason),
+            DbError::ReadOnly => write!(f, "database is opened read-only"),
+        }
+    }
+}
+
+impl Error for DbError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DbError::Io(e) => Some(e),
+            DbError::Serialization(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DbError {
+    fn from(e: io::Error) -> Self {
+        DbError::Io(e)
+    }
+}
+
+impl From<bincode::error::EncodeError> for DbError {
+    fn from(e: bincode::error::EncodeError) -> Self {
+        DbError::Serialization(Box::new(e))
+    }
+}
+
+impl From<bincode::error::DecodeError> for DbError {
+    fn from(e: bincode::error::DecodeError) -> Self {
+        DbError::Serialization(Box::new(e))
+    }
+}
+
+impl From<serde_json::Error> for DbError {
+    fn from(e: serde_json::Error) -> Self {
+        match e.classify() {
+            serde_json::error::Category::Io => DbError::Io(e.into()),
+            _ => DbError::InvalidFormat(e.to_string()),
+        }
+    }
+}
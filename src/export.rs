@@ -0,0 +1,213 @@
+use std::io::{Read, Write};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::Db,
+    error::{DbError, Result},
+};
+
+/// What `Db::import_csv` did with the rows it read.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CsvImport {
+    pub imported: usize,
+    /// Rows without a key or a value column, which were left out.
+    pub skipped: usize,
+}
+
+/// One entry of a JSON dump. Keys and values are arbitrary bytes, so both
+/// are base64 encoded.
+#[derive(Serialize, Deserialize)]
+struct JsonEntry {
+    key: String,
+    value: String,
+    /// When the entry expires, in microseconds since the epoch; left out for
+    /// entries without a TTL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u128>,
+}
+
+impl Db {
+    /// Writes the live entries to `w` as a JSON array of `{"key", "value"}`
+    /// objects in key order, with keys and values base64 encoded. Entries
+    /// with a TTL also carry their `expires_at`; expired ones are left out.
+    pub fn export_json(&self, w: impl Write) -> Result<()> {
+        let entries: Vec<JsonEntry> = self
+            .iter()?
+            .map(|entry| JsonEntry {
+                key: STANDARD.encode(&entry.key),
+                value: STANDARD.encode(entry.value.as_deref().map(Vec::as_slice).unwrap_or_default()),
+                expires_at: entry.expires_at,
+            })
+            .collect();
+        serde_json::to_writer(w, &entries)?;
+        Ok(())
+    }
+
+    /// `stats` as a JSON object with one member per `DbStats` field, e.g. for a
+    /// metrics endpoint.
+    pub fn stats_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(&self.stats()?)?)
+    }
+
+    /// Sets every entry of a dump written by `export_json`, with a single
+    /// commit, restoring the expiry of entries that had one. A malformed dump
+    /// fails with `DbError::InvalidFormat` before anything is written.
+    pub fn import_json(&mut self, r: impl Read) -> Result<()> {
+        let entries: Vec<JsonEntry> = serde_json::from_reader(r)?;
+
+        let decode = |field: &str, idx: usize| {
+            STANDARD
+                .decode(field)
+                .map_err(|e| DbError::InvalidFormat(format!("entry {}: {}", idx, e)))
+        };
+        let mut decoded = Vec::with_capacity(entries.len());
+        for (idx, entry) in entries.iter().enumerate() {
+            decoded.push((decode(&entry.key, idx)?, decode(&entry.value, idx)?, entry.expires_at));
+        }
+
+        let batch: Vec<(&[u8], &[u8], Option<u128>)> =
+            decoded.iter().map(|(k, v, at)| (&k[..], &v[..], *at)).collect();
+        self.set_batch_expiring(&batch)
+    }
+
+    /// Bulk loads CSV rows from `r`, taking each key and value from the
+    /// zero-based columns `key_col` and `value_col` as raw bytes, with a
+    /// single commit. There is no header row. Rows too short to hold both
+    /// columns are skipped; a malformed file fails with
+    /// `DbError::InvalidFormat` before anything is written.
+    pub fn import_csv(&mut self, r: impl Read, key_col: usize, value_col: usize) -> Result<CsvImport> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(r);
+
+        let mut pairs = Vec::new();
+        let mut skipped = 0;
+        for row in reader.byte_records() {
+            let row = row.map_err(|e| match e.into_kind() {
+                csv::ErrorKind::Io(e) => DbError::Io(e),
+                kind => DbError::InvalidFormat(format!("{:?}", kind)),
+            })?;
+            match (row.get(key_col), row.get(value_col)) {
+                (Some(key), Some(value)) => pairs.push((key.to_vec(), value.to_vec())),
+                _ => skipped += 1,
+            }
+        }
+
+        let batch: Vec<(&[u8], &[u8])> = pairs.iter().map(|(k, v)| (&k[..], &v[..])).collect();
+        self.set_batch(&batch)?;
+        Ok(CsvImport {
+            imported: pairs.len(),
+            skipped,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{path::PathBuf, time::Duration};
+
+    use rand::Rng;
+
+    use crate::{db::Db, error::DbError, utils::remove_dir};
+
+    use super::CsvImport;
+
+    #[test]
+    fn json_round_trip() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        // the first entries are flushed into sealed segments
+        db.set_max_memtable_bytes(Some(0));
+        db.set(b"Hello", b"World!").unwrap();
+        db.set(&[0, 159, 255], &[1, 2, 3]).unwrap();
+        db.set(b"Name", b"Vahid").unwrap();
+        db.set_max_memtable_bytes(None);
+        db.delete(b"Name").unwrap();
+        db.set_with_ttl(b"session", b"expired", Duration::ZERO).unwrap();
+        db.set_with_ttl(b"cache", b"v", Duration::from_secs(3600)).unwrap();
+        let expires_at = db.get(b"cache").unwrap().unwrap().expires_at.unwrap();
+
+        let mut dump = Vec::new();
+        db.export_json(&mut dump).unwrap();
+        assert_eq!(
+            format!(
+                r#"[{{"key":"AJ//","value":"AQID"}},{{"key":"SGVsbG8=","value":"V29ybGQh"}},{{"key":"Y2FjaGU=","value":"dg==","expires_at":{}}}]"#,
+                expires_at
+            ),
+            String::from_utf8(dump.clone()).unwrap()
+        );
+
+        db.purge_database().unwrap();
+        assert!(db.is_empty().unwrap());
+        db.import_json(&dump[..]).unwrap();
+        assert_eq!(vec![vec![0, 159, 255], b"Hello".to_vec(), b"cache".to_vec()], db.keys().unwrap());
+        assert_eq!(vec![1, 2, 3], *db.get(&[0, 159, 255]).unwrap().unwrap().value.unwrap());
+        assert_eq!(b"World!".to_vec(), *db.get(b"Hello").unwrap().unwrap().value.unwrap());
+        assert_eq!(None, db.get(b"Hello").unwrap().unwrap().expires_at);
+        assert_eq!(Some(expires_at), db.get(b"cache").unwrap().unwrap().expires_at);
+        assert_eq!(None, db.get(b"Name").unwrap());
+        assert_eq!(None, db.get_raw(b"session").unwrap());
+
+        // nothing from a bad dump is written
+        let err = db.import_json(&br#"[{"key":"TmV3","value":"dg=="},{"key":"!","value":""}]"#[..]);
+        assert!(matches!(err, Err(DbError::InvalidFormat(_))));
+        assert_eq!(None, db.get(b"New").unwrap());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn stats_json() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        db.set(b"Hello", b"World!").unwrap();
+        db.set(b"Name", b"Vahid").unwrap();
+        db.delete(b"Name").unwrap();
+        db.flush().unwrap();
+
+        let stats = db.stats().unwrap();
+        let json: serde_json::Value = serde_json::from_str(&db.stats_json().unwrap()).unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "live_keys": 1,
+                "tombstones": 1,
+                "memtable_bytes": stats.memtable_bytes,
+                "files": 2,
+                "disk_bytes": stats.disk_bytes,
+                "generation": 3,
+            }),
+            json
+        );
+        assert!(stats.disk_bytes > 0);
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+
+    #[test]
+    fn csv_import() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let mut db = Db::init_from_existing(path).unwrap();
+
+        let csv = "1,Hello,World!\n2,Name,\"Vahid, again\"\n3,orphan\n4,gg,wp,extra\n";
+        let report = db.import_csv(csv.as_bytes(), 1, 2).unwrap();
+        assert_eq!(CsvImport { imported: 3, skipped: 1 }, report);
+
+        assert_eq!(b"World!".to_vec(), *db.get(b"Hello").unwrap().unwrap().value.unwrap());
+        assert_eq!(b"Vahid, again".to_vec(), *db.get(b"Name").unwrap().unwrap().value.unwrap());
+        assert_eq!(b"wp".to_vec(), *db.get(b"gg").unwrap().unwrap().value.unwrap());
+        assert_eq!(None, db.get(b"orphan").unwrap());
+
+        // clean up
+        remove_dir(&db.dir).unwrap();
+    }
+}
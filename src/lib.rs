@@ -1,6 +1,18 @@
+#[cfg(feature = "async")]
+pub mod async_engine;
+pub mod batch;
+pub mod config;
 pub mod engine;
 pub mod db;
 pub mod entry;
+pub mod error;
+pub mod export;
+pub mod segment_store;
+pub mod stats;
+pub mod tiered;
+mod bloom;
+mod crypto;
+mod lru;
 mod memtable;
 mod storage;
 mod storage_iterator;
@@ -0,0 +1,87 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// A map from keys to values holding at most `capacity` entries. Looking an
+/// entry up or inserting it makes it the most recently used one; inserting
+/// into a full cache evicts the least recently used. A capacity of 0 holds
+/// nothing.
+#[derive(Debug)]
+pub(crate) struct LruCache<V> {
+    capacity: usize,
+    entries: HashMap<Vec<u8>, (V, u64)>,
+    /// The keys by the tick they were last used at, least recent first.
+    order: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+}
+
+impl<V: Clone> LruCache<V> {
+    pub(crate) fn new(capacity: usize) -> LruCache<V> {
+        LruCache {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub(crate) fn get(&mut self, key: &[u8]) -> Option<V> {
+        let (value, used) = self.entries.get_mut(key)?;
+        let key = self.order.remove(used).expect("every entry is ordered");
+        self.tick += 1;
+        *used = self.tick;
+        self.order.insert(self.tick, key);
+        Some(value.clone())
+    }
+
+    pub(crate) fn insert(&mut self, key: Vec<u8>, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&key);
+        if self.entries.len() == self.capacity {
+            let (_, oldest) = self.order.pop_first().expect("a full cache is not empty");
+            self.entries.remove(&oldest);
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick));
+    }
+
+    pub(crate) fn remove(&mut self, key: &[u8]) {
+        if let Some((_, used)) = self.entries.remove(key) {
+            self.order.remove(&used);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LruCache;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = LruCache::new(2);
+        cache.insert(b"a".to_vec(), 1);
+        cache.insert(b"b".to_vec(), 2);
+        assert_eq!(Some(1), cache.get(b"a"));
+
+        // "b" was used last before "a", so it goes first
+        cache.insert(b"c".to_vec(), 3);
+        assert_eq!(None, cache.get(b"b"));
+        assert_eq!(Some(1), cache.get(b"a"));
+        assert_eq!(Some(3), cache.get(b"c"));
+
+        cache.insert(b"a".to_vec(), 4);
+        cache.remove(b"c");
+        assert_eq!(Some(4), cache.get(b"a"));
+        assert_eq!(None, cache.get(b"c"));
+
+        let mut disabled = LruCache::new(0);
+        disabled.insert(b"a".to_vec(), 1);
+        assert_eq!(None, disabled.get(b"a"));
+    }
+}
@@ -1,29 +1,38 @@
+use std::{
+    collections::{btree_map, BTreeMap},
+    ops::Bound,
+    sync::Arc,
+};
+
 use crate::entry::Entry;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MemTable {
-    entities: Vec<Entry>,
+    entities: BTreeMap<Vec<u8>, Entry>,
     size: usize,
 }
 
 impl MemTable {
     pub fn new() -> MemTable {
         MemTable {
-            entities: Vec::new(),
+            entities: BTreeMap::new(),
             size: 0,
         }
     }
 
+    /// Builds a MemTable from entries in file order; a later entry for a key
+    /// replaces an earlier one.
     #[allow(dead_code)]
     pub fn init_from_file(entities: Vec<Entry>) -> MemTable {
         let size = 0;
+        let entities = entities.into_iter().map(|e| (e.key.clone(), e)).collect();
         let mut mem_table = MemTable { entities, size };
         mem_table.restore_size();
         mem_table
     }
 
     fn restore_size(&mut self) {
-        for entry in &self.entities {
+        for entry in self.entities.values() {
             match entry.value.as_ref() {
                 Some(val) => {
                     self.size += entry.key.len() + val.len() + 16 + 1;
@@ -35,70 +44,174 @@ impl MemTable {
         }
     }
 
+    /// The position of `key` in key order, or where it would be inserted.
+    /// This counts the smaller keys, so it is O(n); use `get` or `range` to
+    /// look entries up.
+    #[allow(dead_code)]
     pub fn get_index(&self, key: &[u8]) -> Result<usize, usize> {
-        self.entities
-            .binary_search_by_key(&key, |entry| entry.key.as_slice())
+        let idx = self
+            .entities
+            .range::<[u8], _>((Bound::Unbounded, Bound::Excluded(key)))
+            .count();
+        if self.entities.contains_key(key) {
+            Ok(idx)
+        } else {
+            Err(idx)
+        }
     }
 
     pub fn set_or_insert(&mut self, key: &[u8], value: &[u8], timestamp: u128) {
-        let entry = Entry {
+        self.insert(Entry {
             key: key.to_owned(),
-            value: Some(value.to_owned()),
+            value: Some(Arc::new(value.to_owned())),
             timestamp,
             deleted: false,
-        };
-
-        match self.get_index(key) {
-            // Update the value if the key exists already
-            Ok(idx) => {
-                if let Some(old_value) = self.entities[idx].value.as_ref() {
-                    // Update the size of the MemTable
-                    self.size += value.len();
-                    self.size -= old_value.len();
-                } else {
-                    self.size += value.len();
-                }
-                self.entities[idx] = entry;
-            }
-            Err(idx) => {
-                // key size + value size + 16 + 1 -> 16 is the size of u128
-                self.size += key.len() + value.len() + 16 + 1;
-                self.entities.insert(idx, entry);
-            }
-        }
+            expires_at: None,
+        });
     }
 
     pub fn delete(&mut self, key: &[u8], timestamp: u128) {
-        let entry = Entry {
+        self.insert(Entry {
             key: key.to_owned(),
             value: None,
             timestamp,
             deleted: true,
-        };
+            expires_at: None,
+        });
+    }
 
-        match self.get_index(&key) {
-            Ok(idx) => {
-                if let Some(old_value) = self.entities[idx].value.as_ref() {
-                    self.size -= old_value.len();
-                }
-                self.entities[idx] = entry;
+    /// Stores `entry` as is, replacing whatever its key held before.
+    pub fn insert(&mut self, entry: Entry) {
+        let value_len = entry.value.as_ref().map_or(0, |v| v.len());
+        match self.entities.get_mut(&entry.key) {
+            // Update the value if the key exists already
+            Some(old) => {
+                // Update the size of the MemTable
+                self.size += value_len;
+                self.size -= old.value.as_ref().map_or(0, |v| v.len());
+                *old = entry;
             }
-            Err(idx) => {
-                self.size += key.len() + 16 + 1;
-                self.entities.insert(idx, entry);
+            None => {
+                // key size + value size + 16 + 1 -> 16 is the size of u128
+                self.size += entry.key.len() + value_len + 16 + 1;
+                self.entities.insert(entry.key.clone(), entry);
             }
         }
     }
 
-    pub fn get(&self, key: &[u8]) -> Option<&Entry> {
-        if let Ok(idx) = self.get_index(key) {
-            return Some(&self.entities[idx]);
+    fn is_newer(&self, key: &[u8], timestamp: u128) -> bool {
+        self.get(key).is_none_or(|entry| timestamp >= entry.timestamp)
+    }
+
+    /// Last-writer-wins `set_or_insert`: ignored when the stored entry has a
+    /// newer timestamp. On a tie the incoming write wins, so replaying records
+    /// in file order still breaks ties in favour of the later record.
+    #[allow(dead_code)]
+    pub fn set_if_newer(&mut self, key: &[u8], value: &[u8], timestamp: u128) -> bool {
+        if !self.is_newer(key, timestamp) {
+            return false;
+        }
+        self.set_or_insert(key, value, timestamp);
+        true
+    }
+
+    /// Last-writer-wins `insert`, see `set_if_newer`.
+    pub fn insert_if_newer(&mut self, entry: Entry) -> bool {
+        if !self.is_newer(&entry.key, entry.timestamp) {
+            return false;
         }
-        None
+        self.insert(entry);
+        true
     }
 
-    pub fn get_all(&self) -> &Vec<Entry> {
-        &self.entities
+    /// Last-writer-wins `delete`, see `set_if_newer`.
+    #[allow(dead_code)]
+    pub fn delete_if_newer(&mut self, key: &[u8], timestamp: u128) -> bool {
+        if !self.is_newer(key, timestamp) {
+            return false;
+        }
+        self.delete(key, timestamp);
+        true
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&Entry> {
+        self.entities.get(key)
+    }
+
+    /// Whether `key` is held and neither deleted nor expired at `now`.
+    pub fn contains_key(&self, key: &[u8], now: u128) -> bool {
+        self.entities
+            .get(key)
+            .is_some_and(|entry| entry.is_live(now))
+    }
+
+    /// Number of entries that are neither deleted nor expired at `now`.
+    pub fn len(&self, now: u128) -> usize {
+        self.entities.values().filter(|entry| entry.is_live(now)).count()
+    }
+
+    /// Approximate bytes held by the entries, tombstones included.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Every entry, tombstones included, in key order.
+    pub fn get_all(&self) -> btree_map::Values<'_, Vec<u8>, Entry> {
+        self.entities.values()
+    }
+
+    /// Every entry, tombstones included, in key order, moved out of the table.
+    pub fn into_entries(self) -> btree_map::IntoValues<Vec<u8>, Entry> {
+        self.entities.into_values()
+    }
+
+    /// The entries with keys between `start` and `end`, in key order. Bounds
+    /// that cross yield nothing.
+    pub fn range<'a>(
+        &'a self,
+        start: Bound<&'a [u8]>,
+        end: Bound<&'a [u8]>,
+    ) -> impl DoubleEndedIterator<Item = &'a Entry> {
+        let empty = match (start, end) {
+            (Bound::Excluded(s), Bound::Excluded(e)) => s >= e,
+            (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) => s > e,
+            _ => false,
+        };
+        (!empty)
+            .then(|| self.entities.range::<[u8], _>((start, end)))
+            .into_iter()
+            .flatten()
+            .map(|(_, entry)| entry)
+    }
+
+    /// Drops tombstones. Only the in-memory view changes: deletes are already
+    /// durable in storage, so replaying the segments still sees them.
+    pub fn shrink_to_fit(&mut self) {
+        let mut removed = 0;
+        self.entities.retain(|_, entry| {
+            if entry.deleted {
+                removed += entry.key.len() + 16 + 1;
+            }
+            !entry.deleted
+        });
+        self.size -= removed;
+    }
+
+    /// Drops entries that expired at or before `now` and returns how many
+    /// were dropped.
+    pub fn remove_expired(&mut self, now: u128) -> usize {
+        let mut removed = 0;
+        let mut count = 0;
+        self.entities.retain(|_, entry| {
+            let expired = entry.is_expired(now);
+            if expired {
+                removed += entry.key.len() + entry.value.as_ref().map_or(0, |v| v.len()) + 16 + 1;
+                count += 1;
+            }
+            !expired
+        });
+        self.size -= removed;
+        count
     }
 
     pub fn purge_mem_table(&mut self) {
@@ -132,7 +245,7 @@ mod test {
         assert_eq!(mem_table.get_index(&key).unwrap(), 0);
         assert_eq!(
             mem_table.get(&key).unwrap().to_owned().value,
-            Some(value.to_vec())
+            Some(Arc::new(value.to_vec()))
         );
     }
 
@@ -160,7 +273,7 @@ mod test {
         timestamp = SystemTime::now().elapsed().unwrap().as_micros();
         mem_table.delete(&key, timestamp);
 
-        assert_eq!(mem_table.size, (5 + 0 + 16 + 1));
+        assert_eq!(mem_table.size, (5 + 16 + 1));
     }
 
     #[test]
@@ -175,7 +288,49 @@ mod test {
         timestamp = SystemTime::now().elapsed().unwrap().as_micros();
         mem_table.delete(&key, timestamp);
 
-        assert_eq!(mem_table.get(&key).unwrap().deleted, true);
+        assert!(mem_table.get(&key).unwrap().deleted);
+    }
+
+    #[test]
+    fn check_len() {
+        let mut mem_table = MemTable::new();
+        assert_eq!(0, mem_table.len(0));
+
+        mem_table.set_or_insert(b"Hello", b"World!", 1);
+        mem_table.set_or_insert(b"Name", b"Vahid", 1);
+        mem_table.delete(b"Name", 2);
+        mem_table.insert(Entry {
+            key: b"session".to_vec(),
+            value: Some(Arc::new(b"value".to_vec())),
+            timestamp: 1,
+            deleted: false,
+            expires_at: Some(10),
+        });
+
+        assert_eq!(2, mem_table.len(5));
+        assert_eq!(1, mem_table.len(10));
+    }
+
+    #[test]
+    fn check_contains_key() {
+        let mut mem_table = MemTable::new();
+
+        mem_table.set_or_insert(b"Hello", b"World!", 1);
+        mem_table.set_or_insert(b"Name", b"Vahid", 1);
+        mem_table.delete(b"Name", 2);
+        mem_table.insert(Entry {
+            key: b"gg".to_vec(),
+            value: Some(Arc::new(b"wp".to_vec())),
+            timestamp: 1,
+            deleted: false,
+            expires_at: Some(10),
+        });
+
+        assert!(mem_table.contains_key(b"Hello", 10));
+        assert!(!mem_table.contains_key(b"Name", 10));
+        assert!(!mem_table.contains_key(b"missing", 10));
+        assert!(mem_table.contains_key(b"gg", 9));
+        assert!(!mem_table.contains_key(b"gg", 10));
     }
 
     #[test]
@@ -192,7 +347,7 @@ mod test {
         timestamp = SystemTime::now().elapsed().unwrap().as_micros();
         mem_table.set_or_insert(&key2, &value2, timestamp);
 
-        assert_eq!(mem_table.get_index(&key2).unwrap(), 1 as usize);
+        assert_eq!(mem_table.get_index(&key2).unwrap(), 1_usize);
 
         timestamp = SystemTime::now().elapsed().unwrap().as_micros();
         mem_table.delete(&key2, timestamp);
@@ -248,7 +403,9 @@ mod test {
 
         let mem_table = MemTable::init_from_file(data);
 
-        assert_eq!(96, mem_table.size);
+        // the later tombstone for Name replaces its value
+        assert_eq!(3, mem_table.entities.len());
+        assert_eq!(70, mem_table.size);
 
         // Clean up
         remove_dir(&path).unwrap();
@@ -268,7 +425,7 @@ mod test {
         timestamp = SystemTime::now().elapsed().unwrap().as_micros();
         mem_table.set_or_insert(&key2, &value2, timestamp);
 
-        assert_eq!(mem_table.get_index(&key2).unwrap(), 1 as usize);
+        assert_eq!(mem_table.get_index(&key2).unwrap(), 1_usize);
 
         timestamp = SystemTime::now().elapsed().unwrap().as_micros();
         mem_table.delete(&key2, timestamp);
@@ -287,6 +444,105 @@ mod test {
         timestamp = SystemTime::now().elapsed().unwrap().as_micros();
         mem_table.set_or_insert(&key2, &value2, timestamp);
 
-        assert_eq!(mem_table.get_index(&key2).unwrap(), 1 as usize);
+        assert_eq!(mem_table.get_index(&key2).unwrap(), 1_usize);
+    }
+
+    #[test]
+    fn test_shrink_to_fit() {
+        let mut mem_table = MemTable::new();
+
+        let timestamp = SystemTime::now().elapsed().unwrap().as_micros();
+        for idx in 0..1000u32 {
+            mem_table.set_or_insert(&idx.to_be_bytes(), b"value", timestamp);
+        }
+        for idx in 10..1000u32 {
+            mem_table.delete(&idx.to_be_bytes(), timestamp);
+        }
+
+        mem_table.shrink_to_fit();
+
+        assert_eq!(10, mem_table.entities.len());
+        assert_eq!(10 * (4 + 5 + 16 + 1), mem_table.size);
+        for idx in 0..10u32 {
+            assert_eq!(
+                Some(Arc::new(b"value".to_vec())),
+                mem_table.get(&idx.to_be_bytes()).unwrap().value
+            );
+        }
+        assert_eq!(None, mem_table.get(&10u32.to_be_bytes()));
+    }
+
+    #[test]
+    fn check_set_if_newer() {
+        let mut mem_table = MemTable::new();
+
+        assert!(mem_table.set_if_newer(b"Hello", b"new", 200));
+        assert!(!mem_table.set_if_newer(b"Hello", b"old", 100));
+        assert!(!mem_table.delete_if_newer(b"Hello", 150));
+        assert_eq!(Some(Arc::new(b"new".to_vec())), mem_table.get(b"Hello").unwrap().value);
+
+        assert!(mem_table.delete_if_newer(b"Hello", 200));
+        assert!(mem_table.get(b"Hello").unwrap().deleted);
+    }
+
+    #[test]
+    fn check_many_random_inserts() {
+        let mut range = rand::thread_rng();
+        let mut mem_table = MemTable::new();
+
+        let mut size = 0;
+        for _ in 0..10_000 {
+            let key = range.gen::<u64>().to_be_bytes();
+            if mem_table.get(&key).is_none() {
+                size += 8 + 5 + 16 + 1;
+            }
+            mem_table.set_or_insert(&key, b"value", 1);
+        }
+
+        let keys: Vec<&Vec<u8>> = mem_table.get_all().map(|e| &e.key).collect();
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(size, mem_table.size());
+    }
+
+    #[test]
+    fn check_remove_expired() {
+        let mut mem_table = MemTable::new();
+        mem_table.set_or_insert(b"keep", b"value", 1);
+        mem_table.insert(Entry {
+            key: b"gone".to_vec(),
+            value: Some(Arc::new(b"value".to_vec())),
+            timestamp: 1,
+            deleted: false,
+            expires_at: Some(100),
+        });
+        mem_table.insert(Entry {
+            key: b"later".to_vec(),
+            value: Some(Arc::new(b"value".to_vec())),
+            timestamp: 1,
+            deleted: false,
+            expires_at: Some(300),
+        });
+
+        assert_eq!(1, mem_table.remove_expired(200));
+        assert!(mem_table.get(b"gone").is_none());
+        assert_eq!(Some(300), mem_table.get(b"later").unwrap().expires_at);
+        assert_eq!((4 + 5 + 16 + 1) + (5 + 5 + 16 + 1), mem_table.size());
+    }
+
+    #[test]
+    fn check_range() {
+        let mut mem_table = MemTable::new();
+        for key in [b"a", b"b", b"c", b"d"] {
+            mem_table.set_or_insert(key, b"value", 1);
+        }
+
+        let keys = |start, end| -> Vec<Vec<u8>> { mem_table.range(start, end).map(|e| e.key.clone()).collect() };
+        assert_eq!(
+            vec![b"b".to_vec(), b"c".to_vec()],
+            keys(Bound::Included(&b"b"[..]), Bound::Excluded(&b"d"[..]))
+        );
+        assert_eq!(4, keys(Bound::Unbounded, Bound::Unbounded).len());
+        assert!(keys(Bound::Included(&b"c"[..]), Bound::Excluded(&b"b"[..])).is_empty());
+        assert!(keys(Bound::Excluded(&b"b"[..]), Bound::Excluded(&b"b"[..])).is_empty());
     }
 }
@@ -0,0 +1,272 @@
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+};
+
+/// Where a database keeps its segment files. `Db` reaches its segments only
+/// through this, so they can live somewhere other than the local file system,
+/// e.g. in an object store. Directories and files are named by paths, which
+/// the store maps to whatever it keeps them in. `LocalFs` is the default.
+pub trait SegmentStore: fmt::Debug + Send + Sync {
+    /// The files directly in `dir`, in any order. Fails with
+    /// `ErrorKind::NotFound` if `dir` does not exist.
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// The directories directly in `dir`, which hold column families.
+    fn list_dirs(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+
+    fn create_dir(&self, dir: &Path) -> io::Result<()>;
+
+    /// Opens the file at `path` for reading from its start.
+    fn open_reader(&self, path: &Path) -> io::Result<Box<dyn SegmentReader>>;
+
+    /// Opens the file at `path` for appending, creating it empty if it is
+    /// missing.
+    fn create_writer(&self, path: &Path) -> io::Result<Box<dyn SegmentWriter>>;
+
+    /// Renames `from` to `to` atomically, replacing any file at `to`:
+    /// afterwards, also after a crash, the file is found under exactly one of
+    /// the two names.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn remove(&self, path: &Path) -> io::Result<()>;
+}
+
+/// A file opened by `SegmentStore::open_reader`.
+pub trait SegmentReader: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> SegmentReader for T {}
+
+/// A file opened by `SegmentStore::create_writer`. Every write appends.
+pub trait SegmentWriter: Write + Send + Sync + fmt::Debug {
+    /// The length of the file in bytes.
+    fn size(&self) -> io::Result<u64>;
+
+    /// Waits until everything written has reached durable storage.
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Cuts the file down to its first `len` bytes.
+    fn truncate(&mut self, len: u64) -> io::Result<()>;
+}
+
+/// How new segment files are named. Either way the name carries the time the
+/// segment was created, and segments replay in creation order.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentNaming {
+    /// The creation time in microseconds since the Unix epoch, e.g.
+    /// `1700000000000000`.
+    #[default]
+    Timestamp,
+    /// A zero-padded sequence number counting the segments of the directory,
+    /// followed by the creation time, e.g. `000042-1700000000000000`, so a
+    /// directory listing shows the order and number of segments.
+    Sequenced,
+}
+
+/// Segments kept as files in directories of the local file system.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LocalFs;
+
+impl SegmentStore for LocalFs {
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                files.push(entry.path());
+            }
+        }
+        Ok(files)
+    }
+
+    fn list_dirs(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut dirs = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                dirs.push(entry.path());
+            }
+        }
+        Ok(dirs)
+    }
+
+    fn create_dir(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir(dir)
+    }
+
+    fn open_reader(&self, path: &Path) -> io::Result<Box<dyn SegmentReader>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn create_writer(&self, path: &Path) -> io::Result<Box<dyn SegmentWriter>> {
+        Ok(Box::new(OpenOptions::new().append(true).create(true).open(path)?))
+    }
+
+    /// Also syncs the directory, so the new name survives a power loss.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)?;
+        if let Some(dir) = to.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+}
+
+impl SegmentWriter for File {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.set_len(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{BTreeMap, BTreeSet},
+        io::{self, Cursor, Write},
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex},
+    };
+
+    use crate::{config::DbConfig, db::Db, error::DbError};
+
+    use super::{SegmentReader, SegmentStore, SegmentWriter};
+
+    /// Keeps every file in memory.
+    #[derive(Debug, Default)]
+    struct MemoryStore {
+        files: Mutex<BTreeMap<PathBuf, Arc<Mutex<Vec<u8>>>>>,
+        dirs: Mutex<BTreeSet<PathBuf>>,
+    }
+
+    #[derive(Debug)]
+    struct MemoryFile(Arc<Mutex<Vec<u8>>>);
+
+    fn not_found(path: &Path) -> io::Error {
+        io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path.display()))
+    }
+
+    impl SegmentStore for MemoryStore {
+        fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+            if !self.dirs.lock().unwrap().contains(dir) {
+                return Err(not_found(dir));
+            }
+            let files = self.files.lock().unwrap();
+            Ok(files.keys().filter(|path| path.parent() == Some(dir)).cloned().collect())
+        }
+
+        fn list_dirs(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+            let dirs = self.dirs.lock().unwrap();
+            Ok(dirs.iter().filter(|path| path.parent() == Some(dir)).cloned().collect())
+        }
+
+        fn create_dir(&self, dir: &Path) -> io::Result<()> {
+            self.dirs.lock().unwrap().insert(dir.to_owned());
+            Ok(())
+        }
+
+        fn open_reader(&self, path: &Path) -> io::Result<Box<dyn SegmentReader>> {
+            let files = self.files.lock().unwrap();
+            let file = files.get(path).ok_or_else(|| not_found(path))?;
+            let bytes = file.lock().unwrap().clone();
+            Ok(Box::new(Cursor::new(bytes)))
+        }
+
+        fn create_writer(&self, path: &Path) -> io::Result<Box<dyn SegmentWriter>> {
+            let mut files = self.files.lock().unwrap();
+            let file = files.entry(path.to_owned()).or_default();
+            Ok(Box::new(MemoryFile(file.clone())))
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            let mut files = self.files.lock().unwrap();
+            let file = files.remove(from).ok_or_else(|| not_found(from))?;
+            files.insert(to.to_owned(), file);
+            Ok(())
+        }
+
+        fn remove(&self, path: &Path) -> io::Result<()> {
+            let mut files = self.files.lock().unwrap();
+            files.remove(path).map(|_| ()).ok_or_else(|| not_found(path))
+        }
+    }
+
+    impl Write for MemoryFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SegmentWriter for MemoryFile {
+        fn size(&self) -> io::Result<u64> {
+            Ok(self.0.lock().unwrap().len() as u64)
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn truncate(&mut self, len: u64) -> io::Result<()> {
+            self.0.lock().unwrap().truncate(len as usize);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn in_memory_store() {
+        let store = Arc::new(MemoryStore::default());
+        let dir = PathBuf::from("memory-db");
+        let config = DbConfig::new(dir.clone()).with_segment_store(store.clone());
+
+        // flushes make gets read sealed segments back from the store
+        let mut db = Db::open(config.clone().with_max_memtable_bytes(0)).unwrap();
+        db.set(b"Hello", b"World!").unwrap();
+        db.set(b"Name", b"Vahid").unwrap();
+        db.delete(b"Name").unwrap();
+        db.set_cf("users", b"Hello", b"family").unwrap();
+        assert_eq!(b"World!".to_vec(), *db.get(b"Hello").unwrap().unwrap().value.unwrap());
+        assert_eq!(None, db.get(b"Name").unwrap());
+        drop(db);
+
+        // reopening replays what the store holds
+        let mut db = Db::open(config).unwrap();
+        assert_eq!(b"World!".to_vec(), *db.get(b"Hello").unwrap().unwrap().value.unwrap());
+        assert_eq!(None, db.get(b"Name").unwrap());
+        assert_eq!(b"family".to_vec(), *db.get_cf("users", b"Hello").unwrap().unwrap().value.unwrap());
+
+        db.compact().unwrap();
+        assert_eq!(1, db.total_records().unwrap());
+        assert_eq!(1, store.list(&dir).unwrap().len());
+        drop(db);
+
+        // read-only handles read through the store as well
+        let mut db = Db::open_read_only_in(store.clone(), dir.clone()).unwrap();
+        assert_eq!(b"World!".to_vec(), *db.get(b"Hello").unwrap().unwrap().value.unwrap());
+        assert_eq!(b"family".to_vec(), *db.get_cf("users", b"Hello").unwrap().unwrap().value.unwrap());
+        assert!(matches!(db.set(b"Name", b"Vahid"), Err(DbError::ReadOnly)));
+
+        let segment = store.list(&dir).unwrap().remove(0);
+        let db = Db::open_segment_in(store.clone(), segment).unwrap();
+        assert_eq!(b"World!".to_vec(), *db.get(b"Hello").unwrap().unwrap().value.unwrap());
+        assert_eq!(1, db.total_records().unwrap());
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,74 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// Minimum, maximum and average of a set of latency samples.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySummary {
+    pub samples: u64,
+    pub min: Duration,
+    pub max: Duration,
+    pub total: Duration,
+}
+
+impl LatencySummary {
+    pub fn record(&mut self, latency: Duration) {
+        if self.samples == 0 || latency < self.min {
+            self.min = latency;
+        }
+        self.max = self.max.max(latency);
+        self.total += latency;
+        self.samples += 1;
+    }
+
+    pub fn avg(&self) -> Duration {
+        if self.samples == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_nanos((self.total.as_nanos() / self.samples as u128) as u64)
+    }
+}
+
+/// Write path latencies collected by `Db` when latency collection is enabled.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub set: LatencySummary,
+    pub flush: LatencySummary,
+}
+
+/// A point-in-time view of how much a `Db` holds, see `Db::stats`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DbStats {
+    pub live_keys: usize,
+    pub tombstones: usize,
+    /// Approximate bytes held by the MemTable, tombstones included.
+    pub memtable_bytes: usize,
+    /// Segment files in the database directory.
+    pub files: usize,
+    /// Bytes taken up by those files.
+    pub disk_bytes: u64,
+    /// See `Db::generation`.
+    pub generation: u64,
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::LatencySummary;
+
+    #[test]
+    fn test_latency_summary() {
+        let mut summary = LatencySummary::default();
+        assert_eq!(Duration::ZERO, summary.avg());
+
+        summary.record(Duration::from_micros(30));
+        summary.record(Duration::from_micros(10));
+        summary.record(Duration::from_micros(20));
+
+        assert_eq!(3, summary.samples);
+        assert_eq!(Duration::from_micros(10), summary.min);
+        assert_eq!(Duration::from_micros(30), summary.max);
+        assert_eq!(Duration::from_micros(20), summary.avg());
+    }
+}
@@ -1,56 +1,371 @@
 use std::{
-    fs::{File, OpenOptions},
-    io::{self, BufWriter, Write},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
+    sync::Arc,
     time::{SystemTime, UNIX_EPOCH},
 };
 
-use crate::utils::remove_file;
+use crate::{
+    crypto::{RecordCipher, NONCE_SIZE, TAG_SIZE},
+    entry::Entry,
+    segment_store::{LocalFs, SegmentNaming, SegmentStore, SegmentWriter},
+    utils::parse_segment_name,
+};
 
 #[derive(Debug)]
 pub struct Storage {
-    writer: BufWriter<File>,
+    store: Arc<dyn SegmentStore>,
+    writer: BufWriter<Box<dyn SegmentWriter>>,
     file_path: PathBuf,
+    offset: u64,
+    committed_offset: u64,
+    sync_on_commit: bool,
+    compression_threshold: Option<usize>,
+    cipher: Option<RecordCipher>,
+    #[cfg(test)]
+    fail_after_writes: Option<usize>,
+}
+
+// Every storage file starts with a magic string and the format version of the
+// records that follow it:
+// +------------+------------------+
+// | Magic (8B) | Version (2B, LE) |
+// +------------+------------------+
+//
+// Version 2 added `FLAG_EXPIRES`. Version 1 files are still read, but only
+// files of the current version are appended to, so an older reader never
+// meets a record it cannot frame.
+const MAGIC: &[u8; 8] = b"RRDBSEG\0";
+const FORMAT_VERSION: u16 = 2;
+pub(crate) const FILE_HEADER_SIZE: usize = 10;
+
+const HEADER_SIZE: usize = 17;
+
+/// Bits of the record flags byte.
+pub(crate) const FLAG_DELETED: u8 = 0b01;
+pub(crate) const FLAG_COMPRESSED: u8 = 0b10;
+pub(crate) const FLAG_ENCRYPTED: u8 = 0b100;
+pub(crate) const FLAG_EXPIRES: u8 = 0b1000;
+
+const TIMESTAMP_SIZE: usize = 16;
+pub(crate) const EXPIRY_SIZE: usize = 16;
+const CHECKSUM_SIZE: usize = 4;
+
+/// The size of an encoded record. Tombstones have no value, so pass 0 for them.
+pub(crate) fn record_len(key_len: usize, value_len: usize) -> usize {
+    HEADER_SIZE + key_len + value_len + TIMESTAMP_SIZE + CHECKSUM_SIZE
+}
+
+/// The size of the record starting at `bytes` as claimed by its length
+/// fields, or `None` if the fixed-size header is incomplete or the lengths
+/// overflow.
+pub(crate) fn framed_len(bytes: &[u8]) -> Option<u64> {
+    let header = bytes.get(..HEADER_SIZE)?;
+    let key_size = u64::from_le_bytes(header[0..8].try_into().ok()?);
+    let value_size = if header[8] & FLAG_DELETED != 0 {
+        0
+    } else {
+        u64::from_le_bytes(header[9..17].try_into().ok()?)
+    };
+    let mut overhead = 0;
+    if header[8] & FLAG_ENCRYPTED != 0 {
+        overhead += NONCE_SIZE + TAG_SIZE;
+    }
+    if header[8] & FLAG_EXPIRES != 0 {
+        overhead += EXPIRY_SIZE;
+    }
+
+    key_size
+        .checked_add(value_size)?
+        .checked_add((HEADER_SIZE + overhead + TIMESTAMP_SIZE + CHECKSUM_SIZE) as u64)
+}
+
+/// Reads the file header from the start of `reader` and returns the format
+/// version, failing with `ErrorKind::InvalidData` if it is not a storage file
+/// of a known version.
+pub(crate) fn read_file_header(reader: &mut impl Read) -> io::Result<u16> {
+    let mut header = [0; FILE_HEADER_SIZE];
+    reader.read_exact(&mut header).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => io::Error::new(io::ErrorKind::InvalidData, "not a storage file"),
+        _ => e,
+    })?;
+
+    if header[..8] != MAGIC[..] {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a storage file"));
+    }
+    let version = u16::from_le_bytes([header[8], header[9]]);
+    if !(1..=FORMAT_VERSION).contains(&version) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported storage format version {}", version),
+        ));
+    }
+    Ok(version)
+}
+
+/// The length of the run of intact records at the start of `file`, which is
+/// `file_len` bytes long: the offset of the first record that is cut short or
+/// fails its checksum, or the file length if there is none. Only framing and
+/// checksums are checked, so encrypted records count as intact without their
+/// key.
+fn intact_len(file: impl Read + Seek, file_len: u64) -> io::Result<u64> {
+    let mut reader = io::BufReader::new(file);
+    reader.seek(SeekFrom::Start(FILE_HEADER_SIZE as u64))?;
+
+    let mut offset = FILE_HEADER_SIZE as u64;
+    let mut header = [0; HEADER_SIZE];
+    while reader.read_exact(&mut header).is_ok() {
+        let record_len = match framed_len(&header) {
+            Some(len) if len <= file_len - offset => len,
+            _ => break,
+        };
+        let mut body = vec![0; record_len as usize - HEADER_SIZE];
+        reader.read_exact(&mut body)?;
+
+        let (body, checksum) = body.split_at(body.len() - CHECKSUM_SIZE);
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header);
+        hasher.update(body);
+        if hasher.finalize().to_le_bytes() != checksum {
+            break;
+        }
+        offset += record_len;
+    }
+    Ok(offset)
+}
+
+/// Extension of segments that are still being written by `Storage::new_temp`.
+pub(crate) const TEMP_EXTENSION: &str = "tmp";
+
+/// A free segment path in `dir`, named after the current time as `naming`
+/// says. Sequence numbers continue from the highest one in `dir`.
+fn new_segment_path(store: &dyn SegmentStore, dir: &Path, naming: SegmentNaming) -> io::Result<PathBuf> {
+    let mut timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(io::Error::other)?
+        .as_micros();
+
+    let taken = store.list(dir)?;
+    let name = |timestamp: u128| match naming {
+        SegmentNaming::Timestamp => format!("{}", timestamp),
+        SegmentNaming::Sequenced => {
+            let last = taken
+                .iter()
+                .filter_map(|path| parse_segment_name(path.file_stem()?.to_str()?))
+                .map(|(_, sequence)| sequence)
+                .max()
+                .unwrap_or(0);
+            format!("{:06}-{}", last + 1, timestamp)
+        }
+    };
+    let mut file_path = dir.join(name(timestamp));
+    while taken.contains(&file_path) || taken.contains(&file_path.with_extension(TEMP_EXTENSION)) {
+        timestamp += 1;
+        file_path = dir.join(name(timestamp));
+    }
+    Ok(file_path)
+}
+
+/// Opens a storage file for appending, writing the file header if the file is
+/// new and checking it otherwise.
+fn open_file(store: &dyn SegmentStore, file_path: &Path) -> io::Result<Box<dyn SegmentWriter>> {
+    let mut file = store.create_writer(file_path)?;
+
+    if file.size()? == 0 {
+        file.write_all(MAGIC)?;
+        file.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    } else if read_file_header(&mut store.open_reader(file_path)?)? != FORMAT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "cannot append to a file of an older storage format version",
+        ));
+    }
+    Ok(file)
+}
+
+// The data layout:
+// +---------------+-------------------+-----------------+----------+------------+-----------------+-------------+
+// | Key size (8B) | Flags (1B)        | Value size (8B) | key (?B) | value (?B) | timestamp (16B) | CRC32 (4B)  |
+// +---------------+-------------------+-----------------+----------+------------+-----------------+-------------+
+//
+// The CRC32 covers every byte before it, length fields included. Tombstones
+// carry no value and a value size of 0. With `FLAG_COMPRESSED` the value is
+// zstd compressed and the value size is that of the compressed bytes. With
+// `FLAG_EXPIRES` the expiry (16B, micros since the epoch) follows the
+// timestamp.
+pub(crate) fn encode_record(
+    key: &[u8],
+    value: &[u8],
+    flags: u8,
+    timestamp: u128,
+    expires_at: Option<u128>,
+    out: &mut Vec<u8>,
+) {
+    let value: &[u8] = if flags & FLAG_DELETED != 0 { &[] } else { value };
+    let flags = if expires_at.is_some() { flags | FLAG_EXPIRES } else { flags };
+    let start = out.len();
+
+    out.extend_from_slice(&(key.len() as u64).to_le_bytes());
+    out.extend_from_slice(&flags.to_le_bytes());
+    out.extend_from_slice(&(value.len() as u64).to_le_bytes());
+
+    out.extend_from_slice(key);
+    out.extend_from_slice(value);
+    out.extend_from_slice(&timestamp.to_le_bytes());
+    if let Some(expires_at) = expires_at {
+        out.extend_from_slice(&expires_at.to_le_bytes());
+    }
+
+    let checksum = crc32fast::hash(&out[start..]);
+    out.extend_from_slice(&checksum.to_le_bytes());
+}
+
+// Encrypted records (`FLAG_ENCRYPTED`) keep their length fields in the clear
+// for framing, but key and value are sealed together with AES-256-GCM:
+// +-------------+------------+-------------------------+-----------+-----------------+------------+
+// | Header(17B) | Nonce(12B) | sealed key + value (?B) | Tag (16B) | timestamp (16B) | CRC32 (4B) |
+// +-------------+------------+-------------------------+-----------+-----------------+------------+
+//
+// The header, timestamp and expiry are authenticated as associated data, so
+// none of them can be swapped between records without failing decryption.
+fn encode_encrypted_record(
+    cipher: &RecordCipher,
+    key: &[u8],
+    value: &[u8],
+    flags: u8,
+    timestamp: u128,
+    expires_at: Option<u128>,
+    out: &mut Vec<u8>,
+) -> io::Result<()> {
+    let value: &[u8] = if flags & FLAG_DELETED != 0 { &[] } else { value };
+    let flags = if expires_at.is_some() { flags | FLAG_EXPIRES } else { flags };
+    let start = out.len();
+
+    out.extend_from_slice(&(key.len() as u64).to_le_bytes());
+    out.extend_from_slice(&(flags | FLAG_ENCRYPTED).to_le_bytes());
+    out.extend_from_slice(&(value.len() as u64).to_le_bytes());
+
+    let aad = record_aad(&out[start..], timestamp, expires_at);
+    let plaintext = [key, value].concat();
+    let (nonce, sealed) = cipher.seal(&plaintext, &aad)?;
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&sealed);
+    out.extend_from_slice(&timestamp.to_le_bytes());
+    if let Some(expires_at) = expires_at {
+        out.extend_from_slice(&expires_at.to_le_bytes());
+    }
+
+    let checksum = crc32fast::hash(&out[start..]);
+    out.extend_from_slice(&checksum.to_le_bytes());
+    Ok(())
+}
+
+/// The associated data of an encrypted record: its header, timestamp and
+/// expiry.
+pub(crate) fn record_aad(header: &[u8], timestamp: u128, expires_at: Option<u128>) -> Vec<u8> {
+    let mut aad = [header, &timestamp.to_le_bytes()].concat();
+    if let Some(expires_at) = expires_at {
+        aad.extend_from_slice(&expires_at.to_le_bytes());
+    }
+    aad
 }
 
 impl Storage {
+    /// Creates a new segment in `dir`, named after the current time. Should a
+    /// segment with that name exist already, the next free microsecond is used.
+    #[allow(dead_code)]
     pub fn new(dir: &Path) -> io::Result<Storage> {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
-            .as_micros();
+        Storage::new_in(Arc::new(LocalFs), dir, SegmentNaming::default())
+    }
+
+    /// Like `new`, in a directory of `store` and named as `naming` says.
+    pub fn new_in(store: Arc<dyn SegmentStore>, dir: &Path, naming: SegmentNaming) -> io::Result<Storage> {
+        let file_path = new_segment_path(&*store, dir, naming)?;
+        Storage::create(store, file_path)
+    }
+
+    /// Creates a segment under a temporary name, which replay ignores, until
+    /// `publish` gives it its real name.
+    #[allow(dead_code)]
+    pub fn new_temp(dir: &Path) -> io::Result<Storage> {
+        Storage::new_temp_in(Arc::new(LocalFs), dir, SegmentNaming::default())
+    }
+
+    /// Like `new_temp`, in a directory of `store` and named as `naming` says.
+    pub fn new_temp_in(store: Arc<dyn SegmentStore>, dir: &Path, naming: SegmentNaming) -> io::Result<Storage> {
+        let file_path = new_segment_path(&*store, dir, naming)?.with_extension(TEMP_EXTENSION);
+        Storage::create(store, file_path)
+    }
 
-        let file_path = Path::new(dir).join(format!("{}", timestamp.to_string()));
+    /// Like `new_temp_in`, but `publish` renames the segment to `path`,
+    /// replacing the segment there in one step.
+    pub fn replacing_in(store: Arc<dyn SegmentStore>, path: &Path) -> io::Result<Storage> {
+        Storage::create(store, path.with_extension(TEMP_EXTENSION))
+    }
 
-        let file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&file_path)?;
+    fn create(store: Arc<dyn SegmentStore>, file_path: PathBuf) -> io::Result<Storage> {
+        let file = open_file(&*store, &file_path)?;
 
+        let offset = file.size()?;
         let writer = BufWriter::new(file);
 
-        Ok(Storage { writer, file_path })
+        Ok(Storage {
+            store,
+            writer,
+            file_path,
+            offset,
+            committed_offset: offset,
+            sync_on_commit: false,
+            compression_threshold: None,
+            cipher: None,
+            #[cfg(test)]
+            fail_after_writes: None,
+        })
     }
 
+    /// Syncs a segment made by `new_temp` and atomically renames it to its
+    /// segment name, so a crash leaves either no segment or a complete one.
+    pub fn publish(&mut self) -> io::Result<()> {
+        self.sync()?;
+        let file_path = self.file_path.with_extension("");
+        self.store.rename(&self.file_path, &file_path)?;
+        self.file_path = file_path;
+        Ok(())
+    }
+
+    /// Opens an existing segment to append to it. A torn write from a crash
+    /// leaves a partial or checksum-failing record at the end of the file;
+    /// everything from the first such record on is truncated so new records
+    /// follow the last intact one.
     #[allow(dead_code)]
     pub fn from_path(file_path: &Path) -> io::Result<Storage> {
-        let file = OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(&file_path)?;
+        Storage::from_path_in(Arc::new(LocalFs), file_path)
+    }
+
+    /// Like `from_path`, for a segment of `store`.
+    pub fn from_path_in(store: Arc<dyn SegmentStore>, file_path: &Path) -> io::Result<Storage> {
+        let mut file = open_file(&*store, file_path)?;
+        let file_len = file.size()?;
+        let offset = intact_len(store.open_reader(file_path)?, file_len)?;
+        if offset < file_len {
+            file.truncate(offset)?;
+        }
         let writer = BufWriter::new(file);
 
         Ok(Storage {
+            store,
             writer,
             file_path: file_path.to_owned(),
+            offset,
+            committed_offset: offset,
+            sync_on_commit: false,
+            compression_threshold: None,
+            cipher: None,
+            #[cfg(test)]
+            fail_after_writes: None,
         })
     }
 
-    // The data layout:
-    // +---------------+-------------------+-----------------+----------+------------+-----------------+
-    // | Key size (8B) | Deleted flag (1B) | Value size (8B) | key (?B) | value (?B) | timestamp (16B) |
-    // +---------------+-------------------+-----------------+----------+------------+-----------------+
-    //
     pub fn set(
         &mut self,
         key: &[u8],
@@ -58,52 +373,221 @@ impl Storage {
         deleted: bool,
         timestamp: u128,
     ) -> io::Result<()> {
-        self.writer.write_all(&(key.len() as u64).to_le_bytes())?;
-        self.writer.write_all(&(deleted as u8).to_le_bytes())?;
-        self.writer.write_all(&(value.len() as u64).to_le_bytes())?;
+        #[cfg(test)]
+        self.inject_failure()?;
+
+        if deleted {
+            return self.write_record(key, value, FLAG_DELETED, timestamp, None);
+        }
+        self.write_value(key, value, timestamp, None)
+    }
+
+    /// Writes `entry` with its timestamp, deleted flag and expiry. With
+    /// `compressed` the value is always stored compressed, as by
+    /// `set_compressed`; otherwise the compression threshold applies.
+    pub fn set_entry(&mut self, entry: &Entry, compressed: bool) -> io::Result<()> {
+        #[cfg(test)]
+        self.inject_failure()?;
+
+        let value = entry.value.as_deref().map(Vec::as_slice).unwrap_or_default();
+        if entry.deleted {
+            self.write_record(&entry.key, &[], FLAG_DELETED, entry.timestamp, entry.expires_at)
+        } else if compressed {
+            let compressed = zstd::bulk::compress(value, 0)?;
+            self.write_record(&entry.key, &compressed, FLAG_COMPRESSED, entry.timestamp, entry.expires_at)
+        } else {
+            self.write_value(&entry.key, value, entry.timestamp, entry.expires_at)
+        }
+    }
+
+    /// Writes a live value, compressed if it reaches the compression threshold
+    /// and shrinks.
+    fn write_value(&mut self, key: &[u8], value: &[u8], timestamp: u128, expires_at: Option<u128>) -> io::Result<()> {
+        match self.compression_threshold {
+            Some(threshold) if value.len() >= threshold => {
+                let compressed = zstd::bulk::compress(value, 0)?;
+                if compressed.len() < value.len() {
+                    return self.write_record(key, &compressed, FLAG_COMPRESSED, timestamp, expires_at);
+                }
+                self.write_record(key, value, 0, timestamp, expires_at)
+            }
+            _ => self.write_record(key, value, 0, timestamp, expires_at),
+        }
+    }
+
+    /// Makes `set` store values of at least `threshold` bytes zstd compressed
+    /// whenever that makes them smaller. Small values rarely shrink enough to
+    /// pay for the frame overhead, so they stay verbatim. `None` (the
+    /// default) never compresses.
+    pub fn set_compression_threshold(&mut self, threshold: Option<usize>) {
+        self.compression_threshold = threshold;
+    }
+
+    /// Like `set`, but stores the value zstd compressed. `StorageIterator`
+    /// decompresses it again, so readers never see the compressed bytes.
+    pub fn set_compressed(&mut self, key: &[u8], value: &[u8], timestamp: u128) -> io::Result<()> {
+        #[cfg(test)]
+        self.inject_failure()?;
+
+        let compressed = zstd::bulk::compress(value, 0)?;
+        self.write_record(key, &compressed, FLAG_COMPRESSED, timestamp, None)
+    }
 
-        self.writer.write_all(key)?;
-        self.writer.write_all(value)?;
+    fn write_record(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        flags: u8,
+        timestamp: u128,
+        expires_at: Option<u128>,
+    ) -> io::Result<()> {
+        let mut record = Vec::with_capacity(record_len(key.len(), value.len()));
+        match &self.cipher {
+            Some(cipher) => {
+                encode_encrypted_record(cipher, key, value, flags, timestamp, expires_at, &mut record)?
+            }
+            None => encode_record(key, value, flags, timestamp, expires_at, &mut record),
+        }
+        self.writer.write_all(&record)?;
 
-        self.writer.write_all(&timestamp.to_le_bytes())?;
+        self.offset += record.len() as u64;
 
         Ok(())
     }
 
     pub fn delete(&mut self, key: &[u8], timestamp: u128) -> io::Result<()> {
-        self.writer.write_all(&key.len().to_le_bytes())?;
-        self.writer.write_all(&(true as u8).to_le_bytes())?;
-        let value_size = 0x0000 as u64;
-        self.writer.write_all(&value_size.to_le_bytes())?;
+        self.set(key, &[], true, timestamp)
+    }
 
-        self.writer.write_all(key)?;
+    /// Appends an already framed record verbatim, e.g. one shipped by a leader,
+    /// and returns the offset it was written at. The record's length fields must
+    /// agree with its actual size and its checksum must match. With an
+    /// encryption key set, plaintext records are encrypted on the way in.
+    #[allow(dead_code)]
+    pub fn append_raw(&mut self, record_bytes: &[u8]) -> io::Result<u64> {
+        if record_bytes.len() < HEADER_SIZE + TIMESTAMP_SIZE + CHECKSUM_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "record is shorter than its fixed-size fields",
+            ));
+        }
+
+        if framed_len(record_bytes) != Some(record_bytes.len() as u64) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "record length fields do not match the record size",
+            ));
+        }
+
+        let (body, checksum) = record_bytes.split_at(record_bytes.len() - CHECKSUM_SIZE);
+        if crc32fast::hash(body).to_le_bytes() != checksum {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "record checksum mismatch"));
+        }
+
+        let offset = self.offset;
+        let flags = record_bytes[8];
+        if self.cipher.is_some() && flags & FLAG_ENCRYPTED == 0 {
+            let key_end = HEADER_SIZE + u64::from_le_bytes(record_bytes[0..8].try_into().unwrap()) as usize;
+            let (fields, expires_at) = if flags & FLAG_EXPIRES != 0 {
+                let (fields, expires_at) = body.split_at(body.len() - EXPIRY_SIZE);
+                (fields, Some(u128::from_le_bytes(expires_at.try_into().unwrap())))
+            } else {
+                (body, None)
+            };
+            let (fields, timestamp) = fields.split_at(fields.len() - TIMESTAMP_SIZE);
+            let timestamp = u128::from_le_bytes(timestamp.try_into().unwrap());
+            let flags = flags & !FLAG_EXPIRES;
+            self.write_record(&fields[HEADER_SIZE..key_end], &fields[key_end..], flags, timestamp, expires_at)?;
+            return Ok(offset);
+        }
+
+        self.writer.write_all(record_bytes)?;
+        self.offset += record_bytes.len() as u64;
+
+        Ok(offset)
+    }
 
-        self.writer.write_all(&timestamp.to_le_bytes())?;
+    pub fn path(&self) -> &Path {
+        &self.file_path
+    }
 
-        Ok(())
+    /// Encrypts every record written from now on with AES-256-GCM under `key`;
+    /// `None` goes back to plaintext records. Records already in the file are
+    /// left as they are.
+    pub fn set_encryption_key(&mut self, key: Option<&[u8; 32]>) {
+        self.cipher = key.map(RecordCipher::new);
+    }
+
+    /// Makes every `commit` wait for the written data to reach the disk, so
+    /// committed records survive a power loss. Off by default: a plain commit
+    /// only hands the bytes to the OS.
+    pub fn set_sync(&mut self, sync: bool) {
+        self.sync_on_commit = sync;
     }
 
     pub fn commit(&mut self) -> io::Result<()> {
         self.writer.flush()?;
+        if self.sync_on_commit {
+            self.writer.get_mut().sync()?;
+        }
+        self.committed_offset = self.offset;
         Ok(())
     }
 
+    /// Commits and then waits until the file's contents have reached the disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.commit()?;
+        self.writer.get_mut().sync()
+    }
+
+    /// Throws away everything written since the last commit: buffered bytes
+    /// are dropped without being flushed and anything that already reached
+    /// the file is truncated away.
+    pub fn rollback(&mut self) -> io::Result<()> {
+        let file = self.store.create_writer(&self.file_path)?;
+        let writer = std::mem::replace(&mut self.writer, BufWriter::new(file));
+        let (mut file, _discarded) = writer.into_parts();
+        file.truncate(self.committed_offset)?;
+        self.offset = self.committed_offset;
+        Ok(())
+    }
+
+    /// Makes the write after the next `writes` ones fail, for testing error paths.
+    #[cfg(test)]
+    pub fn fail_after_writes(&mut self, writes: usize) {
+        self.fail_after_writes = Some(writes);
+    }
+
+    #[cfg(test)]
+    fn inject_failure(&mut self) -> io::Result<()> {
+        match self.fail_after_writes.as_mut() {
+            Some(0) => Err(io::Error::other("injected write failure")),
+            Some(remaining) => {
+                *remaining -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
     pub fn purge_storage(&mut self) -> io::Result<()> {
-        remove_file(&self.file_path)?;
+        self.store.remove(&self.file_path)?;
 
-        let file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&self.file_path)?;
+        let file = open_file(&*self.store, &self.file_path)?;
         let writer = BufWriter::new(file);
         self.writer = writer;
+        self.offset = FILE_HEADER_SIZE as u64;
+        self.committed_offset = self.offset;
 
         Ok(())
     }
 
+    #[cfg(test)]
     pub fn write_all(&mut self, buffer: Vec<u8>) -> io::Result<()> {
         self.writer.write_all(&buffer)?;
         self.writer.flush()?;
+        self.offset += buffer.len() as u64;
+        self.committed_offset = self.offset;
         Ok(())
     }
 }
@@ -111,10 +595,19 @@ impl Storage {
 #[cfg(test)]
 mod test {
 
-    use super::Storage;
-    use crate::utils::{create_dir, file_reader, remove_dir, scan_dir};
+    use super::{Storage, FILE_HEADER_SIZE};
+    use crate::{
+        entry::Entry,
+        storage_iterator::StorageIterator,
+        utils::{create_dir, file_reader, remove_dir, scan_dir},
+    };
     use rand::Rng;
-    use std::{io::Read, path::PathBuf, time::SystemTime};
+    use std::{
+        fs,
+        io::{Read, Write},
+        path::PathBuf,
+        time::SystemTime,
+    };
 
     #[test]
     fn test_create() {
@@ -133,15 +626,15 @@ mod test {
             .expect("Error: could not writer in the file");
         storage.commit().expect("Error in flush!");
 
-        let mut line = [0 as u8; 28];
+        let mut line = [0_u8; 38];
 
-        let files = scan_dir(&path).expect(&format!("Error: could not scan the dir: {:?}", path));
+        let files = scan_dir(&path).unwrap_or_else(|_| panic!("Error: could not scan the dir: {:?}", path));
         let mut reader = file_reader(&files[0]);
 
         reader
             .read_exact(&mut line)
             .expect("Error: could not read the file");
-        assert_eq!(line[17..], *b"HelloWorld!");
+        assert_eq!(line[27..], *b"HelloWorld!");
 
         // Clean up
         remove_dir(&path).expect("Error: could not remove the directory");
@@ -179,15 +672,25 @@ mod test {
             .expect("Error: could not writer in the file");
         storage.commit().expect("Error in flush!");
 
-        let mut line = [0 as u8; 124];
+        let mut line = [0_u8; 134];
 
-        let files = scan_dir(&path).expect(&format!("Error: could not scan the dir: {:?}", path));
+        let files = scan_dir(&path).unwrap_or_else(|_| panic!("Error: could not scan the dir: {:?}", path));
         let mut reader = file_reader(&files[0]);
 
         reader
             .read_exact(&mut line)
             .expect("Error: could not read the file");
-        assert_eq!(line[94], true as u8);
+        assert_eq!(line[112], true as u8);
+        // the key length is a u64 like in every other record, whatever the
+        // pointer width, and a tombstone carries a zero value length
+        assert_eq!(line[104..112], 5u64.to_le_bytes());
+        assert_eq!(line[113..121], 0u64.to_le_bytes());
+
+        let entries: Vec<Entry> = StorageIterator::new(&files[0]).unwrap().collect();
+        assert_eq!(3, entries.len());
+        assert_eq!(b"Hello".to_vec(), entries[2].key);
+        assert!(entries[2].deleted);
+        assert_eq!(timestamp3, entries[2].timestamp);
 
         // Clean up
         remove_dir(&path).expect("Error: could not remove the directory");
@@ -220,7 +723,7 @@ mod test {
 
         drop(storage);
 
-        let files = scan_dir(&path).expect(&format!("Error: could not scan the dir: {:?}", path));
+        let files = scan_dir(&path).unwrap_or_else(|_| panic!("Error: could not scan the dir: {:?}", path));
 
         let mut storage2 = Storage::from_path(&files[0]).unwrap();
 
@@ -231,15 +734,15 @@ mod test {
             .expect("Error: could not writer in the file");
         storage2.commit().expect("Error in flush!");
 
-        let mut line = [0 as u8; 124];
+        let mut line = [0_u8; 134];
 
         let mut reader = file_reader(&files[0]);
 
         reader
             .read_exact(&mut line)
             .expect("Error: could not read the file");
-        assert_eq!(line[17..28], *b"HelloWorld!");
-        assert_eq!(line[94], true as u8);
+        assert_eq!(line[27..38], *b"HelloWorld!");
+        assert_eq!(line[112], true as u8);
 
         // Clean up
         remove_dir(&path).expect("Error: could not remove the directory");
@@ -277,15 +780,15 @@ mod test {
             .expect("Error: could not writer in the file");
         storage.commit().expect("Error in flush!");
 
-        let mut line = [0 as u8; 124];
+        let mut line = [0_u8; 134];
 
-        let files = scan_dir(&path).expect(&format!("Error: could not scan the dir: {:?}", path));
+        let files = scan_dir(&path).unwrap_or_else(|_| panic!("Error: could not scan the dir: {:?}", path));
         let mut reader = file_reader(&files[0]);
 
         reader
             .read_exact(&mut line)
             .expect("Error: could not read the file");
-        assert_eq!(line[94], true as u8);
+        assert_eq!(line[112], true as u8);
 
         // Delete the database
         storage.purge_storage().unwrap();
@@ -313,15 +816,259 @@ mod test {
             .expect("Error: could not writer in the file");
         storage.commit().expect("Error in flush!");
 
-        let mut line = [0 as u8; 124];
+        let mut line = [0_u8; 134];
 
-        let files = scan_dir(&path).expect(&format!("Error: could not scan the dir: {:?}", path));
+        let files = scan_dir(&path).unwrap_or_else(|_| panic!("Error: could not scan the dir: {:?}", path));
         let mut reader = file_reader(&files[0]);
 
         reader
             .read_exact(&mut line)
             .expect("Error: could not read the file");
-        assert_eq!(line[94], true as u8);
+        assert_eq!(line[112], true as u8);
+
+        // Clean up
+        remove_dir(&path).expect("Error: could not remove the directory");
+    }
+
+    #[test]
+    fn test_append_raw() {
+        let mut range = rand::thread_rng();
+        let leader_path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        let follower_path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+
+        create_dir(&leader_path).unwrap();
+        create_dir(&follower_path).unwrap();
+
+        let mut leader = Storage::new(&leader_path).unwrap();
+        let timestamp = SystemTime::now().elapsed().unwrap().as_micros();
+        leader
+            .set(b"Hello", b"World!", false, timestamp)
+            .expect("Error: could not writer in the file");
+        leader.commit().expect("Error in flush!");
+
+        let leader_files = scan_dir(&leader_path).unwrap();
+        let record = fs::read(&leader_files[0]).unwrap()[FILE_HEADER_SIZE..].to_vec();
+
+        let mut follower = Storage::new(&follower_path).unwrap();
+        let start = FILE_HEADER_SIZE as u64;
+        assert_eq!(start, follower.append_raw(&record).unwrap());
+        assert_eq!(start + record.len() as u64, follower.append_raw(&record).unwrap());
+        follower.commit().expect("Error in flush!");
+
+        let follower_files = scan_dir(&follower_path).unwrap();
+        let expected: Vec<Entry> = StorageIterator::new(&leader_files[0]).unwrap().collect();
+        let replicated: Vec<Entry> = StorageIterator::new(&follower_files[0]).unwrap().collect();
+        assert_eq!(2, replicated.len());
+        assert_eq!(expected[0], replicated[0]);
+        assert_eq!(expected[0], replicated[1]);
+
+        // inconsistent length fields are rejected
+        assert!(follower.append_raw(&record[..record.len() - 1]).is_err());
+
+        // so are records whose checksum does not match
+        let mut corrupted = record.clone();
+        corrupted[20] ^= 0xff;
+        assert!(follower.append_raw(&corrupted).is_err());
+
+        // Clean up
+        remove_dir(&leader_path).expect("Error: could not remove the directory");
+        remove_dir(&follower_path).expect("Error: could not remove the directory");
+    }
+
+    #[test]
+    fn test_rollback() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+
+        create_dir(&path).unwrap();
+
+        let mut storage = Storage::new(&path).unwrap();
+        let timestamp = SystemTime::now().elapsed().unwrap().as_micros();
+        storage
+            .set(b"Hello", b"World!", false, timestamp)
+            .expect("Error: could not writer in the file");
+        storage.commit().expect("Error in flush!");
+
+        // one record small enough to stay buffered, one large enough to spill
+        storage
+            .set(b"Name", b"Vahid", false, timestamp)
+            .expect("Error: could not writer in the file");
+        storage
+            .set(b"Large", &[7; 64 * 1024], false, timestamp)
+            .expect("Error: could not writer in the file");
+        storage.rollback().unwrap();
+
+        storage
+            .set(b"gg", b"wp", false, timestamp)
+            .expect("Error: could not writer in the file");
+        storage.commit().expect("Error in flush!");
+
+        let files = scan_dir(&path).unwrap();
+        let keys: Vec<Vec<u8>> = StorageIterator::new(&files[0]).unwrap().map(|e| e.key).collect();
+        assert_eq!(vec![b"Hello".to_vec(), b"gg".to_vec()], keys);
+        assert_eq!(10 + 48 + 41, fs::metadata(&files[0]).unwrap().len());
+
+        // Clean up
+        remove_dir(&path).expect("Error: could not remove the directory");
+    }
+
+    #[test]
+    fn test_sync_on_commit() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+
+        create_dir(&path).unwrap();
+
+        let mut storage = Storage::new(&path).unwrap();
+        storage.set_sync(true);
+        let timestamp = SystemTime::now().elapsed().unwrap().as_micros();
+        storage
+            .set(b"Hello", b"World!", false, timestamp)
+            .expect("Error: could not writer in the file");
+        storage.commit().expect("Error in flush!");
+        drop(storage);
+
+        // reopen and keep writing past the synced record
+        let files = scan_dir(&path).unwrap();
+        let mut storage = Storage::from_path(&files[0]).unwrap();
+        storage.set_sync(true);
+        storage
+            .set(b"Name", b"Vahid", false, timestamp)
+            .expect("Error: could not writer in the file");
+        storage.commit().expect("Error in flush!");
+        drop(storage);
+
+        let entries: Vec<Entry> = StorageIterator::new(&files[0]).unwrap().collect();
+        assert_eq!(2, entries.len());
+        assert_eq!(b"World!".to_vec(), *entries[0].value.clone().unwrap());
+        assert_eq!(b"Vahid".to_vec(), *entries[1].value.clone().unwrap());
+
+        // Clean up
+        remove_dir(&path).expect("Error: could not remove the directory");
+    }
+
+    #[test]
+    fn test_compression_threshold() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+
+        create_dir(&path).unwrap();
+
+        let mut storage = Storage::new(&path).unwrap();
+        storage.set_compression_threshold(Some(1024));
+
+        let large: Vec<u8> = b"{\"name\": \"Vahid\"}, ".iter().cycle().take(64 * 1024).copied().collect();
+        let timestamp = SystemTime::now().elapsed().unwrap().as_micros();
+        storage
+            .set(b"large", &large, false, timestamp)
+            .expect("Error: could not writer in the file");
+        storage
+            .set(b"small", b"World!", false, timestamp)
+            .expect("Error: could not writer in the file");
+        storage.commit().expect("Error in flush!");
+
+        let files = scan_dir(&path).unwrap();
+        assert!(fs::metadata(&files[0]).unwrap().len() < large.len() as u64);
+
+        let mut storage_iterator = StorageIterator::new(&files[0]).unwrap();
+        let (entry, compressed) = storage_iterator.next_record().unwrap();
+        assert!(compressed);
+        assert_eq!(large, *entry.value.unwrap());
+        let (entry, compressed) = storage_iterator.next_record().unwrap();
+        assert!(!compressed);
+        assert_eq!(b"World!".to_vec(), *entry.value.unwrap());
+
+        // Clean up
+        remove_dir(&path).expect("Error: could not remove the directory");
+    }
+
+    #[test]
+    fn test_truncate_torn_write() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+
+        create_dir(&path).unwrap();
+
+        let mut storage = Storage::new(&path).unwrap();
+        let timestamp = SystemTime::now().elapsed().unwrap().as_micros();
+        storage
+            .set(b"Hello", b"World!", false, timestamp)
+            .expect("Error: could not writer in the file");
+        storage.commit().expect("Error in flush!");
+        drop(storage);
+
+        // a crash in the middle of the next record
+        let files = scan_dir(&path).unwrap();
+        let mut file = fs::OpenOptions::new().append(true).open(&files[0]).unwrap();
+        file.write_all(&[5, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0]).unwrap();
+        drop(file);
+
+        let mut storage = Storage::from_path(&files[0]).unwrap();
+        assert_eq!(10 + 48, fs::metadata(&files[0]).unwrap().len());
+
+        storage
+            .set(b"Name", b"Vahid", false, timestamp)
+            .expect("Error: could not writer in the file");
+        storage.commit().expect("Error in flush!");
+        drop(storage);
+
+        // a complete record with garbage in it is cut off as well
+        let mut bytes = fs::read(&files[0]).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0x01;
+        fs::write(&files[0], &bytes).unwrap();
+
+        let storage = Storage::from_path(&files[0]).unwrap();
+        drop(storage);
+        assert_eq!(10 + 48, fs::metadata(&files[0]).unwrap().len());
+        let entries: Vec<Entry> = StorageIterator::new(&files[0]).unwrap().collect();
+        assert_eq!(1, entries.len());
+
+        // Clean up
+        remove_dir(&path).expect("Error: could not remove the directory");
+    }
+
+    #[test]
+    fn test_file_header() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+
+        create_dir(&path).unwrap();
+
+        let storage = Storage::new(&path).unwrap();
+        drop(storage);
+
+        let files = scan_dir(&path).unwrap();
+        let bytes = fs::read(&files[0]).unwrap();
+        assert_eq!(b"RRDBSEG\0\x02\x00", &bytes[..]);
+
+        // reopening keeps the single header
+        let mut storage = Storage::from_path(&files[0]).unwrap();
+        storage
+            .set(b"Hello", b"World!", false, 1)
+            .expect("Error: could not writer in the file");
+        storage.commit().expect("Error in flush!");
+        let entries: Vec<Entry> = StorageIterator::new(&files[0]).unwrap().collect();
+        assert_eq!(1, entries.len());
+
+        // version 1 files are still read, but not appended to
+        let mut bytes = fs::read(&files[0]).unwrap();
+        bytes[8] = 1;
+        let old = path.join("old");
+        fs::write(&old, &bytes).unwrap();
+        assert_eq!(1, StorageIterator::new(&old).unwrap().count());
+        assert_eq!(
+            std::io::ErrorKind::InvalidData,
+            Storage::from_path(&old).unwrap_err().kind()
+        );
+
+        // files that are not ours are refused
+        let foreign = path.join("foreign");
+        fs::write(&foreign, b"0123456789abcdef").unwrap();
+        assert_eq!(
+            std::io::ErrorKind::InvalidData,
+            Storage::from_path(&foreign).unwrap_err().kind()
+        );
 
         // Clean up
         remove_dir(&path).expect("Error: could not remove the directory");
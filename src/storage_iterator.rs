@@ -1,76 +1,268 @@
 use std::{
-    fs::{File, OpenOptions},
-    io::{self, BufReader, Read},
-    path::PathBuf,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+    sync::Arc,
 };
 
-use crate::entry::Entry;
+use crate::{
+    entry::Entry,
+    crypto::{RecordCipher, NONCE_SIZE},
+    segment_store::{LocalFs, SegmentReader, SegmentStore},
+    storage::{
+        framed_len, read_file_header, record_aad, FILE_HEADER_SIZE, FLAG_COMPRESSED, FLAG_DELETED,
+        FLAG_ENCRYPTED, FLAG_EXPIRES,
+    },
+};
 
 pub struct StorageIterator {
-    reader: BufReader<File>,
+    reader: BufReader<Box<dyn SegmentReader>>,
+    offset: u64,
+    file_len: u64,
+    cipher: Option<RecordCipher>,
+    corrupted_at: Option<u64>,
+    undecryptable_at: Option<u64>,
 }
 
 impl StorageIterator {
-    pub fn new(path: &PathBuf) -> io::Result<StorageIterator> {
-        let file = OpenOptions::new().read(true).open(path)?;
-        let reader = BufReader::new(file);
-        Ok(StorageIterator { reader })
+    /// Opens a storage file, failing with `ErrorKind::InvalidData` if it does
+    /// not start with a valid file header.
+    #[allow(dead_code)]
+    pub fn new(path: &Path) -> io::Result<StorageIterator> {
+        StorageIterator::with_key(path, None)
+    }
+
+    /// Like `new`, but decrypts encrypted records with `key`.
+    #[allow(dead_code)]
+    pub fn with_key(path: &Path, key: Option<&[u8; 32]>) -> io::Result<StorageIterator> {
+        StorageIterator::open_in(&LocalFs, path, key)
+    }
+
+    /// Like `with_key`, for a segment of `store`.
+    pub fn open_in(store: &dyn SegmentStore, path: &Path, key: Option<&[u8; 32]>) -> io::Result<StorageIterator> {
+        let mut file = store.open_reader(path)?;
+        let file_len = file.seek(SeekFrom::End(0))?;
+        file.rewind()?;
+        let mut reader = BufReader::new(file);
+        read_file_header(&mut reader)?;
+        Ok(StorageIterator {
+            reader,
+            offset: FILE_HEADER_SIZE as u64,
+            file_len,
+            cipher: key.map(RecordCipher::new),
+            corrupted_at: None,
+            undecryptable_at: None,
+        })
+    }
+
+    /// The length of the file, as it was when it was opened.
+    pub fn file_len(&self) -> u64 {
+        self.file_len
+    }
+
+    /// The byte offset just past the last entry yielded.
+    pub fn current_offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Continues reading at `offset`, typically a `current_offset` saved
+    /// earlier, so a partly processed file need not be read again from the
+    /// start. The offset must be a record boundary; offsets inside the file
+    /// header or past the end of the file are rejected with
+    /// `ErrorKind::InvalidInput`. Seeking clears a stop at a corrupted or
+    /// undecryptable record.
+    pub fn seek(&mut self, offset: u64) -> io::Result<()> {
+        if offset < FILE_HEADER_SIZE as u64 || offset > self.file_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "offset is outside the records of the file",
+            ));
+        }
+
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.offset = offset;
+        self.corrupted_at = None;
+        self.undecryptable_at = None;
+        Ok(())
+    }
+
+    /// The offset of the record whose checksum did not match or whose value
+    /// failed to decompress, if iteration stopped at one. Nothing after a
+    /// corrupted record is yielded, since its length fields cannot be trusted
+    /// either.
+    pub fn corrupted_at(&self) -> Option<u64> {
+        self.corrupted_at
+    }
+
+    /// The offset of an intact encrypted record that could not be decrypted
+    /// because no key or the wrong key was given, if iteration stopped at one.
+    pub fn undecryptable_at(&self) -> Option<u64> {
+        self.undecryptable_at
+    }
+
+    /// Counts the complete records in the file by reading only the fixed-size
+    /// headers and seeking over the rest of each record.
+    pub fn count_records(mut self) -> io::Result<u64> {
+        let file_len = self.file_len;
+        let mut position = FILE_HEADER_SIZE as u64;
+        let mut count = 0;
+        let mut buffer = [0; 17];
+
+        while self.reader.read_exact(&mut buffer).is_ok() {
+            let record_len = match framed_len(&buffer) {
+                Some(len) if len <= file_len - position => len,
+                _ => break,
+            };
+            position += record_len;
+            self.reader.seek_relative(record_len as i64 - 17)?;
+            count += 1;
+        }
+
+        Ok(count)
     }
 }
 
 // The data layout:
-// +---------------+-------------------+-----------------+----------+------------+-----------------+
-// | Key size (8B) | Deleted flag (1B) | Value size (8B) | key (?B) | value (?B) | timestamp (16B) |
-// +---------------+-------------------+-----------------+----------+------------+-----------------+
+// +---------------+-------------------+-----------------+----------+------------+-----------------+------------+
+// | Key size (8B) | Flags (1B)        | Value size (8B) | key (?B) | value (?B) | timestamp (16B) | CRC32 (4B) |
+// +---------------+-------------------+-----------------+----------+------------+-----------------+------------+
 //
-impl Iterator for StorageIterator {
-    type Item = Entry;
+impl StorageIterator {
+    /// Reads the next entry along with whether its value was stored compressed.
+    pub fn next_record(&mut self) -> Option<(Entry, bool)> {
+        if self.corrupted_at.is_some() || self.undecryptable_at.is_some() {
+            return None;
+        }
 
-    fn next(&mut self) -> Option<Entry> {
         let mut buffer = [0; 17];
         if self.reader.read_exact(&mut buffer).is_err() {
             return None;
         }
 
-        let key_size = usize::from_le_bytes(buffer[0..8].try_into().expect("required length of 8"));
-        let deleted = buffer[8] != 0;
-        let value_size =
-            usize::from_le_bytes(buffer[9..17].try_into().expect("required length of 8"));
+        let deleted = buffer[8] & FLAG_DELETED != 0;
+        let compressed = buffer[8] & FLAG_COMPRESSED != 0;
+        let encrypted = buffer[8] & FLAG_ENCRYPTED != 0;
+        let key_size = u64::from_le_bytes(buffer[0..8].try_into().ok()?);
 
-        let mut key = vec![0; key_size];
-        let mut value_buffer = vec![0; value_size];
-        let mut value = None;
+        // A record running past the end of the file is a torn write; stop
+        // before allocating buffers for lengths that cannot be right.
+        let record_len = match framed_len(&buffer) {
+            Some(len) if len <= self.file_len - self.offset => len,
+            _ => return None,
+        };
 
-        if self.reader.read_exact(&mut key).is_err() {
+        let mut body = vec![0; record_len as usize - 17];
+        if self.reader.read_exact(&mut body).is_err() {
             return None;
         }
 
-        if !deleted {
-            if self.reader.read_exact(&mut value_buffer).is_err() {
-                return None;
-            }
-            value = Some(value_buffer);
+        let (body, checksum) = body.split_at(body.len() - 4);
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&buffer);
+        hasher.update(body);
+        if hasher.finalize().to_le_bytes() != checksum {
+            self.corrupted_at = Some(self.offset);
+            return None;
         }
 
-        let mut timestamp_buffer = [0; 16];
-        if self.reader.read_exact(&mut timestamp_buffer).is_err() {
-            return None;
+        let (body, expires_at) = if buffer[8] & FLAG_EXPIRES != 0 {
+            let (body, expires_at) = body.split_at(body.len() - 16);
+            (body, Some(u128::from_le_bytes(expires_at.try_into().ok()?)))
+        } else {
+            (body, None)
+        };
+        let (payload, timestamp_buffer) = body.split_at(body.len() - 16);
+        let timestamp = u128::from_le_bytes(timestamp_buffer.try_into().ok()?);
+
+        let mut key = if encrypted {
+            let (nonce, sealed) = payload.split_at(NONCE_SIZE);
+            let aad = record_aad(&buffer, timestamp, expires_at);
+            match self.cipher.as_ref().and_then(|c| c.open(nonce, sealed, &aad)) {
+                Some(plaintext) => plaintext,
+                None => {
+                    self.undecryptable_at = Some(self.offset);
+                    return None;
+                }
+            }
+        } else {
+            payload.to_vec()
+        };
+        let value_buffer = key.split_off(key_size as usize);
+        let mut value = (!deleted).then_some(value_buffer);
+
+        if compressed {
+            match value.as_deref().map(zstd::decode_all) {
+                Some(Ok(decompressed)) => value = Some(decompressed),
+                _ => {
+                    self.corrupted_at = Some(self.offset);
+                    return None;
+                }
+            }
         }
 
-        let timestamp = u128::from_le_bytes(timestamp_buffer);
+        self.offset += record_len;
 
-        Some(Entry {
+        let entry = Entry {
             key,
-            value,
+            value: value.map(Arc::new),
             timestamp,
             deleted,
+            expires_at,
+        };
+        Some((entry, compressed))
+    }
+}
+
+impl Iterator for StorageIterator {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        self.next_record().map(|(entry, _)| entry)
+    }
+}
+
+/// Yields the entries of a storage file last to first; see
+/// `StorageIterator::reverse`.
+pub struct ReverseStorageIterator {
+    inner: StorageIterator,
+    offsets: Vec<u64>,
+}
+
+impl StorageIterator {
+    /// Turns the iterator around: one forward pass from the current position
+    /// records where each entry starts, then entries are yielded newest
+    /// first. These are exactly the entries a forward pass yields, so reading
+    /// still stops at a corrupted record.
+    #[allow(dead_code)]
+    pub fn reverse(mut self) -> io::Result<ReverseStorageIterator> {
+        let start = self.offset;
+        let mut offsets = Vec::new();
+        let mut offset = self.offset;
+        while self.next_record().is_some() {
+            offsets.push(offset);
+            offset = self.offset;
+        }
+
+        self.seek(start)?;
+        Ok(ReverseStorageIterator {
+            inner: self,
+            offsets,
         })
     }
 }
 
+impl Iterator for ReverseStorageIterator {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        let offset = self.offsets.pop()?;
+        self.inner.seek(offset).ok()?;
+        self.inner.next()
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use std::time::SystemTime;
+    use std::{fs, path::PathBuf, time::SystemTime};
 
     use rand::Rng;
 
@@ -178,4 +370,188 @@ mod test {
         let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
         let _storage_iter = StorageIterator::new(&path).unwrap();
     }
+
+    #[test]
+    fn count_records() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+
+        create_dir(&path).unwrap();
+
+        let mut storage = Storage::new(&path).unwrap();
+
+        let timestamp = SystemTime::now().elapsed().unwrap().as_micros();
+        storage
+            .set(b"Hello", b"World!", false, timestamp)
+            .expect("Error: could not write in the file");
+        storage
+            .delete(b"Hello", timestamp)
+            .expect("Error: could not complete delete operation");
+        storage
+            .set(b"Name", b"Vahid", false, timestamp)
+            .expect("Error: could not write in the file");
+        storage.commit().expect("Error: could not flush the file");
+
+        // a torn trailing record is not counted
+        storage.write_all(vec![5, 0, 0, 0, 0, 0, 0, 0, 0, 1]).unwrap();
+
+        drop(storage);
+
+        let files = scan_dir(&path).expect("Error: could not scan the directory");
+
+        let storage_iterator = StorageIterator::new(&files[0]).unwrap();
+
+        assert_eq!(3, storage_iterator.count_records().unwrap());
+
+        // Clean up
+        remove_dir(&path).unwrap();
+    }
+
+    #[test]
+    fn detect_corrupted_record() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+
+        create_dir(&path).unwrap();
+
+        let mut storage = Storage::new(&path).unwrap();
+
+        let timestamp = SystemTime::now().elapsed().unwrap().as_micros();
+        storage
+            .set(b"Hello", b"World!", false, timestamp)
+            .expect("Error: could not write in the file");
+        storage
+            .set(b"Name", b"Vahid", false, timestamp)
+            .expect("Error: could not write in the file");
+        storage
+            .set(b"gg", b"wp", false, timestamp)
+            .expect("Error: could not write in the file");
+        storage.commit().expect("Error: could not flush the file");
+
+        drop(storage);
+
+        let files = scan_dir(&path).expect("Error: could not scan the directory");
+        let original = fs::read(&files[0]).unwrap();
+
+        // a flipped bit in the value of the second record
+        let mut bytes = original.clone();
+        bytes[58 + 17 + 4] ^= 0x01;
+        fs::write(&files[0], &bytes).unwrap();
+
+        let mut storage_iterator = StorageIterator::new(&files[0]).unwrap();
+        let data: Vec<Entry> = storage_iterator.by_ref().collect();
+        assert_eq!(1, data.len());
+        assert_eq!(b"Hello".to_vec(), data[0].key);
+        assert_eq!(Some(58), storage_iterator.corrupted_at());
+
+        // the length headers are covered too
+        let mut bytes = original;
+        bytes[58] ^= 0x01;
+        fs::write(&files[0], &bytes).unwrap();
+
+        let mut storage_iterator = StorageIterator::new(&files[0]).unwrap();
+        assert_eq!(1, storage_iterator.by_ref().count());
+        assert_eq!(Some(58), storage_iterator.corrupted_at());
+
+        // Clean up
+        remove_dir(&path).unwrap();
+    }
+
+    #[test]
+    fn seek_to_saved_offset() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+
+        create_dir(&path).unwrap();
+
+        let mut storage = Storage::new(&path).unwrap();
+
+        let timestamp = SystemTime::now().elapsed().unwrap().as_micros();
+        for (key, value) in [(b"Hello", b"World!"), (b"Name0", b"Vahid0"), (b"gg000", b"wp0000")] {
+            storage
+                .set(key, value, false, timestamp)
+                .expect("Error: could not write in the file");
+        }
+        storage
+            .delete(b"Hello", timestamp)
+            .expect("Error: could not complete delete operation");
+        storage.commit().expect("Error: could not flush the file");
+
+        drop(storage);
+
+        let files = scan_dir(&path).expect("Error: could not scan the directory");
+
+        let mut storage_iterator = StorageIterator::new(&files[0]).unwrap();
+        assert_eq!(b"Hello".to_vec(), storage_iterator.next().unwrap().key);
+        assert_eq!(b"Name0".to_vec(), storage_iterator.next().unwrap().key);
+        let checkpoint = storage_iterator.current_offset();
+        assert_eq!(10 + 2 * 48, checkpoint);
+
+        let mut storage_iterator = StorageIterator::new(&files[0]).unwrap();
+        storage_iterator.seek(checkpoint).unwrap();
+        let rest: Vec<Entry> = storage_iterator.collect();
+        assert_eq!(2, rest.len());
+        assert_eq!(b"gg000".to_vec(), rest[0].key);
+        assert!(rest[1].deleted);
+
+        let mut storage_iterator = StorageIterator::new(&files[0]).unwrap();
+        let err = storage_iterator.seek(4).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        // Clean up
+        remove_dir(&path).unwrap();
+    }
+
+    #[test]
+    fn reverse_order() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+
+        create_dir(&path).unwrap();
+
+        let mut storage = Storage::new(&path).unwrap();
+
+        let timestamp = SystemTime::now().elapsed().unwrap().as_micros();
+        storage
+            .set(b"Hello", b"World!", false, timestamp)
+            .expect("Error: could not write in the file");
+        storage
+            .set(b"Name", b"Vahid", false, timestamp)
+            .expect("Error: could not write in the file");
+        storage
+            .delete(b"Hello", timestamp)
+            .expect("Error: could not complete delete operation");
+        storage.commit().expect("Error: could not flush the file");
+
+        drop(storage);
+
+        let files = scan_dir(&path).expect("Error: could not scan the directory");
+
+        let forward: Vec<Entry> = StorageIterator::new(&files[0]).unwrap().collect();
+        let mut reversed: Vec<Entry> = StorageIterator::new(&files[0]).unwrap().reverse().unwrap().collect();
+        assert_eq!(3, reversed.len());
+        assert!(reversed[0].deleted);
+        assert_eq!(b"Name".to_vec(), reversed[1].key);
+        reversed.reverse();
+        assert_eq!(forward, reversed);
+
+        // Clean up
+        remove_dir(&path).unwrap();
+    }
+
+    #[test]
+    fn reject_foreign_file() {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+
+        create_dir(&path).unwrap();
+
+        let foreign = path.join("notes.txt");
+        fs::write(&foreign, b"not a storage file at all").unwrap();
+        let err = StorageIterator::new(&foreign).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        // Clean up
+        remove_dir(&path).unwrap();
+    }
 }
@@ -0,0 +1,162 @@
+use crate::{
+    db::{now_micros, Db},
+    entry::Entry,
+    error::Result,
+};
+
+/// A hot/cold pair of databases. Reads check the hot tier first and fall
+/// through to the cold one on a miss, which includes an expired hot entry;
+/// writes and deletes only touch the hot tier, where a tombstone hides any
+/// older value still in the cold tier.
+#[derive(Debug)]
+pub struct TieredDb {
+    hot: Db,
+    cold: Db,
+    promote: bool,
+}
+
+impl TieredDb {
+    pub fn new(hot: Db, cold: Db) -> TieredDb {
+        TieredDb {
+            hot,
+            cold,
+            promote: false,
+        }
+    }
+
+    /// When enabled, values found in the cold tier are copied into the hot
+    /// tier (keeping their original timestamp and expiry) so later reads stay
+    /// hot.
+    pub fn with_promotion(mut self, promote: bool) -> TieredDb {
+        self.promote = promote;
+        self
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Entry>> {
+        let now = now_micros()?;
+        match self.hot.get_raw(key)? {
+            Some(entry) if !entry.is_expired(now) => return Ok((!entry.deleted).then_some(entry)),
+            _ => {}
+        }
+
+        let entry = match self.cold.get_raw(key)? {
+            Some(entry) if entry.is_live(now) => entry,
+            _ => return Ok(None),
+        };
+
+        if self.promote {
+            self.hot.instant_set(&mut entry.clone())?;
+        }
+
+        Ok(Some(entry))
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.hot.set(key, value)
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.hot.delete(key)
+    }
+
+    pub fn hot(&mut self) -> &mut Db {
+        &mut self.hot
+    }
+
+    pub fn cold(&mut self) -> &mut Db {
+        &mut self.cold
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{path::PathBuf, time::Duration};
+
+    use rand::Rng;
+
+    use crate::{db::Db, utils::remove_dir};
+
+    use super::TieredDb;
+
+    fn temp_db() -> Db {
+        let mut range = rand::thread_rng();
+        let path = PathBuf::from(format!("./test-{}-temp", range.gen::<u32>()));
+        Db::init_from_existing(path).unwrap()
+    }
+
+    #[test]
+    fn test_fallback_with_promotion() {
+        let mut cold = temp_db();
+        cold.set(b"Hello", b"World!").unwrap();
+        cold.set(b"Name", b"Vahid").unwrap();
+
+        let mut tiered = TieredDb::new(temp_db(), cold).with_promotion(true);
+
+        assert_eq!(None, tiered.hot().get(b"Hello").unwrap());
+        let entry = tiered.get(b"Hello").unwrap().unwrap();
+        assert_eq!(b"World!".to_vec(), *entry.value.unwrap());
+
+        let promoted = tiered.hot().get(b"Hello").unwrap().unwrap();
+        assert_eq!(b"World!".to_vec(), *promoted.value.unwrap());
+        assert_eq!(entry.timestamp, promoted.timestamp);
+
+        // a hot tombstone hides the cold value
+        tiered.delete(b"Name").unwrap();
+        assert_eq!(None, tiered.get(b"Name").unwrap());
+        assert!(tiered.cold().get(b"Name").unwrap().is_some());
+
+        assert_eq!(None, tiered.get(b"missing").unwrap());
+
+        // clean up
+        remove_dir(&tiered.hot().dir).unwrap();
+        remove_dir(&tiered.cold().dir).unwrap();
+    }
+
+    #[test]
+    fn test_fallback_with_ttl() {
+        let mut cold = temp_db();
+        cold.set_with_ttl(b"cache", b"cold", Duration::from_secs(3600)).unwrap();
+        cold.set_with_ttl(b"stale", b"cold", Duration::ZERO).unwrap();
+        cold.set(b"session", b"cold").unwrap();
+
+        let mut hot = temp_db();
+        hot.set_with_ttl(b"session", b"hot", Duration::ZERO).unwrap();
+
+        let mut tiered = TieredDb::new(hot, cold).with_promotion(true);
+
+        // an expired cold entry is a miss and is not promoted
+        assert_eq!(None, tiered.get(b"stale").unwrap());
+        assert_eq!(None, tiered.hot().get_raw(b"stale").unwrap());
+
+        // an expired hot entry falls through to the cold tier
+        let entry = tiered.get(b"session").unwrap().unwrap();
+        assert_eq!(b"cold".to_vec(), *entry.value.unwrap());
+        assert_eq!(b"cold".to_vec(), *tiered.hot().get(b"session").unwrap().unwrap().value.unwrap());
+
+        // promotion keeps the expiry
+        let entry = tiered.get(b"cache").unwrap().unwrap();
+        let promoted = tiered.hot().get(b"cache").unwrap().unwrap();
+        assert!(promoted.expires_at.is_some());
+        assert_eq!(entry.expires_at, promoted.expires_at);
+        assert_eq!(entry.timestamp, promoted.timestamp);
+
+        // clean up
+        remove_dir(&tiered.hot().dir).unwrap();
+        remove_dir(&tiered.cold().dir).unwrap();
+    }
+
+    #[test]
+    fn test_fallback_without_promotion() {
+        let mut cold = temp_db();
+        cold.set(b"Hello", b"World!").unwrap();
+
+        let mut tiered = TieredDb::new(temp_db(), cold);
+
+        assert!(tiered.get(b"Hello").unwrap().is_some());
+        assert_eq!(None, tiered.hot().get(b"Hello").unwrap());
+
+        // clean up
+        remove_dir(&tiered.hot().dir).unwrap();
+        remove_dir(&tiered.cold().dir).unwrap();
+    }
+}
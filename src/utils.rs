@@ -4,19 +4,43 @@ use std::{
     path::{Path, PathBuf},
 };
 
+use crate::segment_store::{LocalFs, SegmentStore};
+
 #[allow(dead_code)]
 pub(crate) fn file_reader(path: &Path) -> BufReader<File> {
     let file = OpenOptions::new().read(true).open(path).unwrap();
-    return BufReader::new(file);
+    BufReader::new(file)
 }
 
+/// The segment files in `dir`, oldest first: files named after their
+/// creation timestamp, with or without a sequence number in front (see
+/// `SegmentNaming`), sorted by its numeric value so names of different
+/// lengths still replay in write order. Anything else, such as stray files
+/// or the subdirectories holding column families, is left out.
 #[allow(dead_code)]
 pub(crate) fn scan_dir(dir: &Path) -> io::Result<Vec<PathBuf>> {
-    let mut files = fs::read_dir(&dir)?
-        .map(|res| res.map(|e| e.path()))
-        .collect::<Result<Vec<_>, io::Error>>()?;
+    scan_dir_in(&LocalFs, dir)
+}
+
+/// Like `scan_dir`, for a directory of `store`.
+pub(crate) fn scan_dir_in(store: &dyn SegmentStore, dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for path in store.list(dir)? {
+        if let Some(order) = path.file_name().and_then(|name| parse_segment_name(name.to_str()?)) {
+            files.push((order, path));
+        }
+    }
     files.sort();
-    Ok(files)
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+/// The creation timestamp and sequence number of a segment named `name`, or
+/// `None` if it is not a segment name. Names without a sequence number have 0.
+pub(crate) fn parse_segment_name(name: &str) -> Option<(u128, u64)> {
+    match name.split_once('-') {
+        Some((sequence, timestamp)) => Some((timestamp.parse().ok()?, sequence.parse().ok()?)),
+        None => Some((name.parse().ok()?, 0)),
+    }
 }
 
 #[allow(dead_code)]
@@ -39,7 +63,7 @@ pub(crate) fn create_dir(dir: &Path) -> io::Result<()> {
 
 #[cfg(test)]
 mod test {
-    use std::path::PathBuf;
+    use std::{fs, path::PathBuf};
 
     use crate::utils::remove_dir;
 
@@ -55,4 +79,21 @@ mod test {
         assert_eq!(files.len(), 0);
         remove_dir(&dir).unwrap();
     }
+
+    #[test]
+    fn test_scan_dir_numeric_order(){
+        let dir = PathBuf::from("test-directory-order".to_string());
+        create_dir(&dir).unwrap();
+        for name in ["100", "9", ".DS_Store", "10", "notes.txt", "000002-200", "000001-150", "x-300"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        create_dir(&dir.join("11")).unwrap();
+
+        let files = scan_dir(&dir).unwrap();
+        assert_eq!(
+            vec![dir.join("9"), dir.join("10"), dir.join("100"), dir.join("000001-150"), dir.join("000002-200")],
+            files
+        );
+        remove_dir(&dir).unwrap();
+    }
 }